@@ -0,0 +1,146 @@
+//! Where joycond's configuration lives. Other programs read the socket paths
+//! from it, so they find joycond and its proxies wherever it's told to put them.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use toml;
+
+use super::ipc::paths;
+
+/// Read first, and overridden key by key by the user's own file
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/joycond/joycond.toml";
+
+/// The user's configuration directory, as the XDG base directory spec defines it
+pub fn config_home() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(ref dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| {
+            let mut dir = PathBuf::from(home);
+            dir.push(".config");
+            dir
+        }),
+    }
+}
+
+/// joycond's config files, each overriding the one before
+pub fn config_files() -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from(SYSTEM_CONFIG_PATH)];
+    if let Some(mut path) = config_home() {
+        path.push("joycond");
+        path.push("joycond.toml");
+        files.push(path);
+    }
+    files
+}
+
+/// The daemon's socket, `daemon.socket` in the config files
+pub fn daemon_socket() -> Result<PathBuf, String> {
+    daemon_path(&config_files(), "socket")
+        .map(|path| path.unwrap_or_else(|| paths::DAEMON_PATH.into()))
+}
+
+/// Where proxies' sockets are, `daemon.proxy_dir` in the config files
+pub fn proxy_dir() -> Result<PathBuf, String> {
+    daemon_path(&config_files(), "proxy_dir")
+        .map(|path| path.unwrap_or_else(|| paths::PROXY_ROOT_PATH.into()))
+}
+
+// A path from the `[daemon]` table, as set by the last file that sets it
+fn daemon_path(files: &[PathBuf], key: &str) -> Result<Option<PathBuf>, String> {
+    let mut found = None;
+    for path in files {
+        let table = match read(path)? {
+            Some(table) => table,
+            None => continue,
+        };
+        match table.get("daemon").and_then(|daemon| daemon.get(key)) {
+            Some(toml::Value::String(value)) => found = Some(PathBuf::from(value)),
+            Some(_) => return Err(format!("{}: daemon.{} isn't a path", path.display(), key)),
+            None => (),
+        }
+    }
+    Ok(found)
+}
+
+fn read(path: &Path) -> Result<Option<toml::Value>, String> {
+    let mut text = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+        Ok(_) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    }
+    toml::from_str(&text)
+        .map(Some)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    // Config files in a directory of their own, which is removed when it's dropped
+    struct TempFiles {
+        dir: PathBuf,
+        files: Vec<PathBuf>,
+    }
+
+    impl TempFiles {
+        fn new(name: &str, contents: &[&str]) -> TempFiles {
+            let dir = env::temp_dir().join(format!("joycond-config-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let files = contents
+                .iter()
+                .enumerate()
+                .map(|(i, contents)| {
+                    let path = dir.join(format!("{}.toml", i));
+                    fs::write(&path, contents).unwrap();
+                    path
+                })
+                .collect();
+            TempFiles { dir, files }
+        }
+    }
+
+    impl Drop for TempFiles {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn later_files_override_earlier_ones() {
+        let config = TempFiles::new(
+            "override",
+            &[
+                "[daemon]\nsocket = \"/run/a.sock\"\nproxy_dir = \"/run/proxies\"\n",
+                "[daemon]\nsocket = \"/run/b.sock\"\n",
+            ],
+        );
+        let socket = daemon_path(&config.files, "socket").unwrap();
+        assert_eq!(socket, Some(PathBuf::from("/run/b.sock")));
+        let proxy_dir = daemon_path(&config.files, "proxy_dir").unwrap();
+        assert_eq!(proxy_dir, Some(PathBuf::from("/run/proxies")));
+    }
+
+    #[test]
+    fn missing_files_and_keys_are_unset() {
+        let config = TempFiles::new("missing", &["[pairing]\nritual = false\n"]);
+        let mut files = config.files.clone();
+        files.push(config.dir.join("elsewhere.toml"));
+        assert_eq!(daemon_path(&files, "socket").unwrap(), None);
+    }
+
+    #[test]
+    fn broken_files_are_errors() {
+        let config = TempFiles::new("broken", &["[daemon\n"]);
+        assert!(daemon_path(&config.files, "socket").is_err());
+        let config = TempFiles::new("mistyped", &["[daemon]\nsocket = 1\n"]);
+        assert!(daemon_path(&config.files, "socket").is_err());
+    }
+}
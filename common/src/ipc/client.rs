@@ -0,0 +1,231 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use config;
+use super::protocol::{self, *};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The daemon speaks a different protocol version
+    Version(u32),
+    /// The daemon answered with something other than what the request calls for
+    Unexpected(Box<Response>),
+    Daemon(ErrorKind, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Version(version) => write!(
+                f,
+                "Daemon speaks protocol version {}, expected {}",
+                version,
+                protocol::VERSION
+            ),
+            Error::Unexpected(response) => write!(f, "Unexpected response {:?}", response),
+            Error::Daemon(_, message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// A connection to `joycond`
+pub struct Client {
+    stream: UnixStream,
+}
+
+impl Client {
+    /// Connects to the daemon at the socket joycond's configuration names,
+    /// or its usual one
+    pub fn connect() -> Result<Client, Error> {
+        let path = config::daemon_socket()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Client::connect_to(path)
+    }
+
+    pub fn connect_to<P: AsRef<Path>>(path: P) -> Result<Client, Error> {
+        let mut client = Client {
+            stream: UnixStream::connect(path)?,
+        };
+        match client.request(&Request::Hello {
+            version: protocol::VERSION,
+        })? {
+            Response::Hello { version } if version == protocol::VERSION => Ok(client),
+            Response::Hello { version } => Err(Error::Version(version)),
+            response => Err(Error::Unexpected(Box::new(response))),
+        }
+    }
+
+    /// Sends a request and waits for its response, turning `Response::Error` into `Err`
+    pub fn request(&mut self, request: &Request) -> Result<Response, Error> {
+        protocol::write_message(&mut self.stream, request)?;
+        match protocol::read_message(&mut self.stream)? {
+            Response::Error { kind, message } => Err(Error::Daemon(kind, message)),
+            response => Ok(response),
+        }
+    }
+
+    pub fn list(&mut self) -> Result<Vec<ControllerInfo>, Error> {
+        match self.request(&Request::ListControllers)? {
+            Response::Controllers { controllers } => Ok(controllers),
+            response => Err(Error::Unexpected(Box::new(response))),
+        }
+    }
+
+    pub fn state(&mut self, serial: &str) -> Result<ControllerState, Error> {
+        match self.request(&Request::GetState {
+            serial: serial.to_string(),
+        })? {
+            Response::State { state } => Ok(state),
+            response => Err(Error::Unexpected(Box::new(response))),
+        }
+    }
+
+    pub fn set_leds(&mut self, serial: &str, leds: u8) -> Result<(), Error> {
+        self.expect_ok(&Request::SetLeds {
+            serial: serial.to_string(),
+            leds,
+        })
+    }
+
+    pub fn rumble(&mut self, serial: &str, rumble: Rumble) -> Result<(), Error> {
+        self.expect_ok(&Request::Rumble {
+            serial: serial.to_string(),
+            rumble,
+        })
+    }
+
+    pub fn set_home_light(&mut self, serial: &str, light: HomeLight) -> Result<(), Error> {
+        self.expect_ok(&Request::SetHomeLight {
+            serial: serial.to_string(),
+            light,
+        })
+    }
+
+    pub fn set_power(&mut self, serial: &str, power: Power) -> Result<(), Error> {
+        self.expect_ok(&Request::SetPower {
+            serial: serial.to_string(),
+            power,
+        })
+    }
+
+    /// Puts controllers unused for `idle_secs` to sleep, returning their serials
+    pub fn sleep_idle(&mut self, idle_secs: u64) -> Result<Vec<String>, Error> {
+        match self.request(&Request::SleepIdle { idle_secs })? {
+            Response::Serials { serials } => Ok(serials),
+            response => Err(Error::Unexpected(Box::new(response))),
+        }
+    }
+
+    pub fn pair(&mut self, left: &str, right: &str) -> Result<(), Error> {
+        self.expect_ok(&Request::Pair {
+            left: left.to_string(),
+            right: right.to_string(),
+        })
+    }
+
+    pub fn unpair(&mut self, serial: &str) -> Result<(), Error> {
+        self.expect_ok(&Request::Unpair {
+            serial: serial.to_string(),
+        })
+    }
+
+    pub fn set_settings(&mut self, serial: &str, settings: Vec<Setting>) -> Result<(), Error> {
+        self.expect_ok(&Request::SetSettings {
+            serial: serial.to_string(),
+            settings,
+        })
+    }
+
+    pub fn battery(&mut self, serial: &str) -> Result<Battery, Error> {
+        match self.request(&Request::GetBattery {
+            serial: serial.to_string(),
+        })? {
+            Response::Battery { battery } => Ok(battery),
+            response => Err(Error::Unexpected(Box::new(response))),
+        }
+    }
+
+    pub fn calibrate(&mut self, serial: &str) -> Result<(), Error> {
+        self.expect_ok(&Request::Calibrate {
+            serial: serial.to_string(),
+        })
+    }
+
+    /// Reads `length` bytes of SPI flash starting at `address`, a chunk at a time
+    pub fn read_flash(
+        &mut self,
+        serial: &str,
+        address: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let mut flash = Vec::with_capacity(length as usize);
+        while (flash.len() as u32) < length {
+            let remaining = length - flash.len() as u32;
+            let chunk = remaining.min(u32::from(protocol::MAX_FLASH_READ)) as u8;
+            let chunk_address = address.checked_add(flash.len() as u32).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Can't read {} bytes at {:#x}", length, address),
+                )
+            })?;
+            match self.request(&Request::ReadFlash {
+                serial: serial.to_string(),
+                address: chunk_address,
+                length: chunk,
+            })? {
+                Response::Flash { ref data } if data.len() == chunk as usize => {
+                    flash.extend_from_slice(data)
+                }
+                response => return Err(Error::Unexpected(Box::new(response))),
+            }
+        }
+        Ok(flash)
+    }
+
+    /// Gives up the connection in exchange for a stream of events
+    pub fn subscribe(mut self, filter: Filter) -> Result<Subscription, Error> {
+        self.expect_ok(&Request::Subscribe { filter })?;
+        Ok(Subscription {
+            stream: self.stream,
+        })
+    }
+
+    fn expect_ok(&mut self, request: &Request) -> Result<(), Error> {
+        match self.request(request)? {
+            Response::Ok => Ok(()),
+            response => Err(Error::Unexpected(Box::new(response))),
+        }
+    }
+}
+
+/// Events from the daemon, ending when it closes the connection
+pub struct Subscription {
+    stream: UnixStream,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Result<Event, Error>> {
+        match protocol::read_message(&mut self.stream) {
+            Ok(Response::Event { event }) => Some(Ok(event)),
+            Ok(Response::Error { kind, message }) => Some(Err(Error::Daemon(kind, message))),
+            Ok(response) => Some(Err(Error::Unexpected(Box::new(response)))),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(Error::Io(e))),
+        }
+    }
+}
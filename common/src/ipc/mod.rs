@@ -1 +1,8 @@
+pub mod client;
 pub mod paths;
+pub mod peer;
+pub mod protocol;
+pub mod proxy;
+pub mod socket;
+
+pub use self::client::{Client, Subscription};
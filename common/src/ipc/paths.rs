@@ -1,12 +1,13 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub static ROOT_PATH: &str = "/var/run/joycond/";
 pub static DAEMON_PATH: &str = "/var/run/joycond/daemon.sock";
 pub static PROXY_ROOT_PATH: &str = "/var/run/joycond/proxy/";
 
-pub fn proxy_path(id: &str) -> PathBuf {
-    let mut buf = PathBuf::from(PROXY_ROOT_PATH);
-    buf.push(id);
-    buf.push(".sock");
+/// Socket of the proxy for the controller `id`, e.g. its serial, among the
+/// proxies' sockets in `dir`
+pub fn proxy_path(dir: &Path, id: &str) -> PathBuf {
+    let mut buf = dir.to_path_buf();
+    buf.push(format!("{}.sock", id));
     buf
 }
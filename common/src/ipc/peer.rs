@@ -0,0 +1,60 @@
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+use libc;
+
+/// Who's on the other end of a Unix socket, as the kernel saw them connect
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Asks the kernel for the peer's credentials with `SO_PEERCRED`, which
+/// the peer can't forge
+pub fn credentials(stream: &UnixStream) -> Result<Credentials> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(Credentials {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+/// Looks up a user ID, given either a user name or the ID itself
+pub fn user_id(user: &str) -> Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(user).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No user named {}", user),
+        ));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+/// The user ID this process runs as
+pub fn own_user_id() -> u32 {
+    unsafe { libc::geteuid() }
+}
@@ -0,0 +1,443 @@
+use std::io::{self, Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+/// Bumped whenever a message changes incompatibly. Clients and the daemon
+/// exchange it in `Hello` before anything else, as do proxies and their peers.
+///
+/// 1. Controllers, their state, LEDs, rumble, pairing and events
+/// 2. Battery, calibration and flash requests
+/// 3. Controller status
+/// 4. Filtered event subscriptions, and the `Overflowed` error
+/// 5. Proxy sockets, with drivers no longer naming their product
+/// 6. How controllers are powered and connected, and `LowBattery` events
+/// 7. The Home light
+/// 8. Power states and sleeping idle controllers
+/// 9. Drivers naming both halves of a pair to proxies
+/// 10. Saving controller settings
+pub const VERSION: u32 = 10;
+
+// Messages are small; anything larger than this is a corrupt length prefix
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// Most bytes a controller returns from one SPI flash read
+pub const MAX_FLASH_READ: u8 = 0x1d;
+
+/// Size of a controller's SPI flash
+pub const FLASH_SIZE: u32 = 0x80000;
+
+/// Sent by a client. The first request on a connection must be `Hello`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Hello {
+        version: u32,
+    },
+    ListControllers,
+    GetState {
+        serial: String,
+    },
+    /// Low nibble lights the player LEDs, high nibble flashes them
+    SetLeds {
+        serial: String,
+        leds: u8,
+    },
+    Rumble {
+        serial: String,
+        rumble: Rumble,
+    },
+    /// Only right Joy-Con and Pro Controllers have a Home light
+    SetHomeLight {
+        serial: String,
+        light: HomeLight,
+    },
+    /// Combine a left and right Joy-Con into a single controller
+    Pair {
+        left: String,
+        right: String,
+    },
+    /// Split a paired controller back into its halves
+    Unpair {
+        serial: String,
+    },
+    GetBattery {
+        serial: String,
+    },
+    /// Treat the current stick positions and gyroscope reading as neutral.
+    /// The controller should be resting flat with its sticks untouched.
+    Calibrate {
+        serial: String,
+    },
+    /// Read up to `MAX_FLASH_READ` bytes of SPI flash
+    ReadFlash {
+        serial: String,
+        address: u32,
+        length: u8,
+    },
+    /// Disconnect a controller, leaving it in the given state
+    SetPower {
+        serial: String,
+        power: Power,
+    },
+    /// Put every controller that hasn't been used in `idle_secs` to sleep,
+    /// answered with `Response::Serials` of the ones that were. As in the
+    /// daemon's configuration, 0 would mean never, so it's refused.
+    SleepIdle {
+        idle_secs: u64,
+    },
+    /// Change and save a controller's settings, which are kept by its
+    /// Bluetooth address. The deadzones and rumble strength apply right away,
+    /// the rest the next time the controller connects.
+    SetSettings {
+        serial: String,
+        settings: Vec<Setting>,
+    },
+    /// Turn the connection into a stream of `Response::Event`s
+    Subscribe {
+        #[serde(default)]
+        filter: Filter,
+    },
+}
+
+/// Sent by the daemon, one per request, except after `Subscribe` where it
+/// sends an `Event` for everything that happens until the client disconnects
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Hello { version: u32 },
+    Ok,
+    Controllers { controllers: Vec<ControllerInfo> },
+    State { state: ControllerState },
+    Battery { battery: Battery },
+    Flash { data: Vec<u8> },
+    Serials { serials: Vec<String> },
+    Event { event: Event },
+    Error { kind: ErrorKind, message: String },
+}
+
+impl Response {
+    pub fn error(kind: ErrorKind, message: &str) -> Response {
+        Response::Error {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    UnsupportedVersion,
+    InvalidRequest,
+    NoSuchController,
+    /// The controller didn't accept a command
+    Device,
+    /// A subscriber fell too far behind on events, and was cut off
+    Overflowed,
+    /// A controller's settings couldn't be saved
+    Settings,
+}
+
+/// Where a controller is in its lifecycle. Only active controllers accept commands.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControllerStatus {
+    Connecting,
+    /// Reading calibration from the controller's flash
+    Calibrating,
+    Active,
+    /// Failed, and waiting to be reconnected
+    Lost,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ControllerInfo {
+    pub serial: String,
+    /// e.g. "Joy-Con (L)"
+    pub product: String,
+    /// Name the controller was given in its settings
+    #[serde(default)]
+    pub name: Option<String>,
+    pub status: ControllerStatus,
+    /// Player number, from 1 to 8
+    #[serde(default)]
+    pub slot: Option<u8>,
+    pub mac_address: Option<String>,
+    pub firmware_version: Option<String>,
+    /// Serial of the other half, if this Joy-Con is paired
+    pub paired_with: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ControllerState {
+    pub serial: String,
+    /// Names of the buttons currently held, e.g. "A" or "ZL"
+    pub buttons: Vec<String>,
+    /// Raw 12-bit stick positions, left X/Y then right X/Y
+    pub sticks: [u16; 4],
+    pub leds: u8,
+    /// What the controller last said its LEDs show, when it was asked
+    #[serde(default)]
+    pub reported_leds: Option<u8>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryLevel {
+    Empty,
+    Critical,
+    Low,
+    Medium,
+    Full,
+}
+
+/// What a controller says it's connected as
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryConnection {
+    JoyCon,
+    /// A Pro Controller, or Joy-Con in a charging grip
+    ProOrGrip,
+    #[default]
+    Unknown,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Battery {
+    pub level: BatteryLevel,
+    pub charging: bool,
+    /// Running off the console or a USB cable rather than the battery
+    #[serde(default)]
+    pub powered: bool,
+    #[serde(default)]
+    pub connection: BatteryConnection,
+    /// About to run out, with nothing charging it
+    #[serde(default)]
+    pub low: bool,
+    /// Battery voltage, read every so often, so `None` for a little while
+    /// after the controller connects
+    #[serde(default)]
+    pub millivolts: Option<u16>,
+    /// How full the battery is, from 0 to 100, estimated from the voltage
+    #[serde(default)]
+    pub percent: Option<u8>,
+}
+
+impl Battery {
+    /// The battery as the kernel describes a `power_supply` device in its
+    /// uevent file, e.g. `POWER_SUPPLY_CAPACITY_LEVEL=Normal`
+    pub fn power_supply(&self) -> Vec<(&'static str, String)> {
+        let status = if self.charging {
+            "Charging"
+        } else if self.level == BatteryLevel::Full && self.powered {
+            "Full"
+        } else if self.powered {
+            "Not charging"
+        } else {
+            "Discharging"
+        };
+        let capacity_level = match self.level {
+            BatteryLevel::Empty | BatteryLevel::Critical => "Critical",
+            BatteryLevel::Low => "Low",
+            BatteryLevel::Medium => "Normal",
+            BatteryLevel::Full => "Full",
+        };
+        let mut properties = vec![
+            ("POWER_SUPPLY_SCOPE", "Device".to_string()),
+            ("POWER_SUPPLY_PRESENT", "1".to_string()),
+            (
+                "POWER_SUPPLY_ONLINE",
+                (if self.powered { "1" } else { "0" }).to_string(),
+            ),
+            ("POWER_SUPPLY_STATUS", status.to_string()),
+            ("POWER_SUPPLY_CAPACITY_LEVEL", capacity_level.to_string()),
+        ];
+        if let Some(millivolts) = self.millivolts {
+            // The kernel counts in microvolts
+            properties.push((
+                "POWER_SUPPLY_VOLTAGE_NOW",
+                (u32::from(millivolts) * 1000).to_string(),
+            ));
+        }
+        if let Some(percent) = self.percent {
+            properties.push(("POWER_SUPPLY_CAPACITY", percent.to_string()));
+        }
+        properties
+    }
+}
+
+/// One of a controller's saved settings, where `None` clears it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "key", content = "value", rename_all = "snake_case")]
+pub enum Setting {
+    /// Shown instead of the product name
+    Name(Option<String>),
+    /// Player number from 1 to 8, used if it's free when the controller connects
+    Slot(Option<u8>),
+    /// Path of a mapping profile to use instead of the standard gamepad
+    Profile(Option<String>),
+    /// How much of each stick's travel to ignore, left then right, from 0 to 1
+    Deadzones([f32; 2]),
+    /// Scales every rumble, from 0 to 1
+    RumbleStrength(f32),
+}
+
+/// A single rumble command, applied to both actuators of a controller
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Rumble {
+    /// Hz, clamped by the controller to roughly 40-1250
+    pub frequency: f32,
+    /// 0.0-1.0
+    pub amplitude: f32,
+    pub duration_ms: u32,
+}
+
+/// What a controller does once it's disconnected
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Power {
+    /// Sleep until a button is pressed
+    Sleep,
+    /// Reboot and reconnect right away
+    Reconnect,
+    /// Reboot and wait to be paired with a new host
+    Pair,
+    /// Reboot and reconnect as if Home had been pressed
+    Home,
+}
+
+/// Patterns for the light around the Home button
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HomeLight {
+    Off,
+    On,
+    /// Slowly fades in and out
+    Breathing,
+    /// Flashes until it's changed
+    Blink,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Connected {
+        controller: ControllerInfo,
+    },
+    Disconnected {
+        serial: String,
+    },
+    /// A button was pressed or released, named as in `ControllerState`, at
+    /// `time_ms` by the controller's own clock, counted from when it connected
+    Button {
+        serial: String,
+        button: String,
+        pressed: bool,
+        #[serde(default)]
+        time_ms: u64,
+    },
+    /// Sticks and motion at one moment, at the rate the subscriber asked for
+    Sample {
+        serial: String,
+        sticks: [u16; 4],
+        accelerometer: [i16; 3],
+        gyroscope: [i16; 3],
+    },
+    Battery {
+        serial: String,
+        battery: Battery,
+    },
+    /// The battery dropped to low or below while not charging. Sent once
+    /// each time, to subscribers of battery events.
+    LowBattery {
+        serial: String,
+        battery: Battery,
+    },
+}
+
+impl Event {
+    pub fn serial(&self) -> &str {
+        match self {
+            Event::Connected { controller } => &controller.serial,
+            Event::Disconnected { serial }
+            | Event::Button { serial, .. }
+            | Event::Sample { serial, .. }
+            | Event::Battery { serial, .. }
+            | Event::LowBattery { serial, .. } => serial,
+        }
+    }
+
+    /// Whether the event only matters until the next one like it, so a
+    /// subscriber falling behind can miss it
+    pub fn is_sample(&self) -> bool {
+        matches!(self, Event::Sample { .. })
+    }
+}
+
+/// Which events a subscriber hears about. By default, only controllers
+/// connecting and disconnecting.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    /// Controllers to hear about, or all of them if empty
+    pub serials: Vec<String>,
+    pub lifecycle: bool,
+    pub buttons: bool,
+    pub battery: bool,
+    /// Stick and motion samples per second and controller, or 0 for none
+    pub sample_rate: u32,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter {
+            serials: Vec::new(),
+            lifecycle: true,
+            buttons: false,
+            battery: false,
+            sample_rate: 0,
+        }
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, event: &Event) -> bool {
+        if !self.serials.is_empty() && !self.serials.iter().any(|s| s == event.serial()) {
+            return false;
+        }
+        match event {
+            Event::Connected { .. } | Event::Disconnected { .. } => self.lifecycle,
+            Event::Button { .. } => self.buttons,
+            Event::Sample { .. } => self.sample_rate > 0,
+            Event::Battery { .. } | Event::LowBattery { .. } => self.battery,
+        }
+    }
+}
+
+/// Writes a message as a big-endian u32 length followed by that many bytes of JSON
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let body = serde_json::to_vec(message)?;
+    writer.write_all(&(body.len() as u32).to_be_bytes())?;
+    writer.write_all(&body)?;
+    writer.flush()
+}
+
+/// Reads one message written by `write_message`. A connection closed between
+/// messages is reported as `UnexpectedEof`.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<T> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Message of {} bytes is too long", len),
+        ));
+    }
+
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(serde_json::from_slice(&body)?)
+}
@@ -0,0 +1,68 @@
+//! Messages on a proxy's socket, at `paths::proxy_path(id)`. A proxy owns
+//! one virtual device. The driver of whichever controller is using it feeds
+//! it input, and anyone else allowed on the socket can send commands through
+//! it, which the proxy passes on to the driver.
+//!
+//! Messages are framed like the daemon's, with `protocol::write_message`.
+//! Proxies create devices in `/dev/uinput`, so they usually run with more
+//! privileges than drivers, and only take connections from the users they
+//! were told to trust.
+
+use super::protocol::Rumble;
+
+/// Sent to a proxy. The first message on a connection says who's talking,
+/// and the proxy answers it with `Response::Ok` or `Response::Error`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProxyRequest {
+    /// The driver for the controller. Only one can be connected at a time,
+    /// after which it sends `Input` and receives `ProxyCommand`s.
+    Driver {
+        version: u32,
+        serial: String,
+    },
+    /// Anyone else, who sends commands and gets a `Response` to each
+    Control {
+        version: u32,
+    },
+    /// `product` is what the input should be mapped as, e.g. "JoyConL", or
+    /// "ProController" for a pair of Joy-Con. It's `None` if the controller
+    /// hasn't said what it is yet.
+    Input {
+        sample: InputSample,
+        product: Option<String>,
+    },
+    /// Let go of every button, e.g. because the controller dropped out
+    Release,
+    /// The controllers input comes from, when that's more than the driver's
+    /// own, e.g. both halves of a pair
+    Serials {
+        serials: Vec<String>,
+    },
+    SetLeds {
+        leds: u8,
+    },
+    Rumble {
+        rumble: Rumble,
+    },
+}
+
+/// Passed on by a proxy to its driver
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProxyCommand {
+    SetLeds { leds: u8 },
+    Rumble { rumble: Rumble },
+}
+
+/// One decoded input report
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InputSample {
+    /// When the controller sent the report, relative to its first one
+    pub time_us: u64,
+    pub buttons: u32,
+    /// Raw 12-bit stick positions, left X/Y then right X/Y
+    pub sticks: [u16; 4],
+    pub accelerometer: [i16; 3],
+    pub gyroscope: [i16; 3],
+}
@@ -0,0 +1,36 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use log;
+
+/// Binds a listening socket at `path`, creating its directory if needed. A
+/// socket left behind by a process that's gone is replaced, but one that
+/// still accepts connections is an error, so two servers can't share a path.
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("Something is already listening on {}", path.display()),
+        ));
+    }
+    match fs::remove_file(path) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Removes a socket bound with `bind`, on the way out
+pub fn unlink(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => log::e(&format!("Couldn't remove {}: {}", path.display(), e)),
+    }
+}
@@ -0,0 +1,33 @@
+//! Player LED patterns written as 4 characters, player LED 1 first: `0` for
+//! off, `1` for on and `f` for flashing, e.g. `1010` or `1ff0`
+
+pub fn parse(pattern: &str) -> Result<u8, String> {
+    if pattern.chars().count() != 4 {
+        return Err(format!(
+            "LED pattern \"{}\" should have 4 characters",
+            pattern
+        ));
+    }
+    let mut leds = 0;
+    for (i, c) in pattern.chars().enumerate() {
+        leds |= match c {
+            '0' => 0,
+            '1' => 1 << i,
+            'f' | 'F' => 1 << (i + 4),
+            _ => return Err(format!("Unknown LED state '{}' in \"{}\"", c, pattern)),
+        };
+    }
+    Ok(leds)
+}
+
+pub fn format(leds: u8) -> String {
+    (0..4)
+        .map(
+            |i| match (leds & (1 << i) != 0, leds & (1 << (i + 4)) != 0) {
+                (_, true) => 'f',
+                (true, false) => '1',
+                (false, false) => '0',
+            },
+        )
+        .collect()
+}
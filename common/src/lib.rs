@@ -1,6 +1,14 @@
+extern crate libc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate termion;
+extern crate toml;
 
+pub mod config;
 pub mod has;
 pub mod ipc;
+pub mod leds;
 pub mod log;
 pub mod types;
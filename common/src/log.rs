@@ -1,3 +1,4 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
 use termion::{
@@ -6,7 +7,31 @@ use termion::{
 
 static mut START_TIME: Option<Instant> = None;
 
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Debug as usize);
+
+/// How much gets logged. Each level includes the ones after it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Debug,
+    Info,
+    Error,
+}
+
+/// Only log messages at `level` or above from now on. Messages from `wtf`
+/// are always logged.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+fn enabled(level: Level) -> bool {
+    level as usize >= LEVEL.load(Ordering::Relaxed)
+}
+
 pub fn i(message: &str) {
+    if !enabled(Level::Info) {
+        return;
+    }
     println!(
         "{}{}(I){}{} {}",
         Fg(Green),
@@ -18,10 +43,16 @@ pub fn i(message: &str) {
 }
 
 pub fn d(message: &str) {
+    if !enabled(Level::Debug) {
+        return;
+    }
     println!("{}(D){}{} {}", Fg(Yellow), timestamp(), Clear, message);
 }
 
 pub fn e(message: &str) {
+    if !enabled(Level::Error) {
+        return;
+    }
     println!("{}{}(E){}{} {}", Fg(Red), Bold, timestamp(), Clear, message);
 }
 
@@ -1,6 +1,33 @@
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Axis {
     Lx,
     Ly,
     Rx,
     Ry,
 }
+
+impl Axis {
+    /// Whether the axis is vertical, where the controller reports up as positive
+    pub fn is_vertical(&self) -> bool {
+        match self {
+            Axis::Ly | Axis::Ry => true,
+            Axis::Lx | Axis::Rx => false,
+        }
+    }
+}
+
+impl FromStr for Axis {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Axis, String> {
+        match name.to_lowercase().as_str() {
+            "lx" => Ok(Axis::Lx),
+            "ly" => Ok(Axis::Ly),
+            "rx" => Ok(Axis::Rx),
+            "ry" => Ok(Axis::Ry),
+            _ => Err(format!("Unknown axis \"{}\"", name)),
+        }
+    }
+}
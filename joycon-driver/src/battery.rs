@@ -0,0 +1,179 @@
+// Raw voltage readings, as subcommand 0x50 returns them, against how full the
+// battery is estimated to be. The controller's own levels change at 1320
+// (empty), 1376 (critical), 1416 (low) and 1504 (medium), and it reads about
+// 1680 when fully charged.
+const DISCHARGE_CURVE: [(u16, u8); 6] = [
+    (1320, 0),
+    (1376, 8),
+    (1416, 20),
+    (1504, 55),
+    (1600, 85),
+    (1680, 100),
+];
+
+/// How full the battery is, in the steps the controller reports
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Empty,
+    Critical,
+    Low,
+    Medium,
+    Full,
+}
+
+/// What the controller says it's connected as
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Connection {
+    JoyCon,
+    /// A Pro Controller, or Joy-Con in a charging grip
+    ProOrGrip,
+    Unknown(u8),
+}
+
+/// Decoded from the byte after the timer in every full input report: the
+/// battery in the high nibble, the connection in the low one
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BatteryState {
+    pub level: Level,
+    pub charging: bool,
+    pub connection: Connection,
+    /// Running off the console or a USB cable rather than the battery
+    pub powered: bool,
+}
+
+impl From<u8> for BatteryState {
+    fn from(byte: u8) -> BatteryState {
+        let level = match byte >> 5 {
+            0 => Level::Empty,
+            1 => Level::Critical,
+            2 => Level::Low,
+            3 => Level::Medium,
+            _ => Level::Full,
+        };
+        let connection = match (byte >> 1) & 0x3 {
+            0 => Connection::ProOrGrip,
+            3 => Connection::JoyCon,
+            other => Connection::Unknown(other),
+        };
+        BatteryState {
+            level,
+            charging: byte & 0x10 != 0,
+            connection,
+            powered: byte & 0x1 != 0,
+        }
+    }
+}
+
+impl BatteryState {
+    /// Whether the controller is about to run out, and nothing is charging it
+    pub fn is_low(&self) -> bool {
+        self.level <= Level::Low && !self.charging
+    }
+}
+
+/// Converts a raw voltage reading from subcommand 0x50 to millivolts, which
+/// come in steps of 2.5
+pub fn millivolts(raw: u16) -> u16 {
+    (u32::from(raw) * 5 / 2).min(u32::from(u16::MAX)) as u16
+}
+
+/// Estimates how full the battery is, from 0 to 100, from a raw voltage
+/// reading. Charging raises the voltage, so this reads high while charging.
+pub fn percent(raw: u16) -> u8 {
+    let (first, last) = (
+        DISCHARGE_CURVE[0],
+        DISCHARGE_CURVE[DISCHARGE_CURVE.len() - 1],
+    );
+    if raw <= first.0 {
+        return first.1;
+    }
+    if raw >= last.0 {
+        return last.1;
+    }
+    let (low, high) = DISCHARGE_CURVE
+        .windows(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(_, high)| raw < high.0)
+        .expect("Voltage is within the curve");
+    let along = f32::from(raw - low.0) / f32::from(high.0 - low.0);
+    (f32::from(low.1) + along * f32::from(high.1 - low.1)).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_readings_convert_to_millivolts() {
+        assert_eq!(millivolts(1320), 3300);
+        assert_eq!(millivolts(1680), 4200);
+        assert_eq!(millivolts(1321), 3302);
+        assert_eq!(millivolts(u16::MAX), u16::MAX);
+    }
+
+    #[test]
+    fn percent_is_clamped_at_the_ends_of_the_curve() {
+        assert_eq!(percent(0), 0);
+        assert_eq!(percent(1320), 0);
+        assert_eq!(percent(1680), 100);
+        assert_eq!(percent(2000), 100);
+    }
+
+    #[test]
+    fn percent_matches_the_curve_at_its_points() {
+        for &(raw, expected) in DISCHARGE_CURVE.iter() {
+            assert_eq!(percent(raw), expected);
+        }
+    }
+
+    #[test]
+    fn decodes_joycon_on_battery() {
+        let state = BatteryState::from(0x8e);
+        assert_eq!(state.level, Level::Full);
+        assert!(!state.charging);
+        assert_eq!(state.connection, Connection::JoyCon);
+        assert!(!state.powered);
+        assert!(!state.is_low());
+    }
+
+    #[test]
+    fn decodes_pro_controller_charging() {
+        let state = BatteryState::from(0x91);
+        assert_eq!(state.level, Level::Full);
+        assert!(state.charging);
+        assert_eq!(state.connection, Connection::ProOrGrip);
+        assert!(state.powered);
+        assert!(!state.is_low());
+    }
+
+    #[test]
+    fn decodes_critical_battery() {
+        let state = BatteryState::from(0x20);
+        assert_eq!(state.level, Level::Critical);
+        assert!(!state.charging);
+        assert_eq!(state.connection, Connection::ProOrGrip);
+        assert!(!state.powered);
+        assert!(state.is_low());
+    }
+
+    #[test]
+    fn low_only_while_not_charging() {
+        assert!(BatteryState::from(0x4e).is_low());
+        assert!(!BatteryState::from(0x5e).is_low());
+        assert!(!BatteryState::from(0x6e).is_low());
+        assert!(BatteryState::from(0x0e).is_low());
+    }
+
+    #[test]
+    fn decodes_unknown_connections() {
+        assert_eq!(BatteryState::from(0x82).connection, Connection::Unknown(1));
+        assert_eq!(BatteryState::from(0x84).connection, Connection::Unknown(2));
+    }
+
+    #[test]
+    fn percent_interpolates_between_points() {
+        assert_eq!(percent(1348), 4);
+        assert_eq!(percent(1552), 70);
+        assert_eq!(percent(1640), 93);
+    }
+}
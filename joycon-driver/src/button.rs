@@ -1,8 +1,10 @@
+use std::str::FromStr;
+
 use super::id::Product;
 
 use self::Button::*;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Button {
     Y,
     X,
@@ -38,6 +40,12 @@ pub enum Button {
     South,
 }
 
+/// Every button with its own bit in an input report
+pub const REAL_BUTTONS: [Button; 22] = [
+    Y, X, B, A, RightSr, RightSl, R, Zr, Minus, Plus, Cr, Cl, Home, Capture, Down, Up, Right, Left,
+    LeftSr, LeftSl, L, Zl,
+];
+
 impl Button {
     pub fn is_real(&self) -> bool {
         match self {
@@ -80,6 +88,50 @@ impl Button {
     }
 }
 
+impl FromStr for Button {
+    type Err = String;
+
+    /// Parses a button from its case-insensitive name, e.g. `"zl"` or `"RightSr"`
+    fn from_str(name: &str) -> Result<Button, String> {
+        match name.to_lowercase().as_str() {
+            "y" => Ok(Y),
+            "x" => Ok(X),
+            "b" => Ok(B),
+            "a" => Ok(A),
+            "rightsr" => Ok(RightSr),
+            "rightsl" => Ok(RightSl),
+            "r" => Ok(R),
+            "zr" => Ok(Zr),
+
+            "minus" => Ok(Minus),
+            "plus" => Ok(Plus),
+            "cr" => Ok(Cr),
+            "cl" => Ok(Cl),
+            "home" => Ok(Home),
+            "capture" => Ok(Capture),
+
+            "down" => Ok(Down),
+            "up" => Ok(Up),
+            "right" => Ok(Right),
+            "left" => Ok(Left),
+            "leftsr" => Ok(LeftSr),
+            "leftsl" => Ok(LeftSl),
+            "l" => Ok(L),
+            "zl" => Ok(Zl),
+
+            "sl" => Ok(Sl),
+            "sr" => Ok(Sr),
+
+            "north" => Ok(North),
+            "east" => Ok(East),
+            "west" => Ok(West),
+            "south" => Ok(South),
+
+            _ => Err(format!("Unknown button \"{}\"", name)),
+        }
+    }
+}
+
 impl From<Button> for u32 {
     fn from(button: Button) -> u32 {
         match button {
@@ -0,0 +1,129 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::frame::InputFrame;
+
+/// Where each block of calibration data lives in SPI flash, and how long it is
+pub const FACTORY_STICKS: (u32, usize) = (0x603d, 18);
+pub const FACTORY_IMU: (u32, usize) = (0x6020, 24);
+pub const USER_STICKS: (u32, usize) = (0x8010, 22);
+pub const USER_IMU: (u32, usize) = (0x8026, 26);
+
+// Marks a user calibration block as present
+const USER_MAGIC: [u8; 2] = [0xb2, 0xa1];
+
+const STICK_CENTER: u16 = 0x800;
+const STICK_MAX: u16 = 0xfff;
+
+/// Neutral readings that are subtracted from raw input, so a resting stick
+/// reads 0x800 and a still controller reads no rotation
+#[derive(Copy, Clone, Debug)]
+pub struct Calibration {
+    /// Left then right stick, as raw 12-bit (x, y) positions
+    pub stick_centers: [(u16, u16); 2],
+    pub gyro_bias: (i16, i16, i16),
+}
+
+impl Default for Calibration {
+    fn default() -> Calibration {
+        Calibration {
+            stick_centers: [(STICK_CENTER, STICK_CENTER); 2],
+            gyro_bias: (0, 0, 0),
+        }
+    }
+}
+
+impl Calibration {
+    /// Reads calibration from the blocks listed above, preferring the user's
+    /// own calibration over the factory's
+    pub fn from_flash(
+        factory_sticks: &[u8],
+        factory_imu: &[u8],
+        user_sticks: &[u8],
+        user_imu: &[u8],
+    ) -> Calibration {
+        let mut calibration = Calibration::default();
+
+        let left = user_block(&user_sticks[..11]).or_else(|| factory_block(&factory_sticks[..9]));
+        if let Some(left) = left {
+            // The left stick stores its maximums first, then its center
+            let values = unpack_stick(left);
+            calibration.stick_centers[0] = (values[2], values[3]);
+        }
+        let right = user_block(&user_sticks[11..]).or_else(|| factory_block(&factory_sticks[9..]));
+        if let Some(right) = right {
+            let values = unpack_stick(right);
+            calibration.stick_centers[1] = (values[0], values[1]);
+        }
+
+        let imu = user_block(user_imu).or_else(|| factory_block(factory_imu));
+        if let Some(imu) = imu {
+            let offset = |i: usize| LittleEndian::read_i16(&imu[12 + i * 2..14 + i * 2]);
+            calibration.gyro_bias = (offset(0), offset(1), offset(2));
+        }
+
+        calibration
+    }
+
+    /// Takes a frame read from a controller at rest as the new neutral. The
+    /// gyroscope's stays as it was if the frame carries no motion.
+    pub fn at_rest(&self, frame: &InputFrame) -> Calibration {
+        Calibration {
+            stick_centers: [
+                (frame.axes.lx, frame.axes.ly),
+                (frame.axes.rx, frame.axes.ry),
+            ],
+            gyro_bias: frame
+                .motion
+                .map_or(self.gyro_bias, |motion| motion.gyroscope),
+        }
+    }
+
+    pub fn apply(&self, frame: &mut InputFrame) {
+        let recenter = |value: u16, center: u16| {
+            (i32::from(value) - i32::from(center) + i32::from(STICK_CENTER))
+                .clamp(0, i32::from(STICK_MAX)) as u16
+        };
+        let [(lx, ly), (rx, ry)] = self.stick_centers;
+        frame.axes.lx = recenter(frame.axes.lx, lx);
+        frame.axes.ly = recenter(frame.axes.ly, ly);
+        frame.axes.rx = recenter(frame.axes.rx, rx);
+        frame.axes.ry = recenter(frame.axes.ry, ry);
+
+        if let Some(ref mut motion) = frame.motion {
+            let (x, y, z) = motion.gyroscope;
+            let (bx, by, bz) = self.gyro_bias;
+            motion.gyroscope = (
+                x.saturating_sub(bx),
+                y.saturating_sub(by),
+                z.saturating_sub(bz),
+            );
+        }
+    }
+}
+
+fn user_block(buf: &[u8]) -> Option<&[u8]> {
+    if buf[..2] == USER_MAGIC {
+        Some(&buf[2..])
+    } else {
+        None
+    }
+}
+
+// Unwritten flash reads as 0xff
+fn factory_block(buf: &[u8]) -> Option<&[u8]> {
+    if buf.iter().all(|&b| b == 0xff) {
+        None
+    } else {
+        Some(buf)
+    }
+}
+
+/// Unpacks six 12-bit values from nine bytes
+fn unpack_stick(buf: &[u8]) -> [u16; 6] {
+    let mut values = [0; 6];
+    for (i, bytes) in buf.chunks(3).take(3).enumerate() {
+        values[i * 2] = (u16::from(bytes[1]) << 8) & 0xf00 | u16::from(bytes[0]);
+        values[i * 2 + 1] = (u16::from(bytes[2]) << 4) | (u16::from(bytes[1]) >> 4);
+    }
+    values
+}
@@ -1,3 +1,4 @@
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum InputMode {
     Full,
     NfcIr,
@@ -25,10 +26,16 @@ impl<'a> From<&'a InputMode> for u8 {
     }
 }
 
+/// What the controller's Bluetooth does next, set with subcommand 0x06
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum HciState {
+    /// Disconnect and sleep until a button wakes it
     Disconnect,
+    /// Reboot and reconnect to the host it was connected to
     Reconnect,
+    /// Reboot and wait to be paired with a new host
     Pair,
+    /// Reboot and reconnect, the way it does when Home is pressed
     Home,
 }
 
@@ -1,29 +1,35 @@
 use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fmt;
+use std::time::{Duration, Instant};
 
 use arraydeque::{ArrayDeque, Wrapping};
-use hidapi::{HidApi, HidDevice, HidError};
+use byteorder::{ByteOrder, LittleEndian};
+use hidapi::{HidApi, HidDevice, HidDeviceInfo, HidError};
 use termion::{color, style};
 
 use common::has::Has;
 use common::log;
 
+use super::battery::{self, BatteryState};
 use super::button::Button;
+use super::calibration::{self, Calibration};
 use super::device::{HciState, InputMode};
+use super::event::{ButtonEvent, EventLog};
 use super::frame::{AxisFrame, ButtonFrame, InputFrame, MotionFrame};
+use super::home_light::Pattern;
 use super::id::{Product, Vendor};
 use super::input::{InputReport, ResponseData, SpiChunk};
-use super::output::{Command::*, OutputReport::*, NEUTRAL_RUMBLE};
-
-fn init_api() -> HidApi {
-    match HidApi::new() {
-        Ok(api) => api,
-        Err(e) => {
-            log::wtf("Couldn't initialize HidApi");
-            panic!(e);
-        }
-    }
-}
+use super::output::{self, Command, Command::*, OutputReport::*, NEUTRAL_RUMBLE};
+use super::player_leds::{LedController, LedState};
+use super::setup::{self, Step};
+use super::stats::{ReportStats, StatsSummary};
+
+// How long to wait for a controller to answer a command
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// How often `poll_voltage` asks for the battery voltage
+const VOLTAGE_INTERVAL: Duration = Duration::from_secs(30);
 
 // Offset in SPI memory that `spi_mirror` begins at
 const SPI_ORIGIN: u16 = 0x6000;
@@ -35,7 +41,18 @@ pub struct Driver {
     device: HidDevice,
     serial_number: String,
     rumble_counter: Cell<u8>,
-    leds: Cell<u8>,
+    rumble: Cell<[u8; 8]>,
+    leds: LedController,
+    // Whether anything has asked for the LEDs to change since connecting
+    leds_set: bool,
+    // Bitmask last sent to the controller, or read back from it
+    shown_leds: u8,
+    // Bitmask the controller last answered `read_leds` with
+    reported_leds: Option<u8>,
+    battery: Option<BatteryState>,
+    // Raw reading, from the latest answer to `poll_voltage`
+    raw_voltage: Option<u16>,
+    voltage_requested: Option<Instant>,
 
     firmware_version: Option<u16>,
     product: Option<Product>,
@@ -47,23 +64,57 @@ pub struct Driver {
 
     // Mirror of a subset of the Joy-Con's internal flash memory
     spi_mirror: [u8; 0xA000],
+    // Most recent flash read, which may lie outside the mirror
+    spi_read: Option<(u32, Vec<u8>)>,
+
+    // Setup steps not yet sent, and the one waiting for an answer
+    setup: VecDeque<Step>,
+    awaiting: Option<Awaiting>,
 
+    calibration: Calibration,
+    // Most recent frame as the controller sent it
+    uncalibrated: InputFrame,
+
+    stats: ReportStats,
+    // Most recent frame, kept after the queue has been drained
+    current: InputFrame,
     frames: ArrayDeque<[InputFrame; 32], Wrapping>,
+    events: EventLog,
+}
+
+// A setup step's subcommand, waiting for the controller to answer it
+struct Awaiting {
+    subcommand: u8,
+    // Flash reads are told apart by address
+    address: Option<u32>,
+    sent: Instant,
+    answered: bool,
 }
 
 impl Driver {
     /// Constructs a new Driver for the first device matching the given product ID
-    pub fn find(product: Product) -> Result<Driver, HidError> {
-        let api = init_api();
+    pub fn find(api: &HidApi, product: Product) -> Result<Driver, HidError> {
         match api.open(Vendor::Nintendo as u16, product as u16) {
             Ok(device) => Driver::for_device(device),
             Err(e) => Err(e),
         }
     }
 
+    /// Constructs a new Driver for an enumerated device
+    pub fn open(api: &HidApi, info: &HidDeviceInfo) -> Result<Driver, HidError> {
+        info.open_device(api).and_then(Driver::for_device)
+    }
+
+    /// Like `open`, but returns right away, leaving the controller to be
+    /// identified by calling `poll_setup` until it's done
+    pub fn connect(api: &HidApi, info: &HidDeviceInfo) -> Result<Driver, HidError> {
+        let mut jc = info.open_device(api).and_then(Driver::new)?;
+        jc.start_setup(&setup::IDENTIFY);
+        Ok(jc)
+    }
+
     /// Constructs a new Driver for the device matching the given serial number
-    pub fn for_serial(serial: &str) -> Result<Driver, HidError> {
-        let api = init_api();
+    pub fn for_serial(api: &HidApi, serial: &str) -> Result<Driver, HidError> {
         let device_info = api.devices().iter().find(|dev| match &dev.serial_number {
             Some(s) if s == serial => true,
             Some(_) | None => false,
@@ -85,6 +136,12 @@ impl Driver {
     }
 
     fn for_device(device: HidDevice) -> Result<Driver, HidError> {
+        let mut jc = Driver::new(device)?;
+        jc.start_setup(&setup::IDENTIFY);
+        jc.finish_setup().map(|_| jc)
+    }
+
+    fn new(device: HidDevice) -> Result<Driver, HidError> {
         let serial = match device.get_serial_number_string() {
             Ok(Some(s)) => s,
             Ok(None) => String::new(),
@@ -95,32 +152,120 @@ impl Driver {
             return Err(e);
         }
 
-        let mut jc = Driver {
+        Ok(Driver {
             device,
             rumble_counter: Cell::new(0),
+            rumble: Cell::new(NEUTRAL_RUMBLE),
             serial_number: serial,
-            leds: Cell::new(0x00),
+            leds: LedController::new(LedState::Raw(0x00)),
+            leds_set: false,
+            shown_leds: 0x00,
+            reported_leds: None,
+            battery: None,
+            raw_voltage: None,
+            voltage_requested: None,
 
             firmware_version: None,
             mac_address: None,
             product: None,
 
             spi_mirror: [0; 0xA000],
+            spi_read: None,
+
+            setup: VecDeque::new(),
+            awaiting: None,
+
+            calibration: Calibration::default(),
+            uncalibrated: InputFrame::new(),
 
             read_buffer: [0; 360],
 
+            stats: ReportStats::default(),
+            current: InputFrame::new(),
             frames: ArrayDeque::new(),
+            events: EventLog::default(),
+        })
+    }
+
+    /// Queues steps for `poll_setup` to work through
+    pub fn start_setup(&mut self, steps: &[Step]) {
+        self.setup.extend(steps.iter().cloned());
+    }
+
+    /// Reads pending input and moves on to the next setup step once the
+    /// controller has answered the last, without blocking. Returns whether
+    /// every step queued by `start_setup` is done, and fails if the
+    /// controller doesn't answer one within `RESPONSE_TIMEOUT`.
+    pub fn poll_setup(&mut self) -> Result<bool, HidError> {
+        self.flush()?;
+        loop {
+            if let Some(ref awaiting) = self.awaiting {
+                if !awaiting.answered {
+                    if awaiting.sent.elapsed() < RESPONSE_TIMEOUT {
+                        return Ok(false);
+                    }
+                    return Err(HidError::HidApiError {
+                        message: format!(
+                            "Timed out waiting for {} to answer subcommand {:#04x}",
+                            self.serial_number, awaiting.subcommand
+                        ),
+                    });
+                }
+            }
+            self.awaiting = None;
+            match self.setup.pop_front() {
+                Some(step) => self.run_step(step)?,
+                None => return Ok(true),
+            }
+        }
+    }
+
+    // Blocks until every queued setup step is done
+    fn finish_setup(&mut self) -> Result<(), HidError> {
+        while !self.poll_setup()? {
+            self.handle_input(RESPONSE_TIMEOUT.as_millis() as i32)?;
+        }
+        Ok(())
+    }
+
+    fn run_step(&mut self, step: Step) -> Result<(), HidError> {
+        let (sub, address) = match step {
+            Step::InputMode(mode) => (SetInputMode(mode), None),
+            Step::DeviceInfo => (RequestDeviceInfo, None),
+            Step::ReadFlash(addr, length) => (ReadSpi(addr, length), Some(addr)),
+            Step::ReadLeds => (GetLeds, None),
+            Step::Imu(enabled) => (EnableImu(enabled), None),
+            Step::Vibration(enabled) => (EnableVibration(enabled), None),
+            Step::LoadCalibration => {
+                self.calibration = Calibration::from_flash(
+                    self.mirrored(calibration::FACTORY_STICKS),
+                    self.mirrored(calibration::FACTORY_IMU),
+                    self.mirrored(calibration::USER_STICKS),
+                    self.mirrored(calibration::USER_IMU),
+                );
+                return Ok(());
+            }
+            Step::Leds(state) => return self.set_leds(state).map(|_| ()),
         };
+        self.awaiting = Some(Awaiting {
+            subcommand: u8::from(&sub),
+            address,
+            sent: Instant::now(),
+            answered: false,
+        });
+        self.send(sub).map(|_| ())
+    }
 
-        // TODO Find a way to guarantee this isn't racing other input packets
-        jc.flush()
-            .and_then(|_| jc.set_input_mode(InputMode::Simple))
-            .and_then(|_| jc.await_input())
-            .and_then(|_| jc.get_device_info())
-            .and_then(|_| jc.await_input())
-            .and_then(|_| jc.read_spi(0x6050, 6))
-            .and_then(|_| jc.await_input())
-            .and_then(|_| Ok(jc))
+    // Marks the awaited setup step as answered, given a subcommand reply
+    fn note_reply(&mut self, data: &[u8]) {
+        if let Some(ref mut awaiting) = self.awaiting {
+            let address = LittleEndian::read_u32(&data[2..6]);
+            if data[1] == awaiting.subcommand
+                && awaiting.address.is_none_or(|expected| expected == address)
+            {
+                awaiting.answered = true;
+            }
+        }
     }
 
     /// Read and handle all buffered inputs. Blocks until the queue is emptied.
@@ -128,7 +273,7 @@ impl Driver {
     pub fn flush(&mut self) -> Result<usize, HidError> {
         let mut count = 0;
         loop {
-            match self.handle_input() {
+            match self.handle_input(0) {
                 Ok(None) => return Ok(count),
                 Err(e) => return Err(e),
                 _ => count += 1,
@@ -136,31 +281,15 @@ impl Driver {
         }
     }
 
-    /// Block until the next input packet and handle it with `handle_input()`
-    fn await_input(&mut self) -> Result<usize, HidError> {
-        // TODO Investigate whether `set_blocking_mode()` introduces any overhead
-        if let Err(e) = self.device.set_blocking_mode(true) {
-            return Err(e);
-        };
-        let result = match self.handle_input() {
-            Ok(Some(value)) => Ok(value),
-            Ok(None) => Ok(0),
-            Err(e) => Err(e),
-        };
-        if let Err(e) = self.device.set_blocking_mode(false) {
-            return Err(e);
-        };
-        result
-    }
-
     /// Receive an input packet, read its input report code, and handle the rest
     /// of its data appropriately. Callers cannot access this data directly;
     /// instead, the data is saved to the controller's state and can be read
-    /// after `handle_input()` returns.
-    fn handle_input(&mut self) -> Result<Option<usize>, HidError> {
+    /// after `handle_input()` returns. Waits up to `timeout` milliseconds for
+    /// a packet, or forever if it's negative.
+    fn handle_input(&mut self, timeout: i32) -> Result<Option<usize>, HidError> {
         let mut buf = self.read_buffer;
 
-        let len = match self.device.read(&mut buf[..]) {
+        let len = match self.device.read_timeout(&mut buf[..], timeout) {
             Ok(0) => return Ok(None),
             Err(e) => return Err(e),
             Ok(len) => len,
@@ -169,22 +298,60 @@ impl Driver {
         let report = InputReport::from(&buf[..]);
         match report {
             InputReport::CommandResponse {
-                battery: _,
+                timer,
+                battery,
                 frame,
                 data,
             } => {
-                self.frames.push_back(frame);
+                self.battery = Some(battery);
+                self.push_frame(timer, frame);
+                self.note_reply(&buf[13..49]);
                 self.handle_response(data);
             }
-            InputReport::ExtendedInput { battery: _, frame } => {
-                self.frames.push_back(frame);
-                ()
+            InputReport::ExtendedInput {
+                timer,
+                battery,
+                frame,
+            } => {
+                self.battery = Some(battery);
+                self.push_frame(timer, frame);
             }
             _ => (),
         }
         Ok(Some(len))
     }
 
+    fn push_frame(&mut self, timer: u8, mut frame: InputFrame) {
+        frame.time = self.events.record(timer, frame.buttons);
+        self.uncalibrated = frame;
+        self.calibration.apply(&mut frame);
+        self.stats.record_report(timer, frame.received);
+        self.current = frame;
+        self.frames.push_back(frame);
+    }
+
+    /// Take the oldest input frame that hasn't been consumed yet
+    pub fn next_frame(&mut self) -> Option<InputFrame> {
+        let frame = self.frames.pop_front();
+        if let Some(ref frame) = frame {
+            self.stats.record_latency(frame.received.elapsed());
+        }
+        frame
+    }
+
+    /// Take the oldest button press or release that hasn't been consumed yet,
+    /// along with when the controller reported it. Every frame is diffed as
+    /// it's read, so taps shorter than the caller's polling interval still
+    /// show up here.
+    pub fn next_event(&mut self) -> Option<(Duration, ButtonEvent)> {
+        self.events.take()
+    }
+
+    /// How many presses and releases were dropped for not being taken in time
+    pub fn dropped_events(&self) -> u64 {
+        self.events.dropped()
+    }
+
     fn handle_response(&mut self, data: ResponseData) {
         match data {
             ResponseData::RequestDeviceInfo {
@@ -197,6 +364,15 @@ impl Driver {
                 self.mac_address = Some(mac_address);
             }
             ResponseData::ReadSpi(chunk) => self.save_spi_chunk(chunk),
+            ResponseData::GetVoltage(raw) => self.raw_voltage = Some(raw),
+            ResponseData::GetLeds(bitmask) => {
+                self.shown_leds = bitmask;
+                self.reported_leds = Some(bitmask);
+                // Leave the LEDs as they were until told otherwise
+                if !self.leds_set {
+                    self.leds = LedController::new(LedState::Raw(bitmask));
+                }
+            }
             ResponseData::Unknown(buf) => {
                 log::e(&format!(
                     "Received unknown response ACK {}",
@@ -210,27 +386,186 @@ impl Driver {
 
     fn save_spi_chunk(&mut self, chunk: SpiChunk) {
         let SpiChunk(addr, buf) = chunk;
-        let start = (addr - SPI_ORIGIN) as usize;
+        let origin = u32::from(SPI_ORIGIN);
+        let start = addr.wrapping_sub(origin) as usize;
         let end = start + buf.len();
-        self.spi_mirror[start..end].copy_from_slice(buf);
+        if addr >= origin && end <= self.spi_mirror.len() {
+            self.spi_mirror[start..end].copy_from_slice(buf);
+        }
+        self.spi_read = Some((addr, buf.to_vec()));
+    }
+
+    /// Asks for `length` bytes of SPI flash, up to 0x1d, without waiting for
+    /// them. They can be taken with `take_flash` once they've arrived.
+    pub fn request_flash(&mut self, addr: u32, length: usize) -> Result<(), HidError> {
+        self.spi_read = None;
+        self.read_spi(addr, length).map(|_| ())
+    }
+
+    /// Flash asked for by `request_flash`, if it's arrived
+    pub fn take_flash(&mut self, addr: u32) -> Option<Vec<u8>> {
+        match self.spi_read.take() {
+            Some((read, buf)) if read == addr => Some(buf),
+            other => {
+                self.spi_read = other;
+                None
+            }
+        }
+    }
+
+    // A range of flash that's been read into the mirror
+    fn mirrored(&self, (addr, length): (u32, usize)) -> &[u8] {
+        let start = (addr - u32::from(SPI_ORIGIN)) as usize;
+        &self.spi_mirror[start..start + length]
+    }
+
+    /// Load the stick and gyroscope calibration stored in the controller's
+    /// flash, blocking until it's read. `setup::CALIBRATE` does the same
+    /// through `poll_setup`.
+    pub fn load_calibration(&mut self) -> Result<(), HidError> {
+        self.start_setup(&setup::CALIBRATE);
+        self.finish_setup()
+    }
+
+    /// Take the current stick positions and gyroscope reading as neutral. The
+    /// controller should be resting flat with its sticks untouched.
+    pub fn calibrate(&mut self) {
+        self.calibration = self.calibration.at_rest(&self.uncalibrated);
     }
 
     pub fn serial_number(&self) -> &str {
         &self.serial_number
     }
 
-    pub fn set_leds(&self, bitmask: u8) -> Result<usize, HidError> {
-        if bitmask == self.leds.replace(bitmask) {
+    /// Most recent input frame, with calibration applied
+    pub fn frame(&self) -> &InputFrame {
+        &self.current
+    }
+
+    /// Bitmask the player LEDs are showing
+    pub fn leds(&self) -> u8 {
+        self.shown_leds
+    }
+
+    /// Bitmask the controller last said its player LEDs show, which is read
+    /// when it connects, and again with `read_leds`
+    pub fn reported_leds(&self) -> Option<u8> {
+        self.reported_leds
+    }
+
+    /// Asks the controller what its player LEDs show. The answer arrives
+    /// with later input, after which `update_leds` puts right any difference
+    /// from the current state.
+    pub fn read_leds(&self) -> Result<usize, HidError> {
+        let cmd = DoCommand(self.next_packet(), &self.rumble.get(), GetLeds);
+        self.device.write(&<Vec<u8>>::from(cmd))
+    }
+
+    pub fn led_state(&self) -> LedState {
+        self.leds.state()
+    }
+
+    /// Battery and connection from the latest full report, if there's been one
+    pub fn battery(&self) -> Option<BatteryState> {
+        self.battery
+    }
+
+    /// Battery voltage in millivolts, once `poll_voltage` has had an answer
+    pub fn voltage(&self) -> Option<u16> {
+        self.raw_voltage.map(battery::millivolts)
+    }
+
+    /// How full the battery is, from 0 to 100, estimated from its voltage
+    pub fn battery_percent(&self) -> Option<u8> {
+        self.raw_voltage.map(battery::percent)
+    }
+
+    /// Asks for the battery voltage every `VOLTAGE_INTERVAL`. The answer
+    /// arrives with later input, so call this along with `flush`.
+    pub fn poll_voltage(&mut self) -> Result<(), HidError> {
+        if self
+            .voltage_requested
+            .is_some_and(|requested| requested.elapsed() < VOLTAGE_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.voltage_requested = Some(Instant::now());
+        let cmd = DoCommand(self.next_packet(), &self.rumble.get(), GetVoltage);
+        self.device.write(&<Vec<u8>>::from(cmd)).map(|_| ())
+    }
+
+    pub fn firmware_version(&self) -> Option<u16> {
+        self.firmware_version
+    }
+
+    pub fn mac_address(&self) -> Option<u64> {
+        self.mac_address
+    }
+
+    /// Packet loss, timing and latency for recent input reports
+    pub fn stats(&self) -> StatsSummary {
+        self.stats.summary()
+    }
+
+    pub fn product(&self) -> Option<Product> {
+        self.product
+    }
+
+    pub fn set_leds(&mut self, state: LedState) -> Result<usize, HidError> {
+        self.leds_set = true;
+        self.leds.set(state);
+        self.update_leds()
+    }
+
+    /// Steps the player LEDs through their sequence, if they're showing one.
+    /// Call it regularly, e.g. along with `flush`.
+    pub fn update_leds(&mut self) -> Result<usize, HidError> {
+        let bitmask = self.leds.bitmask();
+        if bitmask == self.shown_leds {
             return Ok(0);
         }
         let sub = SetLeds(bitmask);
-        let cmd = DoCommand(self.rumble_counter.get(), &NEUTRAL_RUMBLE, sub);
+        let cmd = DoCommand(self.next_packet(), &self.rumble.get(), sub);
+        let written = self.device.write(&<Vec<u8>>::from(cmd))?;
+        self.shown_leds = bitmask;
+        Ok(written)
+    }
+
+    /// Play `pattern` on the light around the Home button. Controllers
+    /// without one, per `Product::has_home_light`, ignore it.
+    pub fn set_home_light(&self, pattern: &Pattern) -> Result<usize, HidError> {
+        let sub = SetHomeLight(pattern.encode());
+        let cmd = DoCommand(self.next_packet(), &self.rumble.get(), sub);
         self.device.write(&<Vec<u8>>::from(cmd))
     }
 
+    /// Play a tone on both actuators until the next call. An amplitude of 0
+    /// stops rumbling.
+    pub fn rumble(&self, frequency: f32, amplitude: f32) -> Result<usize, HidError> {
+        let side = output::encode_rumble(frequency, amplitude);
+        let mut rumble = [0; 8];
+        rumble[..4].copy_from_slice(&side);
+        rumble[4..].copy_from_slice(&side);
+        self.rumble.set(rumble);
+        let report = Rumble(self.next_packet(), &rumble);
+        self.device.write(&<Vec<u8>>::from(report))
+    }
+
     pub fn set_input_mode(&self, mode: InputMode) -> Result<usize, HidError> {
         let sub = SetInputMode(mode);
-        let cmd = DoCommand(self.rumble_counter.get(), &NEUTRAL_RUMBLE, sub);
+        let cmd = DoCommand(self.next_packet(), &self.rumble.get(), sub);
+        self.device.write(&<Vec<u8>>::from(cmd))
+    }
+
+    pub fn enable_imu(&self, enabled: bool) -> Result<usize, HidError> {
+        let sub = EnableImu(enabled);
+        let cmd = DoCommand(self.next_packet(), &self.rumble.get(), sub);
+        self.device.write(&<Vec<u8>>::from(cmd))
+    }
+
+    pub fn enable_vibration(&self, enabled: bool) -> Result<usize, HidError> {
+        let sub = EnableVibration(enabled);
+        let cmd = DoCommand(self.next_packet(), &self.rumble.get(), sub);
         self.device.write(&<Vec<u8>>::from(cmd))
     }
 
@@ -238,21 +573,33 @@ impl Driver {
         if let Err(e) = self.set_input_mode(InputMode::Simple) {
             return Err(e);
         };
-        let sub = SetHciState(HciState::Reconnect);
-        let cmd = DoCommand(self.rumble_counter.get(), &NEUTRAL_RUMBLE, sub);
+        self.set_hci_state(HciState::Reconnect)
+    }
+
+    /// Disconnects the controller, which then goes to sleep, reconnects or
+    /// waits to be paired depending on `state`. Either way this driver is
+    /// done with, since the device goes away.
+    pub fn set_hci_state(&self, state: HciState) -> Result<usize, HidError> {
+        let sub = SetHciState(state);
+        let cmd = DoCommand(self.next_packet(), &self.rumble.get(), sub);
         self.device.write(&<Vec<u8>>::from(cmd))
     }
 
-    fn get_device_info(&self) -> Result<usize, HidError> {
-        let sub = RequestDeviceInfo;
-        let cmd = DoCommand(self.rumble_counter.get(), &NEUTRAL_RUMBLE, sub);
-        let buf = &<Vec<u8>>::from(cmd);
-        self.device.write(buf)
+    fn send(&self, sub: Command) -> Result<usize, HidError> {
+        let cmd = DoCommand(self.next_packet(), &self.rumble.get(), sub);
+        self.device.write(&<Vec<u8>>::from(cmd))
+    }
+
+    /// Output reports carry a 4-bit counter, which the controller uses to spot repeats
+    fn next_packet(&self) -> u8 {
+        let counter = self.rumble_counter.get();
+        self.rumble_counter.set((counter + 1) & 0xf);
+        counter
     }
 
     fn read_spi(&self, addr: u32, length: usize) -> Result<usize, HidError> {
         let sub = ReadSpi(addr, length);
-        let cmd = DoCommand(self.rumble_counter.get(), &NEUTRAL_RUMBLE, sub);
+        let cmd = DoCommand(self.next_packet(), &self.rumble.get(), sub);
         let buf = &<Vec<u8>>::from(cmd);
         self.device.write(buf)
     }
@@ -284,12 +631,9 @@ impl Driver {
 
 impl Has<Button> for Driver {
     fn has(&self, btn: Button) -> bool {
-        let real_btn = self.product.and_then(|product| btn.to_real(product));
-        let last_frame = self.frames.back();
-
-        match (real_btn, last_frame) {
-            (Some(btn), Some(frame)) => frame.buttons.has(btn),
-            _ => false,
+        match self.product.and_then(|product| btn.to_real(product)) {
+            Some(btn) => self.current.buttons.has(btn),
+            None => false,
         }
     }
 }
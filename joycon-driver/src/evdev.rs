@@ -0,0 +1,80 @@
+use uinput;
+use uinput::event::{absolute, controller, keyboard, relative, Event};
+
+// Range reported for absolute axes other than hats, matching a signed 16-bit stick
+pub const AXIS_MAX: i32 = 32767;
+
+/// A uinput device that emits evdev events on behalf of a controller
+pub struct VirtualDevice {
+    device: uinput::Device,
+}
+
+impl VirtualDevice {
+    /// Creates a virtual device capable of emitting each of the given events
+    pub fn new(name: &str, events: &[Event]) -> Result<VirtualDevice, uinput::Error> {
+        let mut builder = uinput::default()?.name(name)?;
+        for &event in events {
+            builder = builder.event(event)?;
+            if let Event::Absolute(axis) = event {
+                let (min, max) = axis_range(axis);
+                builder = builder.min(min).max(max);
+            }
+        }
+        builder.create().map(|device| VirtualDevice { device })
+    }
+
+    pub fn send(&mut self, event: Event, value: i32) -> Result<(), uinput::Error> {
+        self.device.send(event, value)
+    }
+
+    /// Marks the end of a group of events, which the kernel delivers together
+    pub fn sync(&mut self) -> Result<(), uinput::Error> {
+        self.device.synchronize()
+    }
+}
+
+fn axis_range(axis: absolute::Absolute) -> (i32, i32) {
+    match axis {
+        absolute::Absolute::Hat(_) => (-1, 1),
+        _ => (-AXIS_MAX, AXIS_MAX),
+    }
+}
+
+/// Parses an event from a `kind:Name` pair, where `Name` is the variant name
+/// used by the `uinput` crate, e.g. `key:LeftControl`, `btn:South`,
+/// `mouse:Left`, `rel:X`, `wheel:Vertical`, `abs:RX` or `hat:X0`
+pub fn parse_event(name: &str) -> Result<Event, String> {
+    let mut parts = name.splitn(2, ':');
+    let (kind, variant) = match (parts.next(), parts.next()) {
+        (Some(kind), Some(variant)) => (kind.trim().to_lowercase(), variant.trim()),
+        _ => return Err(format!("Event \"{}\" should look like \"kind:Name\"", name)),
+    };
+
+    let event = match kind.as_str() {
+        "key" => find(keyboard::Key::iter_variants(), variant),
+        "keypad" => find(keyboard::KeyPad::iter_variants(), variant),
+        "media" => find(keyboard::Misc::iter_variants(), variant),
+        "fn" => find(keyboard::Function::iter_variants(), variant),
+        "btn" => find(controller::GamePad::iter_variants(), variant),
+        "dpad" => find(controller::DPad::iter_variants(), variant),
+        "mouse" => find(controller::Mouse::iter_variants(), variant),
+        "rel" => find(relative::Position::iter_variants(), variant),
+        "wheel" => find(relative::Wheel::iter_variants(), variant),
+        "abs" => find(absolute::Position::iter_variants(), variant),
+        "hat" => find(absolute::Hat::iter_variants(), variant),
+        _ => return Err(format!("Unknown event kind \"{}\" in \"{}\"", kind, name)),
+    };
+    event.ok_or_else(|| format!("Unknown event \"{}\"", name))
+}
+
+fn find<T, I>(variants: I, name: &str) -> Option<Event>
+where
+    T: Into<Event> + ::std::fmt::Debug,
+    I: Iterator<Item = T>,
+{
+    let name = name.to_lowercase();
+    variants
+        .into_iter()
+        .find(|v| format!("{:?}", v).to_lowercase() == name)
+        .map(|v| v.into())
+}
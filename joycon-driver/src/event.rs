@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+use arraydeque::{ArrayDeque, Wrapping};
+
+use common::has::Has;
+
+use super::button::{Button, REAL_BUTTONS};
+use super::frame::ButtonFrame;
+use super::timer::ReportClock;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ButtonEvent {
+    Pressed(Button),
+    Released(Button),
+}
+
+/// Every press and release between two consecutive frames
+pub fn diff<'a>(
+    previous: &'a ButtonFrame,
+    next: &'a ButtonFrame,
+) -> impl Iterator<Item = ButtonEvent> + 'a {
+    REAL_BUTTONS
+        .iter()
+        .filter_map(move |&btn| match (previous.has(btn), next.has(btn)) {
+            (false, true) => Some(ButtonEvent::Pressed(btn)),
+            (true, false) => Some(ButtonEvent::Released(btn)),
+            _ => None,
+        })
+}
+
+/// Presses and releases that haven't been taken yet, timed by the report
+/// timer. If nobody takes them, the oldest make way for new ones once 256
+/// are waiting, and are counted as dropped.
+#[derive(Default)]
+pub struct EventLog {
+    clock: ReportClock,
+    buttons: ButtonFrame,
+    events: ArrayDeque<[(Duration, ButtonEvent); 256], Wrapping>,
+    dropped: u64,
+}
+
+impl EventLog {
+    /// Log what changed since the last report, returning this one's time
+    pub fn record(&mut self, timer: u8, buttons: ButtonFrame) -> Duration {
+        let time = self.clock.tick(timer);
+        for event in diff(&self.buttons, &buttons) {
+            if self.events.push_back((time, event)).is_some() {
+                self.dropped += 1;
+            }
+        }
+        self.buttons = buttons;
+        time
+    }
+
+    pub fn take(&mut self) -> Option<(Duration, ButtonEvent)> {
+        self.events.pop_front()
+    }
+
+    /// How many events were pushed out before being taken
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Button::*;
+    use super::*;
+
+    fn frame(buttons: &[Button]) -> ButtonFrame {
+        ButtonFrame(buttons.iter().fold(0, |mask, &btn| mask | u32::from(btn)))
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn diffs_frames() {
+        let events: Vec<_> = diff(&frame(&[A, L]), &frame(&[A, B])).collect();
+        assert_eq!(
+            events,
+            vec![ButtonEvent::Pressed(B), ButtonEvent::Released(L)]
+        );
+    }
+
+    #[test]
+    fn times_events_by_the_report_timer() {
+        let mut log = EventLog::default();
+        assert_eq!(log.record(10, frame(&[])), ms(0));
+        // Three ticks of 5ms later
+        assert_eq!(log.record(13, frame(&[A])), ms(15));
+        assert_eq!(log.record(16, frame(&[A, B])), ms(30));
+        assert_eq!(log.record(19, frame(&[B])), ms(45));
+        assert_eq!(log.take(), Some((ms(15), ButtonEvent::Pressed(A))));
+        assert_eq!(log.take(), Some((ms(30), ButtonEvent::Pressed(B))));
+        assert_eq!(log.take(), Some((ms(45), ButtonEvent::Released(A))));
+        assert_eq!(log.take(), None);
+    }
+
+    #[test]
+    fn keeps_time_across_timer_wraparound() {
+        let mut log = EventLog::default();
+        log.record(0xfd, frame(&[]));
+        assert_eq!(log.record(0x00, frame(&[Zr])), ms(15));
+        assert_eq!(log.record(0x03, frame(&[])), ms(30));
+        assert_eq!(log.take(), Some((ms(15), ButtonEvent::Pressed(Zr))));
+        assert_eq!(log.take(), Some((ms(30), ButtonEvent::Released(Zr))));
+    }
+
+    #[test]
+    fn keeps_events_taken_in_time() {
+        let mut log = EventLog::default();
+        let mut events = Vec::new();
+        for i in 0..500u32 {
+            let buttons = if i % 2 == 0 { frame(&[A]) } else { frame(&[]) };
+            log.record(i as u8, buttons);
+            if i % 100 == 99 {
+                events.extend((0..).map_while(|_| log.take()));
+            }
+        }
+        assert_eq!(events.len(), 500);
+        assert_eq!(events[0], (ms(0), ButtonEvent::Pressed(A)));
+        // 499 ticks, across the timer wrapping around
+        assert_eq!(events[499], (ms(2495), ButtonEvent::Released(A)));
+        assert_eq!(log.dropped(), 0);
+    }
+
+    #[test]
+    fn drops_the_oldest_events_nobody_takes() {
+        let mut log = EventLog::default();
+        for i in 0..500u32 {
+            let buttons = if i % 2 == 0 { frame(&[A]) } else { frame(&[]) };
+            log.record(i as u8, buttons);
+        }
+        let events: Vec<_> = (0..).map_while(|_| log.take()).collect();
+        assert_eq!(events.len(), 256);
+        assert_eq!(log.dropped(), 244);
+        assert_eq!(events[0], (ms(1220), ButtonEvent::Pressed(A)));
+        assert_eq!(events[255], (ms(2495), ButtonEvent::Released(A)));
+    }
+}
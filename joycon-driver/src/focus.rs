@@ -0,0 +1,22 @@
+use std::process::Command;
+
+/// Looks up the window class of the focused X11 window with `xprop`. Returns
+/// `None` outside of X11, or when `xprop` isn't installed.
+pub fn active_application() -> Option<String> {
+    // _NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007
+    let window = xprop(&["-root", "_NET_ACTIVE_WINDOW"])?;
+    let id = window.rsplit(' ').next()?.trim().to_string();
+
+    // WM_CLASS(STRING) = "libreoffice", "libreoffice-impress"
+    let class = xprop(&["-id", &id, "WM_CLASS"])?;
+    class.split('"').nth(3).map(String::from)
+}
+
+fn xprop(args: &[&str]) -> Option<String> {
+    match Command::new("xprop").args(args).output() {
+        Ok(ref output) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+        _ => None,
+    }
+}
@@ -1,22 +1,33 @@
+use std::time::{Duration, Instant};
+
 use byteorder::{ByteOrder, LittleEndian};
 
 use common::has::Has;
+use common::ipc::proxy::InputSample;
 
 use super::axis::Axis;
 use super::button::Button;
 
+#[derive(Copy, Clone)]
 pub struct InputFrame {
+    /// When the controller sent this frame, relative to its first report
+    pub time: Duration,
+    /// When the host read this frame
+    pub received: Instant,
     pub buttons: ButtonFrame,
     pub axes: AxisFrame,
-    pub motion: MotionFrame,
+    /// `None` for replies to subcommands, which carry no motion data
+    pub motion: Option<MotionFrame>,
 }
 
 impl InputFrame {
     pub fn new() -> InputFrame {
         InputFrame {
+            time: Duration::from_secs(0),
+            received: Instant::now(),
             buttons: Default::default(),
             axes: AxisFrame::new(),
-            motion: MotionFrame::new(),
+            motion: None,
         }
     }
 }
@@ -25,22 +36,59 @@ impl From<&[u8]> for InputFrame {
     fn from(buf: &[u8]) -> InputFrame {
         let buttons = if buf.len() >= 3 { &buf[0..3] } else { &[0; 3] };
         let axes = if buf.len() >= 9 { &buf[3..9] } else { &[0; 6] };
-        // TODO We actually get 3 motion frames here, should probably average them
-        let motion = if buf.len() >= 45 {
-            &buf[9..45]
+        // Byte 9 is the vibrator's input report, motion data follows it
+        let motion = if buf.len() >= 46 {
+            Some(MotionFrame::from(&buf[10..46]))
         } else {
-            &[0; 36]
+            None
         };
 
         InputFrame {
+            time: Duration::from_secs(0),
+            received: Instant::now(),
             buttons: ButtonFrame::from(buttons),
             axes: AxisFrame::from(axes),
-            motion: MotionFrame::from(motion),
+            motion,
+        }
+    }
+}
+
+impl From<&InputFrame> for InputSample {
+    /// Proxies don't map motion, so frames without it are sent as still
+    fn from(frame: &InputFrame) -> InputSample {
+        let motion = frame.motion.unwrap_or_else(MotionFrame::new);
+        let (ax, ay, az) = motion.accelerometer;
+        let (gx, gy, gz) = motion.gyroscope;
+        InputSample {
+            time_us: frame.time.as_micros() as u64,
+            buttons: frame.buttons.0,
+            sticks: [frame.axes.lx, frame.axes.ly, frame.axes.rx, frame.axes.ry],
+            accelerometer: [ax, ay, az],
+            gyroscope: [gx, gy, gz],
         }
     }
 }
 
-#[derive(Default)]
+impl From<&InputSample> for InputFrame {
+    /// Rebuilds a frame sent from another process, taking it as received now
+    fn from(sample: &InputSample) -> InputFrame {
+        let [lx, ly, rx, ry] = sample.sticks;
+        let [ax, ay, az] = sample.accelerometer;
+        let [gx, gy, gz] = sample.gyroscope;
+        InputFrame {
+            time: Duration::from_micros(sample.time_us),
+            received: Instant::now(),
+            buttons: ButtonFrame(sample.buttons),
+            axes: AxisFrame { rx, ry, lx, ly },
+            motion: Some(MotionFrame {
+                accelerometer: (ax, ay, az),
+                gyroscope: (gx, gy, gz),
+            }),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Default)]
 pub struct ButtonFrame(pub u32);
 
 impl From<&[u8]> for ButtonFrame {
@@ -55,6 +103,7 @@ impl Has<Button> for ButtonFrame {
     }
 }
 
+#[derive(Copy, Clone)]
 pub struct AxisFrame {
     pub rx: u16,
     pub ry: u16,
@@ -62,15 +111,18 @@ pub struct AxisFrame {
     pub ly: u16,
 }
 
-// FIXME this needs to handle 4 axes per frame instead of 2
 impl From<&[u8]> for AxisFrame {
+    /// Reads both sticks, left then right, packed as two 12-bit values each
     fn from(buf: &[u8]) -> AxisFrame {
-        AxisFrame {
-            rx: buf[0] as u16 | ((buf[1] as u16 & 0xf) << 8),
-            ry: (buf[1] as u16 >> 4) | ((buf[2] as u16) << 4),
-            lx: 0,
-            ly: 0,
-        }
+        let stick = |b: &[u8]| {
+            (
+                b[0] as u16 | ((b[1] as u16 & 0xf) << 8),
+                (b[1] as u16 >> 4) | ((b[2] as u16) << 4),
+            )
+        };
+        let (lx, ly) = stick(&buf[0..3]);
+        let (rx, ry) = stick(&buf[3..6]);
+        AxisFrame { rx, ry, lx, ly }
     }
 }
 
@@ -85,7 +137,7 @@ impl AxisFrame {
         }
     }
 
-    fn get(&self, axis: Axis) -> u16 {
+    pub fn get(&self, axis: Axis) -> u16 {
         match axis {
             Axis::Rx => self.rx,
             Axis::Ry => self.ry,
@@ -95,33 +147,37 @@ impl AxisFrame {
     }
 }
 
+/// Accelerometer and gyroscope readings, in the IMU's raw units
+#[derive(Copy, Clone)]
 pub struct MotionFrame {
-    accelerometer: (u16, u16, u16),
-    gyroscope: (u16, u16, u16),
+    pub accelerometer: (i16, i16, i16),
+    pub gyroscope: (i16, i16, i16),
 }
 
 impl MotionFrame {
     pub fn new() -> MotionFrame {
         MotionFrame {
-            accelerometer: (0x8000, 0x8000, 0x8000),
-            gyroscope: (0x8000, 0x8000, 0x8000),
+            accelerometer: (0, 0, 0),
+            gyroscope: (0, 0, 0),
         }
     }
 }
 
 impl From<&[u8]> for MotionFrame {
+    /// Each report carries three IMU samples taken 5ms apart. They're averaged
+    /// into one frame, which keeps the integral over the report period intact.
     fn from(buf: &[u8]) -> MotionFrame {
+        let mut sums = [0i32; 6];
+        for sample in buf.chunks(12).take(3) {
+            for (i, sum) in sums.iter_mut().enumerate() {
+                *sum += i32::from(LittleEndian::read_i16(&sample[i * 2..i * 2 + 2]));
+            }
+        }
+        let avg = |i: usize| (sums[i] / 3) as i16;
+
         MotionFrame {
-            accelerometer: (
-                LittleEndian::read_u16(&buf[0..2]),
-                LittleEndian::read_u16(&buf[2..4]),
-                LittleEndian::read_u16(&buf[4..6]),
-            ),
-            gyroscope: (
-                LittleEndian::read_u16(&buf[6..8]),
-                LittleEndian::read_u16(&buf[8..10]),
-                LittleEndian::read_u16(&buf[10..12]),
-            ),
+            accelerometer: (avg(0), avg(1), avg(2)),
+            gyroscope: (avg(3), avg(4), avg(5)),
         }
     }
 }
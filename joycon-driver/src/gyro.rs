@@ -0,0 +1,439 @@
+use std::ops::{Add, Mul, Sub};
+use std::str::FromStr;
+
+use arraydeque::{ArrayDeque, Wrapping};
+
+use common::has::Has;
+
+use super::button::Button;
+use super::frame::InputFrame;
+use super::id::Product;
+
+// Degrees per second for each raw gyroscope unit, at the IMU's default ±2000dps range
+const GYRO_DPS_PER_UNIT: f32 = 0.070;
+
+// Time covered by the three IMU samples in one input report
+const FRAME_SECONDS: f32 = 0.015;
+
+// How quickly the gravity estimate follows the accelerometer, per frame
+const GRAVITY_FOLLOW: f32 = 0.05;
+
+// The controller counts as resting while it turns slower than this many
+// degrees per second, once its bias is removed...
+const REST_SPEED: f32 = 2.0;
+
+// ...and the accelerometer strays less than this fraction of gravity from its
+// average, which a hand holding the controller still doesn't manage
+const REST_SHAKE: f32 = 0.02;
+
+// How quickly the bias estimate follows the gyroscope while resting, per frame
+const BIAS_FOLLOW: f32 = 0.02;
+
+// Player space lets yaw exceed its world space component by this factor, so
+// turning still feels natural when the controller is held at an angle
+const YAW_RELAX_FACTOR: f32 = 1.41;
+
+/// Which axis counts as "yaw" when turning angular velocity into horizontal motion
+#[derive(Copy, Clone, Debug)]
+pub enum YawSpace {
+    /// Rotation around the controller's own vertical axis
+    Local,
+    /// Rotation around the axis of gravity, regardless of how the controller is held
+    World,
+    /// A blend of local yaw and roll, steered by gravity
+    Player,
+}
+
+impl FromStr for YawSpace {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<YawSpace, String> {
+        match name.to_lowercase().as_str() {
+            "local" => Ok(YawSpace::Local),
+            "world" => Ok(YawSpace::World),
+            "player" => Ok(YawSpace::Player),
+            _ => Err(format!("Unknown yaw space \"{}\"", name)),
+        }
+    }
+}
+
+/// Raises sensitivity linearly from `GyroSettings::sensitivity` at
+/// `slow_threshold` to `fast_sensitivity` at `fast_threshold`, both in degrees
+/// per second
+#[derive(Copy, Clone, Debug)]
+pub struct Acceleration {
+    pub fast_sensitivity: f32,
+    pub slow_threshold: f32,
+    pub fast_threshold: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct GyroSettings {
+    /// Mouse counts per degree turned
+    pub sensitivity: f32,
+    pub acceleration: Option<Acceleration>,
+    /// Turning speeds below this many degrees per second are smoothed out,
+    /// hiding hand tremor without adding latency to fast movements
+    pub smoothing_threshold: f32,
+    /// While held, this button pauses tracking so the controller can be re-centered
+    pub ratchet: Option<Button>,
+    pub space: YawSpace,
+}
+
+impl Default for GyroSettings {
+    fn default() -> GyroSettings {
+        GyroSettings {
+            sensitivity: 8.0,
+            acceleration: None,
+            smoothing_threshold: 5.0,
+            ratchet: None,
+            space: YawSpace::Player,
+        }
+    }
+}
+
+/// Converts a controller's angular velocity into relative mouse motion
+pub struct GyroMouse {
+    settings: GyroSettings,
+    gravity: Vector,
+    // What the gyroscope reads at rest, in degrees per second, which drifts
+    // with temperature after the flash calibration is taken off
+    bias: Vector,
+    // Recent slow movements, averaged by the smoothing filter (~120ms)
+    history: ArrayDeque<[(f32, f32); 8], Wrapping>,
+    // Fractions of a count that haven't been emitted yet
+    remainder: (f32, f32),
+}
+
+impl GyroMouse {
+    pub fn new(settings: GyroSettings) -> GyroMouse {
+        GyroMouse {
+            settings,
+            gravity: Vector::default(),
+            bias: Vector::default(),
+            history: ArrayDeque::new(),
+            remainder: (0.0, 0.0),
+        }
+    }
+
+    /// Consume one input frame, returning the mouse motion it produced in
+    /// whole counts. Frames without motion, e.g. replies to subcommands,
+    /// produce none and leave the gravity and bias estimates alone.
+    pub fn update(&mut self, frame: &InputFrame, product: Option<Product>) -> (i32, i32) {
+        let motion = match frame.motion {
+            Some(motion) => motion,
+            None => return (0, 0),
+        };
+        let gyro = Vector::from(motion.gyroscope) * GYRO_DPS_PER_UNIT;
+        let accel = Vector::from(motion.accelerometer);
+        self.gravity = self.gravity * (1.0 - GRAVITY_FOLLOW) + accel * GRAVITY_FOLLOW;
+        let gyro = self.remove_bias(gyro, accel);
+
+        let ratcheting = self
+            .settings
+            .ratchet
+            .and_then(|btn| product.and_then(|product| btn.to_real(product)))
+            .is_some_and(|btn| frame.buttons.has(btn));
+        if ratcheting {
+            self.history.clear();
+            self.remainder = (0.0, 0.0);
+            return (0, 0);
+        }
+
+        let (yaw, pitch) = self.project(gyro);
+        let (yaw, pitch) = self.smooth(yaw, pitch);
+        let sensitivity = self.sensitivity(yaw.hypot(pitch)) * FRAME_SECONDS;
+
+        // Turning right is a negative yaw, tilting up is a positive pitch
+        let dx = self.remainder.0 - yaw * sensitivity;
+        let dy = self.remainder.1 - pitch * sensitivity;
+        self.remainder = (dx.fract(), dy.fract());
+        (dx.trunc() as i32, dy.trunc() as i32)
+    }
+
+    /// Take the estimated bias off a gyroscope reading, first moving the
+    /// estimate towards the reading if the controller is resting
+    fn remove_bias(&mut self, gyro: Vector, accel: Vector) -> Vector {
+        let turn = gyro - self.bias;
+        let shake = accel - self.gravity;
+        let resting = turn.dot(turn) < REST_SPEED * REST_SPEED
+            && shake.dot(shake) < REST_SHAKE * REST_SHAKE * self.gravity.dot(self.gravity);
+        if resting {
+            self.bias = self.bias * (1.0 - BIAS_FOLLOW) + gyro * BIAS_FOLLOW;
+        }
+        gyro - self.bias
+    }
+
+    /// Split angular velocity into yaw and pitch according to the configured yaw space
+    fn project(&self, gyro: Vector) -> (f32, f32) {
+        let up = self.gravity.normalized();
+        let yaw = match self.settings.space {
+            YawSpace::Local => gyro.yaw,
+            YawSpace::World => up.dot(gyro),
+            YawSpace::Player => {
+                let world_yaw = gyro.yaw * up.yaw + gyro.roll * up.roll;
+                let local_yaw = gyro.yaw.hypot(gyro.roll);
+                world_yaw.signum() * (world_yaw.abs() * YAW_RELAX_FACTOR).min(local_yaw)
+            }
+        };
+        (yaw, gyro.pitch)
+    }
+
+    /// Soft tiered smoothing: slow movements are averaged over recent frames,
+    /// fast ones pass straight through, and speeds in between are blended
+    fn smooth(&mut self, yaw: f32, pitch: f32) -> (f32, f32) {
+        let threshold = self.settings.smoothing_threshold;
+        if threshold <= 0.0 {
+            return (yaw, pitch);
+        }
+
+        let lower = threshold / 2.0;
+        let direct = ((yaw.hypot(pitch) - lower) / (threshold - lower)).clamp(0.0, 1.0);
+        self.history
+            .push_back((yaw * (1.0 - direct), pitch * (1.0 - direct)));
+
+        let capacity = self.history.capacity() as f32;
+        let (sum_yaw, sum_pitch) = self
+            .history
+            .iter()
+            .fold((0.0, 0.0), |(y, p), &(dy, dp)| (y + dy, p + dp));
+        (
+            yaw * direct + sum_yaw / capacity,
+            pitch * direct + sum_pitch / capacity,
+        )
+    }
+
+    fn sensitivity(&self, speed: f32) -> f32 {
+        let base = self.settings.sensitivity;
+        match self.settings.acceleration {
+            Some(accel) => {
+                let range = (accel.fast_threshold - accel.slow_threshold).max(f32::EPSILON);
+                let t = ((speed - accel.slow_threshold) / range).clamp(0.0, 1.0);
+                base + (accel.fast_sensitivity - base) * t
+            }
+            None => base,
+        }
+    }
+}
+
+/// A motion reading, rearranged so each component names the rotation it measures
+#[derive(Copy, Clone, Default)]
+struct Vector {
+    pitch: f32,
+    yaw: f32,
+    roll: f32,
+}
+
+impl Vector {
+    fn dot(&self, other: Vector) -> f32 {
+        self.pitch * other.pitch + self.yaw * other.yaw + self.roll * other.roll
+    }
+
+    fn normalized(&self) -> Vector {
+        let length = self.dot(*self).sqrt();
+        if length > 0.0 {
+            *self * (1.0 / length)
+        } else {
+            *self
+        }
+    }
+}
+
+impl From<(i16, i16, i16)> for Vector {
+    fn from((x, y, z): (i16, i16, i16)) -> Vector {
+        Vector {
+            pitch: f32::from(y),
+            yaw: f32::from(z),
+            roll: f32::from(x),
+        }
+    }
+}
+
+impl Add for Vector {
+    type Output = Vector;
+
+    fn add(self, other: Vector) -> Vector {
+        Vector {
+            pitch: self.pitch + other.pitch,
+            yaw: self.yaw + other.yaw,
+            roll: self.roll + other.roll,
+        }
+    }
+}
+
+impl Sub for Vector {
+    type Output = Vector;
+
+    fn sub(self, other: Vector) -> Vector {
+        self + other * -1.0
+    }
+}
+
+impl Mul<f32> for Vector {
+    type Output = Vector;
+
+    fn mul(self, scale: f32) -> Vector {
+        Vector {
+            pitch: self.pitch * scale,
+            yaw: self.yaw * scale,
+            roll: self.roll * scale,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use frame::MotionFrame;
+
+    // Raw accelerometer reading with the controller lying flat
+    const FLAT: (i16, i16, i16) = (0, 0, 4096);
+
+    // Raw gyroscope units for turning at `dps` around the vertical axis
+    fn yaw(dps: f32) -> (i16, i16, i16) {
+        (0, 0, (dps / GYRO_DPS_PER_UNIT).round() as i16)
+    }
+
+    fn frame(gyroscope: (i16, i16, i16)) -> InputFrame {
+        let mut frame = InputFrame::new();
+        frame.motion = Some(MotionFrame {
+            accelerometer: FLAT,
+            gyroscope,
+        });
+        frame
+    }
+
+    fn settled(settings: GyroSettings) -> GyroMouse {
+        let mut mouse = GyroMouse::new(GyroSettings {
+            space: YawSpace::Local,
+            ..settings
+        });
+        // Settle the gravity estimate
+        for _ in 0..200 {
+            mouse.update(&frame((0, 0, 0)), Some(Product::JoyConR));
+        }
+        mouse
+    }
+
+    fn unsmoothed() -> GyroSettings {
+        GyroSettings {
+            smoothing_threshold: 0.0,
+            ..GyroSettings::default()
+        }
+    }
+
+    fn total(mouse: &mut GyroMouse, frame: &InputFrame, frames: usize) -> (i32, i32) {
+        (0..frames).fold((0, 0), |(x, y), _| {
+            let (dx, dy) = mouse.update(frame, Some(Product::JoyConR));
+            (x + dx, y + dy)
+        })
+    }
+
+    #[test]
+    fn turning_right_moves_right() {
+        let mut mouse = settled(unsmoothed());
+        // 100 frames at 100dps is 150 degrees, at 8 counts per degree
+        let (dx, dy) = total(&mut mouse, &frame(yaw(-100.0)), 100);
+        assert!((1195..=1205).contains(&dx), "{}", dx);
+        assert_eq!(dy, 0);
+    }
+
+    #[test]
+    fn resting_bias_is_removed() {
+        let mut mouse = settled(unsmoothed());
+        let drifting = frame(yaw(1.0));
+        total(&mut mouse, &drifting, 1000);
+        assert_eq!(total(&mut mouse, &drifting, 100), (0, 0));
+    }
+
+    #[test]
+    fn turning_isnt_taken_for_bias() {
+        let mut mouse = settled(unsmoothed());
+        let turning = frame(yaw(-10.0));
+        total(&mut mouse, &turning, 1000);
+        let (dx, _) = total(&mut mouse, &turning, 100);
+        assert!((115..=125).contains(&dx), "{}", dx);
+    }
+
+    #[test]
+    fn shaking_isnt_rest() {
+        let mut mouse = settled(unsmoothed());
+        let mut shaking = frame(yaw(-1.0));
+        for i in 0..1000 {
+            if let Some(ref mut motion) = shaking.motion {
+                motion.accelerometer.0 = if i % 2 == 0 { 400 } else { -400 };
+            }
+            mouse.update(&shaking, Some(Product::JoyConR));
+        }
+        assert_eq!(mouse.bias.yaw, 0.0);
+    }
+
+    #[test]
+    fn frames_without_motion_are_skipped() {
+        let mut mouse = settled(unsmoothed());
+        let drifting = frame(yaw(1.0));
+        total(&mut mouse, &drifting, 1000);
+        let bias = mouse.bias.yaw;
+        // Replies to subcommands would otherwise read as resting at 0dps
+        let reply = InputFrame::new();
+        assert_eq!(total(&mut mouse, &reply, 1000), (0, 0));
+        assert_eq!(mouse.bias.yaw, bias);
+        assert_eq!(total(&mut mouse, &drifting, 100), (0, 0));
+    }
+
+    #[test]
+    fn slow_movement_is_smoothed() {
+        let mut mouse = settled(GyroSettings {
+            smoothing_threshold: 40.0,
+            ..GyroSettings::default()
+        });
+        // Below half the threshold, a single frame's motion is spread over
+        // the history rather than arriving at once
+        assert_eq!(
+            mouse.update(&frame(yaw(-15.0)), Some(Product::JoyConR)),
+            (0, 0)
+        );
+        let (dx, _) = total(&mut mouse, &frame(yaw(-15.0)), 7);
+        assert!(dx > 0);
+
+        // Fast movements pass straight through
+        let mut mouse = settled(GyroSettings {
+            smoothing_threshold: 40.0,
+            ..GyroSettings::default()
+        });
+        let (fast, _) = mouse.update(&frame(yaw(-400.0)), Some(Product::JoyConR));
+        assert!((46..=48).contains(&fast), "{}", fast);
+    }
+
+    #[test]
+    fn acceleration_ramps_between_thresholds() {
+        let mouse = GyroMouse::new(GyroSettings {
+            sensitivity: 2.0,
+            acceleration: Some(Acceleration {
+                fast_sensitivity: 10.0,
+                slow_threshold: 50.0,
+                fast_threshold: 150.0,
+            }),
+            ..GyroSettings::default()
+        });
+        assert_eq!(mouse.sensitivity(0.0), 2.0);
+        assert_eq!(mouse.sensitivity(50.0), 2.0);
+        assert_eq!(mouse.sensitivity(100.0), 6.0);
+        assert_eq!(mouse.sensitivity(150.0), 10.0);
+        assert_eq!(mouse.sensitivity(500.0), 10.0);
+    }
+
+    #[test]
+    fn ratchet_pauses_tracking() {
+        let mut mouse = settled(GyroSettings {
+            ratchet: Some(Button::Zr),
+            ..unsmoothed()
+        });
+        let mut held = frame(yaw(-100.0));
+        held.buttons.0 = u32::from(Button::Zr);
+        assert_eq!(total(&mut mouse, &held, 100), (0, 0));
+        let (dx, _) = total(&mut mouse, &frame(yaw(-100.0)), 100);
+        assert!(dx > 0);
+    }
+}
@@ -0,0 +1,124 @@
+//! Patterns for the ring light around the Home button, which right Joy-Con
+//! and Pro Controllers have, in the format of subcommand 0x38. A pattern is a
+//! list of steps, each fading to an intensity and holding it, played a number
+//! of times. When it's done, the light stays at the last step's intensity.
+//!
+//! Intensities run from 0 (off) to 15, and times are in units of the
+//! pattern's `step_duration`.
+
+/// Most steps a pattern can have
+pub const MAX_STEPS: usize = 15;
+
+// Intensity the presets use for "on"
+const FULL: u8 = 0xf;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Step {
+    /// 0-15
+    pub intensity: u8,
+    /// Time taken to fade from the previous step's intensity, 0-15
+    pub fade: u8,
+    /// Time the intensity is held once reached, 0-15
+    pub hold: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    /// Unit of `fade` and `hold`, from 1 for about 8ms to 15 for 175ms
+    pub step_duration: u8,
+    /// Intensity before the first step, 0-15
+    pub start_intensity: u8,
+    /// Times to play the steps, from 1 to 15, or 0 to repeat them forever
+    pub repeat: u8,
+    /// Up to `MAX_STEPS`; any more are ignored
+    pub steps: Vec<Step>,
+}
+
+impl Pattern {
+    pub fn off() -> Pattern {
+        Pattern::on(0)
+    }
+
+    /// Stays at `intensity`, from 0 to 15
+    pub fn on(intensity: u8) -> Pattern {
+        Pattern {
+            step_duration: 1,
+            start_intensity: intensity,
+            repeat: 1,
+            steps: vec![Step {
+                intensity,
+                fade: 0,
+                hold: 0,
+            }],
+        }
+    }
+
+    /// Slowly fades in and out, forever
+    pub fn breathing() -> Pattern {
+        Pattern {
+            step_duration: 8,
+            start_intensity: 0,
+            repeat: 0,
+            steps: vec![
+                Step {
+                    intensity: FULL,
+                    fade: 15,
+                    hold: 2,
+                },
+                Step {
+                    intensity: 0,
+                    fade: 15,
+                    hold: 4,
+                },
+            ],
+        }
+    }
+
+    /// Flashes `times` times, from 1 to 15, or forever if 0, then goes out
+    pub fn blink(times: u8) -> Pattern {
+        Pattern {
+            step_duration: 15,
+            start_intensity: 0,
+            repeat: times.min(15),
+            steps: vec![
+                Step {
+                    intensity: FULL,
+                    fade: 0,
+                    hold: 1,
+                },
+                Step {
+                    intensity: 0,
+                    fade: 0,
+                    hold: 1,
+                },
+            ],
+        }
+    }
+
+    /// The subcommand's data. Every value is a nibble, so larger ones are cut
+    /// down to their low 4 bits.
+    pub fn encode(&self) -> Vec<u8> {
+        let steps = &self.steps[..self.steps.len().min(MAX_STEPS)];
+        let mut buf = vec![
+            (steps.len() as u8) << 4 | (self.step_duration & 0xf),
+            (self.start_intensity & 0xf) << 4 | (self.repeat & 0xf),
+        ];
+        // Steps are packed in pairs, with both intensities in the first byte
+        for pair in steps.chunks(2) {
+            let first = pair[0];
+            let second = pair.get(1).cloned();
+            buf.push(
+                (first.intensity & 0xf) << 4 | second.map_or(0, |second| second.intensity & 0xf),
+            );
+            buf.push(timing(first));
+            if let Some(second) = second {
+                buf.push(timing(second));
+            }
+        }
+        buf
+    }
+}
+
+fn timing(step: Step) -> u8 {
+    (step.fade & 0xf) << 4 | (step.hold & 0xf)
+}
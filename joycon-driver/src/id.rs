@@ -16,6 +16,25 @@ impl Product {
             _ => None,
         }
     }
+
+    /// Whether there's a light around the Home button, for `Driver::set_home_light`
+    pub fn has_home_light(self) -> bool {
+        match self {
+            Product::JoyConR | Product::ProController => true,
+            Product::JoyConL | Product::ChargeGrip => false,
+        }
+    }
+
+    /// Parses the variant name, as written by `{:?}`
+    pub fn from_name(name: &str) -> Option<Product> {
+        match name {
+            "JoyConL" => Some(Product::JoyConL),
+            "JoyConR" => Some(Product::JoyConR),
+            "ProController" => Some(Product::ProController),
+            "ChargeGrip" => Some(Product::ChargeGrip),
+            _ => None,
+        }
+    }
 }
 
 pub enum Vendor {
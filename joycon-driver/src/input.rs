@@ -1,18 +1,19 @@
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
 
+use super::battery::BatteryState;
 use super::frame::{AxisFrame, ButtonFrame, InputFrame, MotionFrame};
 
 use self::InputReport::*;
 
-type BatteryState = u8;
-
 pub enum InputReport<'a> {
     CommandResponse {
+        timer: u8,
         battery: BatteryState,
         frame: InputFrame,
         data: ResponseData<'a>,
     },
     ExtendedInput {
+        timer: u8,
         battery: BatteryState,
         frame: InputFrame,
     },
@@ -24,14 +25,14 @@ impl<'a> From<&'a [u8]> for InputReport<'a> {
     fn from(buf: &[u8]) -> InputReport {
         match buf[0] {
             0x21 => CommandResponse {
-                // Timer byte at buf[1]
-                battery: buf[2] >> 1,
+                timer: buf[1],
+                battery: BatteryState::from(buf[2]),
                 frame: InputFrame::from(&buf[3..12]),
                 data: ResponseData::from(&buf[13..49]),
             },
             0x30 | 0x31 | 0x32 | 0x33 => ExtendedInput {
-                // Timer byte at buf[1]
-                battery: buf[2] >> 1,
+                timer: buf[1],
+                battery: BatteryState::from(buf[2]),
                 frame: InputFrame::from(&buf[3..49]),
             },
             0x3f => SimpleInput(LittleEndian::read_u16(&buf[1..4]), buf[3]),
@@ -49,7 +50,13 @@ pub enum ResponseData<'a> {
     SetInputMode,
     ReadSpi(SpiChunk<'a>),
     SetLeds,
-    GetLeds,
+    /// Player LEDs bitmask, as `Command::SetLeds` takes it
+    GetLeds(u8),
+    SetHomeLight,
+    EnableImu,
+    EnableVibration,
+    /// Raw battery voltage, see `battery::millivolts`
+    GetVoltage(u16),
     Unknown(&'a [u8]),
 }
 
@@ -64,17 +71,21 @@ impl<'a> From<&'a [u8]> for ResponseData<'a> {
             0x03 => ResponseData::SetInputMode,
             0x10 => ResponseData::ReadSpi(SpiChunk::from(&buf[2..])),
             0x30 => ResponseData::SetLeds,
-            0x31 => ResponseData::GetLeds,
+            0x31 => ResponseData::GetLeds(buf[2]),
+            0x38 => ResponseData::SetHomeLight,
+            0x40 => ResponseData::EnableImu,
+            0x48 => ResponseData::EnableVibration,
+            0x50 => ResponseData::GetVoltage(LittleEndian::read_u16(&buf[2..4])),
             _ => ResponseData::Unknown(&buf[..]),
         }
     }
 }
 
-pub struct SpiChunk<'a>(pub u16, pub &'a [u8]);
+pub struct SpiChunk<'a>(pub u32, pub &'a [u8]);
 
 impl<'a> From<&'a [u8]> for SpiChunk<'a> {
     fn from(buf: &'a [u8]) -> SpiChunk {
-        let addr = LittleEndian::read_u16(&buf[..4]);
+        let addr = LittleEndian::read_u32(&buf[..4]);
         let size = buf[4] as usize;
         let buf = &buf[5..5 + size];
         SpiChunk(addr, buf)
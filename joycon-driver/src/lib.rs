@@ -0,0 +1,34 @@
+extern crate arraydeque;
+extern crate byteorder;
+extern crate hidapi;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate termion;
+extern crate toml;
+extern crate uinput;
+
+extern crate common;
+
+pub mod axis;
+pub mod battery;
+pub mod button;
+pub mod calibration;
+pub mod device;
+pub mod driver;
+pub mod evdev;
+pub mod event;
+pub mod focus;
+pub mod frame;
+pub mod gyro;
+pub mod home_light;
+pub mod id;
+pub mod input;
+pub mod mapping;
+pub mod output;
+pub mod pipeline;
+pub mod player_leds;
+pub mod proxy;
+pub mod setup;
+pub mod stats;
+pub mod timer;
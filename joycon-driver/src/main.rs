@@ -1,62 +1,387 @@
-extern crate arraydeque;
-extern crate byteorder;
 extern crate getopts;
 extern crate hidapi;
 extern crate signal_hook;
-extern crate termion;
+extern crate uinput;
 
 extern crate common;
+extern crate joycon_driver;
 
-mod axis;
-mod button;
-mod device;
-mod driver;
-mod frame;
-mod id;
-mod input;
-mod output;
-
-use std::time::Instant;
+use std::env;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
+use getopts::{Matches, Options};
+use hidapi::HidApi;
 use signal_hook::{iterator::Signals, SIGINT, SIGTERM};
+use uinput::event::controller::Mouse;
+use uinput::event::relative::Position;
 
+use common::config;
+use common::ipc::proxy::ProxyCommand;
 use common::log;
 
-use device::InputMode;
-use driver::Driver;
-use id::Product;
+use joycon_driver::device::InputMode;
+use joycon_driver::driver::Driver;
+use joycon_driver::evdev::VirtualDevice;
+use joycon_driver::focus;
+use joycon_driver::gyro::{Acceleration, GyroMouse, GyroSettings};
+use joycon_driver::id::Product;
+use joycon_driver::mapping::{Context, Mapper, Profile};
+use joycon_driver::player_leds::LedState;
+use joycon_driver::proxy::ProxyLink;
+
+// How often the focused application is checked when choosing a mapping profile
+const FOCUS_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often statistics are printed with `--stats`
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help text");
+    opts.optflag(
+        "e",
+        "events",
+        "Print button presses and releases instead of the controller's state",
+    );
+    opts.optflag(
+        "s",
+        "stats",
+        "Print packet loss and latency statistics every second",
+    );
+    opts.optmulti(
+        "p",
+        "profile",
+        "Remap buttons and sticks with a profile, may be given several times",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "gyro-mouse",
+        "Move a virtual mouse by turning the controller",
+    );
+    opts.optopt(
+        "",
+        "gyro-sensitivity",
+        "Mouse counts per degree turned (default 8)",
+        "COUNTS",
+    );
+    opts.optopt(
+        "",
+        "gyro-accel",
+        "Ramp sensitivity up to FAST between SLOW_DPS and FAST_DPS",
+        "FAST,SLOW_DPS,FAST_DPS",
+    );
+    opts.optopt(
+        "",
+        "gyro-smoothing",
+        "Smooth out turns slower than this (default 5)",
+        "DPS",
+    );
+    opts.optopt(
+        "",
+        "proxy",
+        "Send input to the joycon-proxy for ID, and take rumble and LEDs from it",
+        "ID",
+    );
+    opts.optopt(
+        "",
+        "gyro-ratchet",
+        "Pause tracking while this button is held",
+        "BUTTON",
+    );
+    opts.optopt(
+        "",
+        "gyro-space",
+        "Yaw axis to track: local, world or player (default player)",
+        "SPACE",
+    );
+    opts
+}
+
+fn parse_opt<T: FromStr>(matches: &Matches, name: &str) -> Result<Option<T>, String> {
+    match matches.opt_str(name) {
+        Some(value) => match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(format!("Invalid value \"{}\" for --{}", value, name)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn gyro_settings(matches: &Matches) -> Result<GyroSettings, String> {
+    let mut settings = GyroSettings::default();
+
+    match parse_opt(matches, "gyro-sensitivity") {
+        Ok(Some(sensitivity)) => settings.sensitivity = sensitivity,
+        Ok(None) => (),
+        Err(e) => return Err(e),
+    }
+    match parse_opt(matches, "gyro-smoothing") {
+        Ok(Some(threshold)) => settings.smoothing_threshold = threshold,
+        Ok(None) => (),
+        Err(e) => return Err(e),
+    }
+    match parse_opt(matches, "gyro-ratchet") {
+        Ok(ratchet) => settings.ratchet = ratchet,
+        Err(e) => return Err(e),
+    }
+    match parse_opt(matches, "gyro-space") {
+        Ok(Some(space)) => settings.space = space,
+        Ok(None) => (),
+        Err(e) => return Err(e),
+    }
+
+    if let Some(accel) = matches.opt_str("gyro-accel") {
+        let values: Vec<Option<f32>> = accel.split(',').map(|v| v.trim().parse().ok()).collect();
+        settings.acceleration = match values.as_slice() {
+            [Some(fast), Some(slow_dps), Some(fast_dps)] => Some(Acceleration {
+                fast_sensitivity: *fast,
+                slow_threshold: *slow_dps,
+                fast_threshold: *fast_dps,
+            }),
+            _ => return Err(format!("Invalid value \"{}\" for --gyro-accel", accel)),
+        };
+    }
+
+    Ok(settings)
+}
+
+fn create_mouse() -> Result<VirtualDevice, String> {
+    VirtualDevice::new(
+        "Joy-Con Gyro Mouse",
+        &[
+            Position::X.into(),
+            Position::Y.into(),
+            Mouse::Left.into(),
+            Mouse::Right.into(),
+            Mouse::Middle.into(),
+        ],
+    )
+    .map_err(|e| format!("Couldn't create virtual mouse: {:?}", e))
+}
 
-const PENDING_LEDS: u8 = 0b1111_0000;
+fn create_mapping(paths: &[String]) -> Result<(Mapper, VirtualDevice), String> {
+    let profiles = paths
+        .iter()
+        .map(|path| Profile::load(Path::new(path)))
+        .collect::<Result<Vec<Profile>, String>>()?;
+    let mapper = Mapper::new(profiles);
+    VirtualDevice::new("Joy-Con (mapped)", &mapper.events())
+        .map(|device| (mapper, device))
+        .map_err(|e| format!("Couldn't create virtual device: {:?}", e))
+}
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    let opts = options();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(matches) => matches,
+        Err(e) => {
+            log::e(&e.to_string());
+            print!("{}", opts.usage(&format!("Usage: {} [options]", args[0])));
+            return;
+        }
+    };
+    if matches.opt_present("help") {
+        print!("{}", opts.usage(&format!("Usage: {} [options]", args[0])));
+        return;
+    }
+
+    let mut gyro_mouse = if matches.opt_present("gyro-mouse") {
+        match gyro_settings(&matches)
+            .and_then(|settings| create_mouse().map(|mouse| (GyroMouse::new(settings), mouse)))
+        {
+            Ok(gyro_mouse) => Some(gyro_mouse),
+            Err(e) => {
+                log::e(&e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let profiles = matches.opt_strs("profile");
+    let mut mapping = if profiles.is_empty() {
+        None
+    } else {
+        match create_mapping(&profiles) {
+            Ok(mapping) => Some(mapping),
+            Err(e) => {
+                log::e(&e);
+                return;
+            }
+        }
+    };
+    let mut focus_checked: Option<Instant> = None;
+    let mut stats_printed = Instant::now();
+    let mut events_dropped = 0;
+
     let signals = match Signals::new(&[SIGINT, SIGTERM]) {
         Ok(signals) => signals,
         Err(e) => panic!(e),
     };
 
-    let mut driver = match Driver::find(Product::JoyConL)
-        .or_else(|_| Driver::find(Product::JoyConR))
-        .or_else(|_| Driver::find(Product::ProController))
+    let api = match HidApi::new() {
+        Ok(api) => api,
+        Err(e) => {
+            log::wtf("Couldn't initialize HidApi");
+            panic!("{:?}", e);
+        }
+    };
+    let mut driver = match Driver::find(&api, Product::JoyConL)
+        .or_else(|_| Driver::find(&api, Product::JoyConR))
+        .or_else(|_| Driver::find(&api, Product::ProController))
     {
         Ok(driver) => driver,
         Err(_) => panic!("No Joy-Con or Switch Pro Controller devices found"),
     };
 
+    if let Err(e) = driver.load_calibration() {
+        log::e(&format!("Couldn't load calibration: {:?}", e));
+    }
     if let Err(e) = driver
         .set_input_mode(InputMode::Full)
-        .and_then(|_| driver.set_leds(PENDING_LEDS))
+        .and_then(|_| driver.set_leds(LedState::Searching))
+        .and_then(|_| driver.enable_imu(gyro_mouse.is_some()))
     {
         log::e(&format!("{:?}", e));
     }
 
     println!("Connected to {}", driver);
 
+    let mut proxy = match matches.opt_str("proxy") {
+        Some(id) => {
+            let linked = config::proxy_dir().and_then(|dir| {
+                ProxyLink::connect(&dir, &id, driver.serial_number()).map_err(|e| e.to_string())
+            });
+            match linked {
+                Ok(proxy) => Some(proxy),
+                Err(e) => {
+                    log::e(&format!("Couldn't connect to proxy {}: {}", id, e));
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+    let mut rumble_until: Option<Instant> = None;
+
     'main: loop {
-        if let Err(e) = driver.flush() {
+        if let Err(e) = driver
+            .flush()
+            .and_then(|_| driver.poll_voltage())
+            .and_then(|_| driver.update_leds().map(|_| ()))
+        {
             log::e(&format!("{:?}", e));
         }
 
-        println!("{}", driver);
+        if let Some((ref mut mapper, ref mut device)) = mapping {
+            if focus_checked.is_none_or(|checked| checked.elapsed() >= FOCUS_INTERVAL) {
+                focus_checked = Some(Instant::now());
+                let serials = [driver.serial_number().to_string()];
+                let application = focus::active_application();
+                let context = Context {
+                    serials: &serials,
+                    application: application.as_deref(),
+                };
+                let previous = mapper.active_profile().map(|p| p.name.clone());
+                if let Err(e) = mapper.select(&context, device) {
+                    log::e(&format!("{:?}", e));
+                }
+                match mapper.active_profile() {
+                    Some(profile) if Some(&profile.name) != previous.as_ref() => {
+                        log::i(&format!("Using profile \"{}\"", profile.name))
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        if let Some(ref link) = proxy {
+            let mut commands = Vec::new();
+            loop {
+                match link.next_command() {
+                    Ok(Some(command)) => commands.push(command),
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::e(&format!("{}", e));
+                        break 'main;
+                    }
+                }
+            }
+            for command in commands {
+                let result = match command {
+                    ProxyCommand::SetLeds { leds } => driver.set_leds(LedState::Raw(leds)),
+                    ProxyCommand::Rumble { rumble } => {
+                        rumble_until = Some(
+                            Instant::now() + Duration::from_millis(u64::from(rumble.duration_ms)),
+                        );
+                        driver.rumble(rumble.frequency, rumble.amplitude)
+                    }
+                };
+                if let Err(e) = result {
+                    log::e(&format!("{:?}", e));
+                }
+            }
+        }
+        if rumble_until.is_some_and(|until| Instant::now() >= until) {
+            rumble_until = None;
+            if let Err(e) = driver.rumble(0.0, 0.0) {
+                log::e(&format!("{:?}", e));
+            }
+        }
+
+        while let Some(frame) = driver.next_frame() {
+            if let Some(ref mut link) = proxy {
+                if let Err(e) = link.send(&frame, driver.product()) {
+                    log::e(&format!("Couldn't reach the proxy: {}", e));
+                    break 'main;
+                }
+            }
+            if let Some((ref mut mapper, ref mut device)) = mapping {
+                if let Err(e) = mapper.update(&frame, driver.product(), frame.time, device) {
+                    log::e(&format!("{:?}", e));
+                }
+            }
+            if let Some((ref mut gyro, ref mut mouse)) = gyro_mouse {
+                let (dx, dy) = gyro.update(&frame, driver.product());
+                if dx == 0 && dy == 0 {
+                    continue;
+                }
+                if let Err(e) = mouse
+                    .send(Position::X.into(), dx)
+                    .and_then(|_| mouse.send(Position::Y.into(), dy))
+                    .and_then(|_| mouse.sync())
+                {
+                    log::e(&format!("{:?}", e));
+                }
+            }
+        }
+
+        if matches.opt_present("stats") {
+            if stats_printed.elapsed() >= STATS_INTERVAL {
+                stats_printed = Instant::now();
+                println!("{}", driver.stats());
+            }
+        } else if matches.opt_present("events") {
+            while let Some((time, event)) = driver.next_event() {
+                println!(
+                    "[{:6}.{:03}] {:?}",
+                    time.as_secs(),
+                    time.subsec_millis(),
+                    event
+                );
+            }
+            if driver.dropped_events() > events_dropped {
+                events_dropped = driver.dropped_events();
+                log::e(&format!("{} events dropped so far", events_dropped));
+            }
+        } else {
+            println!("{}", driver);
+        }
 
         for signal in signals.pending() {
             match signal {
@@ -0,0 +1,612 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use toml;
+use uinput;
+use uinput::event::Event;
+
+use super::axis::Axis;
+use super::button::Button;
+use super::evdev::{self, VirtualDevice, AXIS_MAX};
+use super::frame::{AxisFrame, InputFrame};
+use super::id::Product;
+use super::pipeline::{Binding, Chord, Layer, Pipeline, Timings};
+
+// Raw stick values are 12 bits wide, centered on this value
+const STICK_CENTER: i32 = 0x800;
+
+/// A standard gamepad following the controller's own layout, for controllers
+/// without a profile of their own
+pub const DEFAULT_PROFILE: &str = include_str!("../profiles/gamepad.toml");
+
+/// What a controller button does on the virtual device
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Holds a key or button for as long as the controller button is held
+    Hold(Event),
+    /// Moves an absolute axis to a fixed value while held, e.g. a hat switch
+    Axis(Event, i32),
+    /// Taps a sequence of key combinations on press, one after another
+    Macro(Vec<Vec<Event>>),
+}
+
+/// Controllers and applications a profile applies to. Empty lists match anything.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Selector {
+    /// Serial numbers of individual controllers
+    #[serde(default)]
+    pub serials: Vec<String>,
+    /// Serial numbers of both halves of a pair, which only match while combined
+    #[serde(default)]
+    pub pair: Vec<String>,
+    /// Names of focused applications, as reported by their window class
+    #[serde(default)]
+    pub applications: Vec<String>,
+}
+
+impl Selector {
+    /// How specific a match `context` is for this selector, or `None` if it doesn't match
+    fn score(&self, context: &Context) -> Option<usize> {
+        let mut score = 0;
+        if !self.serials.is_empty() {
+            if !context.serials.iter().any(|s| self.serials.contains(s)) {
+                return None;
+            }
+            score += 1;
+        }
+        if !self.pair.is_empty() {
+            let mut pair = self.pair.clone();
+            let mut serials = context.serials.to_vec();
+            pair.sort();
+            serials.sort();
+            if pair != serials {
+                return None;
+            }
+            score += 2;
+        }
+        if !self.applications.is_empty() {
+            match context.application {
+                Some(app)
+                    if self
+                        .applications
+                        .iter()
+                        .any(|a| a.eq_ignore_ascii_case(app)) =>
+                {
+                    score += 4
+                }
+                _ => return None,
+            }
+        }
+        Some(score)
+    }
+}
+
+/// What's known about the controllers and desktop when choosing a profile
+pub struct Context<'a> {
+    pub serials: &'a [String],
+    pub application: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    name: String,
+    #[serde(default)]
+    select: Selector,
+    #[serde(default)]
+    timing: TimingSpec,
+    #[serde(default)]
+    buttons: BTreeMap<String, BindingSpec>,
+    #[serde(default)]
+    layers: BTreeMap<String, BTreeMap<String, BindingSpec>>,
+    #[serde(default)]
+    chords: Vec<ChordSpec>,
+    #[serde(default)]
+    axes: BTreeMap<String, String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimingSpec {
+    hold_ms: Option<u64>,
+    chord_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChordSpec {
+    buttons: Vec<String>,
+    press: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BindingSpec {
+    Action(ActionSpec),
+    Extended(ExtendedSpec),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExtendedSpec {
+    action: Option<ActionSpec>,
+    tap: Option<ActionSpec>,
+    hold: Option<ActionSpec>,
+    turbo: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ActionSpec {
+    Hold(String),
+    Axis {
+        axis: String,
+        value: i32,
+    },
+    Macro {
+        #[serde(rename = "macro")]
+        steps: Vec<Vec<String>>,
+    },
+}
+
+/// A named set of button and stick bindings, loaded from a TOML file:
+///
+/// ```toml
+/// name = "slides"
+///
+/// [select]
+/// applications = ["libreoffice"]
+///
+/// [buttons]
+/// East = "key:Right"
+/// West = "key:Left"
+/// Home = { axis = "hat:X0", value = 1 }
+/// Plus = { macro = [["key:LeftControl", "key:S"]] }
+/// South = { tap = "key:Esc", hold = "key:LeftShift" }
+/// North = { action = "key:Space", turbo = 10 }
+///
+/// # Holding Capture switches to this layer
+/// [layers.Capture]
+/// East = "key:PageDown"
+///
+/// [[chords]]
+/// buttons = ["Minus", "Plus"]
+/// press = "Home"
+///
+/// [timing]
+/// hold_ms = 200
+/// chord_ms = 50
+///
+/// [axes]
+/// Lx = "abs:X"
+/// ```
+pub struct Profile {
+    pub name: String,
+    pub selector: Selector,
+    actions: Vec<Action>,
+    pipeline: Pipeline,
+    axes: Vec<(Axis, Event)>,
+}
+
+impl Profile {
+    pub fn load(path: &Path) -> Result<Profile, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Profile::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Profile, String> {
+        let file: ProfileFile = toml::from_str(text).map_err(|e| e.to_string())?;
+
+        let mut actions = Vec::new();
+        let buttons = parse_bindings(file.buttons, &mut actions, "buttons")?;
+
+        let mut layers = Vec::with_capacity(file.layers.len());
+        for (name, bindings) in file.layers {
+            let button = name
+                .parse()
+                .map_err(|e| format!("layers.{}: {}", name, e))?;
+            let bindings = parse_bindings(bindings, &mut actions, &format!("layers.{}", name))?;
+            layers.push(Layer { button, bindings });
+        }
+
+        let mut chords = Vec::with_capacity(file.chords.len());
+        for (i, chord) in file.chords.into_iter().enumerate() {
+            let buttons = chord
+                .buttons
+                .iter()
+                .map(|name| name.parse())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("chords[{}].buttons: {}", i, e))?;
+            if buttons.len() < 2 {
+                return Err(format!(
+                    "chords[{}].buttons: a chord needs at least two buttons",
+                    i
+                ));
+            }
+            let output = chord
+                .press
+                .parse()
+                .map_err(|e| format!("chords[{}].press: {}", i, e))?;
+            chords.push(Chord { buttons, output });
+        }
+
+        let defaults = Timings::default();
+        let timings = Timings {
+            hold: file
+                .timing
+                .hold_ms
+                .map_or(defaults.hold, Duration::from_millis),
+            chord: file
+                .timing
+                .chord_ms
+                .map_or(defaults.chord, Duration::from_millis),
+        };
+
+        let mut axes = Vec::with_capacity(file.axes.len());
+        for (name, target) in file.axes {
+            let axis = name.parse().map_err(|e| format!("axes.{}: {}", name, e))?;
+            let event = match evdev::parse_event(&target) {
+                Ok(event @ Event::Absolute(_)) => event,
+                Ok(_) => return Err(format!("axes.{}: \"{}\" isn't an axis", name, target)),
+                Err(e) => return Err(format!("axes.{}: {}", name, e)),
+            };
+            axes.push((axis, event));
+        }
+
+        Ok(Profile {
+            name: file.name,
+            selector: file.select,
+            actions,
+            pipeline: Pipeline::new(timings, chords, layers, buttons),
+            axes,
+        })
+    }
+
+    fn events(&self) -> Vec<Event> {
+        let mut events = Vec::new();
+        for action in &self.actions {
+            match action {
+                Action::Hold(event) | Action::Axis(event, _) => events.push(*event),
+                Action::Macro(steps) => events.extend(steps.iter().flatten()),
+            }
+        }
+        events.extend(self.axes.iter().map(|&(_, event)| event));
+        events
+    }
+}
+
+fn parse_bindings(
+    specs: BTreeMap<String, BindingSpec>,
+    actions: &mut Vec<Action>,
+    table: &str,
+) -> Result<Vec<(Button, Binding)>, String> {
+    let mut bindings = Vec::with_capacity(specs.len());
+    for (name, spec) in specs {
+        let button = name
+            .parse()
+            .map_err(|e| format!("{}.{}: {}", table, name, e))?;
+        let binding =
+            parse_binding(spec, actions).map_err(|e| format!("{}.{}: {}", table, name, e))?;
+        bindings.push((button, binding));
+    }
+    Ok(bindings)
+}
+
+fn parse_binding(spec: BindingSpec, actions: &mut Vec<Action>) -> Result<Binding, String> {
+    let spec = match spec {
+        BindingSpec::Action(action) => {
+            return Ok(Binding {
+                hold: Some(intern(actions, parse_action(action)?)),
+                ..Binding::default()
+            })
+        }
+        BindingSpec::Extended(spec) => spec,
+    };
+
+    let hold = match (spec.action, spec.hold) {
+        (Some(_), Some(_)) => return Err("use either \"action\" or \"hold\", not both".to_string()),
+        (Some(action), None) | (None, Some(action)) => Some(intern(actions, parse_action(action)?)),
+        (None, None) => None,
+    };
+    let tap = match spec.tap {
+        Some(action) => Some(intern(actions, parse_action(action)?)),
+        None => None,
+    };
+    if hold.is_none() && tap.is_none() {
+        return Err("a binding needs an \"action\", \"tap\" or \"hold\"".to_string());
+    }
+    let turbo = match spec.turbo {
+        Some(hz) if hz > 0.0 => Some(Duration::from_secs_f32(1.0 / hz)),
+        Some(_) => return Err("\"turbo\" should be a rate above 0Hz".to_string()),
+        None => None,
+    };
+
+    Ok(Binding { hold, tap, turbo })
+}
+
+/// Index of `action` in `actions`, adding it if it's new. Sharing indices lets
+/// two buttons bound to the same key hold it down together.
+fn intern(actions: &mut Vec<Action>, action: Action) -> usize {
+    match actions.iter().position(|a| *a == action) {
+        Some(i) => i,
+        None => {
+            actions.push(action);
+            actions.len() - 1
+        }
+    }
+}
+
+fn parse_action(spec: ActionSpec) -> Result<Action, String> {
+    match spec {
+        ActionSpec::Hold(name) => evdev::parse_event(&name).map(Action::Hold),
+        ActionSpec::Axis { axis, value } => match evdev::parse_event(&axis) {
+            Ok(event @ Event::Absolute(_)) => Ok(Action::Axis(event, value)),
+            Ok(_) => Err(format!("\"{}\" isn't an axis", axis)),
+            Err(e) => Err(e),
+        },
+        ActionSpec::Macro { steps } => steps
+            .iter()
+            .map(|step| step.iter().map(|name| evdev::parse_event(name)).collect())
+            .collect::<Result<_, _>>()
+            .map(Action::Macro),
+    }
+}
+
+/// Translates controller input into virtual device events through the best
+/// matching profile
+pub struct Mapper {
+    profiles: Vec<Profile>,
+    active: Option<usize>,
+    axes: AxisFrame,
+}
+
+impl Mapper {
+    pub fn new(profiles: Vec<Profile>) -> Mapper {
+        Mapper {
+            profiles,
+            active: None,
+            axes: AxisFrame::new(),
+        }
+    }
+
+    /// Every event any loaded profile can emit, which the virtual device must support
+    pub fn events(&self) -> Vec<Event> {
+        let mut events: Vec<Event> = Vec::new();
+        for event in self.profiles.iter().flat_map(|p| p.events()) {
+            if !events.contains(&event) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    pub fn active_profile(&self) -> Option<&Profile> {
+        self.active.map(|i| &self.profiles[i])
+    }
+
+    /// Switch to the most specific profile matching `context`. Outputs held by
+    /// the previous profile are released first, so nothing gets stuck down.
+    pub fn select(
+        &mut self,
+        context: &Context,
+        device: &mut VirtualDevice,
+    ) -> Result<(), uinput::Error> {
+        let next = self.best_profile(context);
+        if next == self.active {
+            return Ok(());
+        }
+        self.release(device)?;
+        self.active = next;
+        Ok(())
+    }
+
+    // The most specific profile matching `context`, the first one listed on a tie
+    fn best_profile(&self, context: &Context) -> Option<usize> {
+        let mut best: Option<(usize, usize)> = None;
+        for (i, profile) in self.profiles.iter().enumerate() {
+            match (profile.selector.score(context), best) {
+                (Some(score), Some((_, best_score))) if score <= best_score => (),
+                (Some(score), _) => best = Some((i, score)),
+                (None, _) => (),
+            }
+        }
+        best.map(|(i, _)| i)
+    }
+
+    /// Release every output and center every axis, e.g. when the controller
+    /// goes away, so nothing gets stuck down
+    pub fn release(&mut self, device: &mut VirtualDevice) -> Result<(), uinput::Error> {
+        self.axes = AxisFrame::new();
+        let profile = match self.active {
+            Some(i) => &mut self.profiles[i],
+            None => return Ok(()),
+        };
+        for action in profile.pipeline.release_all() {
+            apply(device, &profile.actions[action], false)?;
+        }
+        for &(_, event) in &profile.axes {
+            device.send(event, 0)?;
+        }
+        device.sync()
+    }
+
+    /// Emit events for whatever changed between the previous frame and this
+    /// one. `time` is when the frame was read, on any monotonic clock.
+    pub fn update(
+        &mut self,
+        frame: &InputFrame,
+        product: Option<Product>,
+        time: Duration,
+        device: &mut VirtualDevice,
+    ) -> Result<(), uinput::Error> {
+        let axes = self.axes;
+        self.axes = frame.axes;
+        let (profile, product) = match (self.active, product) {
+            (Some(i), Some(product)) => (&mut self.profiles[i], product),
+            _ => return Ok(()),
+        };
+
+        let transitions = profile.pipeline.update(frame.buttons, product, time);
+        let mut changed = false;
+        for &action in &transitions.released {
+            apply(device, &profile.actions[action], false)?;
+            changed = true;
+        }
+        for &action in &transitions.pressed {
+            apply(device, &profile.actions[action], true)?;
+            changed = true;
+        }
+        for &action in &transitions.tapped {
+            apply(device, &profile.actions[action], true)?;
+            device.sync()?;
+            apply(device, &profile.actions[action], false)?;
+            changed = true;
+        }
+        for &(axis, event) in &profile.axes {
+            let value = frame.axes.get(axis);
+            if value != axes.get(axis) {
+                device.send(event, scale_stick(axis, value))?;
+                changed = true;
+            }
+        }
+
+        if changed {
+            device.sync()?;
+        }
+        Ok(())
+    }
+}
+
+fn apply(device: &mut VirtualDevice, action: &Action, pressed: bool) -> Result<(), uinput::Error> {
+    match action {
+        Action::Hold(event) => device.send(*event, pressed as i32),
+        Action::Axis(event, value) => device.send(*event, if pressed { *value } else { 0 }),
+        Action::Macro(steps) if pressed => {
+            for step in steps {
+                for &event in step {
+                    device.send(event, 1)?;
+                }
+                device.sync()?;
+                for &event in step.iter().rev() {
+                    device.send(event, 0)?;
+                }
+                device.sync()?;
+            }
+            Ok(())
+        }
+        Action::Macro(_) => Ok(()),
+    }
+}
+
+/// Convert a raw 12-bit stick value to the virtual device's signed axis range
+fn scale_stick(axis: Axis, value: u16) -> i32 {
+    let centered = i32::from(value) - STICK_CENTER;
+    let scaled = (centered * AXIS_MAX / STICK_CENTER).clamp(-AXIS_MAX, AXIS_MAX);
+    // evdev treats up as negative
+    if axis.is_vertical() {
+        -scaled
+    } else {
+        scaled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector(serials: &[&str], pair: &[&str], applications: &[&str]) -> Selector {
+        let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+        Selector {
+            serials: strings(serials),
+            pair: strings(pair),
+            applications: strings(applications),
+        }
+    }
+
+    fn score(selector: &Selector, serials: &[&str], application: Option<&str>) -> Option<usize> {
+        let serials: Vec<String> = serials.iter().map(|s| s.to_string()).collect();
+        selector.score(&Context {
+            serials: &serials,
+            application,
+        })
+    }
+
+    #[test]
+    fn empty_selector_matches_anything() {
+        let any = Selector::default();
+        assert_eq!(score(&any, &["L1"], None), Some(0));
+        assert_eq!(score(&any, &["L1", "R1"], Some("game")), Some(0));
+    }
+
+    #[test]
+    fn serials_match_either_half() {
+        let serials = selector(&["R1"], &[], &[]);
+        assert_eq!(score(&serials, &["R1"], None), Some(1));
+        assert_eq!(score(&serials, &["L1", "R1"], None), Some(1));
+        assert_eq!(score(&serials, &["L1"], None), None);
+    }
+
+    #[test]
+    fn pair_needs_both_halves_in_any_order() {
+        let pair = selector(&[], &["L1", "R1"], &[]);
+        assert_eq!(score(&pair, &["R1", "L1"], None), Some(2));
+        assert_eq!(score(&pair, &["L1"], None), None);
+        assert_eq!(score(&pair, &["L1", "R2"], None), None);
+    }
+
+    #[test]
+    fn applications_ignore_case_and_need_one_focused() {
+        let game = selector(&[], &[], &["Game"]);
+        assert_eq!(score(&game, &["L1"], Some("game")), Some(4));
+        assert_eq!(score(&game, &["L1"], Some("editor")), None);
+        assert_eq!(score(&game, &["L1"], None), None);
+    }
+
+    #[test]
+    fn scores_add_up() {
+        let all = selector(&["L1"], &["L1", "R1"], &["game"]);
+        assert_eq!(score(&all, &["L1", "R1"], Some("game")), Some(7));
+        assert_eq!(score(&all, &["L1"], Some("game")), None);
+    }
+
+    #[test]
+    fn misspelled_sections_are_errors() {
+        assert!(Profile::parse("name = \"typo\"\n[button]\nA = \"BTN_SOUTH\"\n").is_err());
+        assert!(Profile::parse("name = \"typo\"\n[select]\nserial = [\"L1\"]\n").is_err());
+    }
+
+    #[test]
+    fn most_specific_profile_wins() {
+        let profile = |name: &str, select: &str| {
+            Profile::parse(&format!("name = \"{}\"\n[select]\n{}", name, select)).unwrap()
+        };
+        let mapper = Mapper::new(vec![
+            profile("default", ""),
+            profile("mine", "serials = [\"L1\"]"),
+            profile("pair", "pair = [\"L1\", \"R1\"]"),
+            profile("game", "applications = [\"game\"]"),
+            profile("also default", ""),
+        ]);
+        let best = |serials: &[&str], application: Option<&str>| {
+            let serials: Vec<String> = serials.iter().map(|s| s.to_string()).collect();
+            let context = Context {
+                serials: &serials,
+                application,
+            };
+            mapper
+                .best_profile(&context)
+                .map(|i| mapper.profiles[i].name.as_str())
+        };
+        assert_eq!(best(&["L2"], None), Some("default"));
+        assert_eq!(best(&["L1"], None), Some("mine"));
+        assert_eq!(best(&["L1", "R1"], None), Some("pair"));
+        assert_eq!(best(&["L1", "R1"], Some("game")), Some("game"));
+    }
+}
@@ -11,6 +11,43 @@ pub const NEUTRAL_RUMBLE: [u8; 8] = [
     0x01, 0x40, 0x40,
 ];
 
+/// Encodes one side's HD rumble data for a tone of `frequency` Hz, from
+/// roughly 40 to 1250, at an `amplitude` from 0.0 to 1.0. The tone is played
+/// on both the high and low frequency bands, as far as their ranges allow.
+pub fn encode_rumble(frequency: f32, amplitude: f32) -> [u8; 4] {
+    if amplitude <= 0.0 {
+        return [
+            NEUTRAL_RUMBLE[4],
+            NEUTRAL_RUMBLE[5],
+            NEUTRAL_RUMBLE[6],
+            NEUTRAL_RUMBLE[7],
+        ];
+    }
+
+    let encoded_freq = ((frequency / 10.0).log2() * 32.0).round() as i32;
+    let hf = ((encoded_freq - 0x60).clamp(0x01, 0x7f) * 4) as u16;
+    let lf = (encoded_freq - 0x40).clamp(0x01, 0x7f) as u8;
+
+    let amplitude = amplitude.min(1.0);
+    let encoded_amp = if amplitude > 0.23 {
+        (amplitude * 8.7).log2() * 32.0
+    } else if amplitude > 0.12 {
+        (amplitude * 17.0).log2() * 16.0
+    } else {
+        (amplitude.log2() * 32.0 - 96.0) / (4.0 - 2.0 * amplitude)
+    };
+    let encoded_amp = encoded_amp.round().clamp(0.0, 100.0) as u16;
+    let hf_amp = (encoded_amp * 2) as u8;
+    let lf_amp = encoded_amp / 2 + 0x40;
+
+    [
+        (hf & 0xff) as u8,
+        hf_amp + (hf >> 8) as u8,
+        lf + (lf_amp >> 8) as u8,
+        (lf_amp & 0xff) as u8,
+    ]
+}
+
 pub enum OutputReport<'a> {
     DoCommand(u8, &'a [u8], Command),
     Rumble(u8, &'a [u8]),
@@ -52,6 +89,12 @@ pub enum Command {
     SetHciState(HciState),
     ReadSpi(u32, usize),
     SetLeds(u8),
+    GetLeds,
+    /// An encoded `home_light::Pattern`
+    SetHomeLight(Vec<u8>),
+    EnableImu(bool),
+    EnableVibration(bool),
+    GetVoltage,
     Unknown,
 }
 
@@ -63,6 +106,11 @@ impl<'a> From<&'a [u8]> for Command {
             0x06 => SetHciState(HciState::from(&buf[1])),
             0x10 => ReadSpi(LittleEndian::read_u32(&buf[1..5]), buf[5] as usize),
             0x30 => SetLeds(buf[1]),
+            0x31 => GetLeds,
+            0x38 => SetHomeLight(buf[1..].to_vec()),
+            0x40 => EnableImu(buf[1] != 0),
+            0x48 => EnableVibration(buf[1] != 0),
+            0x50 => GetVoltage,
             _ => Unknown,
         }
     }
@@ -76,6 +124,11 @@ impl<'a> From<&'a Command> for u8 {
             SetHciState(_) => 0x06,
             ReadSpi(_, _) => 0x10,
             SetLeds(_) => 0x30,
+            GetLeds => 0x31,
+            SetHomeLight(_) => 0x38,
+            EnableImu(_) => 0x40,
+            EnableVibration(_) => 0x48,
+            GetVoltage => 0x50,
             Unknown => 0x00,
         }
     }
@@ -90,6 +143,9 @@ impl<'a> From<Command> for Vec<u8> {
             SetInputMode(mode) => {
                 buf.push(u8::from(&mode));
             }
+            SetHciState(state) => {
+                buf.push(u8::from(&state));
+            }
             ReadSpi(addr, len) => {
                 buf.resize(5, 0);
                 LittleEndian::write_u32(&mut buf[1..5], addr);
@@ -98,6 +154,12 @@ impl<'a> From<Command> for Vec<u8> {
             SetLeds(bitmask) => {
                 buf.push(bitmask);
             }
+            SetHomeLight(pattern) => {
+                buf.extend_from_slice(&pattern);
+            }
+            EnableImu(enabled) | EnableVibration(enabled) => {
+                buf.push(enabled as u8);
+            }
             _ => {}
         }
         buf
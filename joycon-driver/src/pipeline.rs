@@ -0,0 +1,468 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use super::button::Button;
+use super::frame::ButtonFrame;
+use super::id::Product;
+
+// Real buttons occupy the low 24 bits of a `ButtonFrame`
+const BUTTON_BITS: usize = 24;
+
+/// How long a button must be held before it stops counting as a tap, and how
+/// close together a chord's buttons must be pressed
+#[derive(Copy, Clone, Debug)]
+pub struct Timings {
+    pub hold: Duration,
+    pub chord: Duration,
+}
+
+impl Default for Timings {
+    fn default() -> Timings {
+        Timings {
+            hold: Duration::from_millis(200),
+            chord: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Buttons that act as a different button when pressed together, e.g. Minus+Plus as Home
+#[derive(Clone, Debug)]
+pub struct Chord {
+    pub buttons: Vec<Button>,
+    pub output: Button,
+}
+
+/// What a button activates. Actions are identified by their index in the profile.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Binding {
+    /// Active while the button is held, or once it's been held past the hold
+    /// time if `tap` is also set
+    pub hold: Option<usize>,
+    /// Pressed and released at once when the button is let go before the hold time
+    pub tap: Option<usize>,
+    /// Turns `hold` on and off once per period while held
+    pub turbo: Option<Duration>,
+}
+
+/// Bindings that take over from the base layer while `button` is held
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub button: Button,
+    pub bindings: Vec<(Button, Binding)>,
+}
+
+/// How the set of active actions changed after a frame
+#[derive(Default)]
+pub struct Transitions {
+    pub pressed: Vec<usize>,
+    pub released: Vec<usize>,
+    pub tapped: Vec<usize>,
+}
+
+#[derive(Copy, Clone)]
+struct Press {
+    since: Duration,
+    binding: Binding,
+}
+
+/// Turns raw button frames into actions, in stages: chords first, then
+/// layers, then tap/hold and turbo for each remaining button
+pub struct Pipeline {
+    timings: Timings,
+    chords: Vec<Chord>,
+    layers: Vec<Layer>,
+    bindings: Vec<(Button, Binding)>,
+
+    raw: u32,
+    pressed_at: [Duration; BUTTON_BITS],
+    engaged: Vec<bool>,
+    // Members of a broken chord, ignored until they're released
+    suppressed: u32,
+    // Output of the chord stage for the previous frame
+    chorded: u32,
+    // Output of the layer stage for the previous frame
+    logical: u32,
+    presses: [Option<Press>; BUTTON_BITS],
+    active: BTreeSet<usize>,
+}
+
+impl Pipeline {
+    pub fn new(
+        timings: Timings,
+        chords: Vec<Chord>,
+        layers: Vec<Layer>,
+        bindings: Vec<(Button, Binding)>,
+    ) -> Pipeline {
+        let engaged = vec![false; chords.len()];
+        Pipeline {
+            timings,
+            chords,
+            layers,
+            bindings,
+
+            raw: 0,
+            pressed_at: [Duration::from_secs(0); BUTTON_BITS],
+            engaged,
+            suppressed: 0,
+            chorded: 0,
+            logical: 0,
+            presses: [None; BUTTON_BITS],
+            active: BTreeSet::new(),
+        }
+    }
+
+    /// Feed the next frame through the pipeline. `time` only needs to be
+    /// monotonic, e.g. time since the controller connected.
+    pub fn update(
+        &mut self,
+        buttons: ButtonFrame,
+        product: Product,
+        time: Duration,
+    ) -> Transitions {
+        let raw = buttons.0;
+        for bit in 0..BUTTON_BITS {
+            if raw & !self.raw & (1 << bit) != 0 {
+                self.pressed_at[bit] = time;
+            }
+        }
+
+        let chorded = self.chord(raw, product, time);
+        let (layer, logical) = self.layer(chorded, product);
+        self.raw = raw;
+        self.chorded = chorded;
+        self.bind(logical, layer, product, time)
+    }
+
+    /// Release every action, e.g. before switching profiles
+    pub fn release_all(&mut self) -> Vec<usize> {
+        let released = self.active.iter().cloned().collect();
+        *self = Pipeline::new(
+            self.timings,
+            self.chords.clone(),
+            self.layers.clone(),
+            self.bindings.clone(),
+        );
+        released
+    }
+
+    fn chord(&mut self, raw: u32, product: Product, time: Duration) -> u32 {
+        self.suppressed &= raw;
+        let mut output = raw & !self.suppressed;
+
+        for (i, chord) in self.chords.iter().enumerate() {
+            let members = mask(&chord.buttons, product);
+            if members == 0 {
+                continue;
+            }
+            let held = raw & members;
+
+            if self.engaged[i] {
+                if held == members {
+                    output = (output & !members) | mask(&[chord.output], product);
+                } else {
+                    // Don't let the remaining buttons through when a chord is broken
+                    self.engaged[i] = false;
+                    self.suppressed |= held;
+                    output &= !members;
+                }
+                continue;
+            }
+
+            let times = bits(members).map(|bit| self.pressed_at[bit]);
+            let spread = times.clone().max().unwrap_or_default() - times.min().unwrap_or_default();
+            if held == members && spread <= self.timings.chord {
+                self.engaged[i] = true;
+                output = (output & !members) | mask(&[chord.output], product);
+                continue;
+            }
+
+            for bit in bits(members) {
+                let pending = time.saturating_sub(self.pressed_at[bit]) < self.timings.chord
+                    && self.chorded & (1 << bit) == 0;
+                if pending && held & (1 << bit) != 0 {
+                    // Hold this button back in case the rest of the chord follows
+                    output &= !(1 << bit);
+                } else if pending && self.raw & (1 << bit) != 0 {
+                    // Released while held back, so let it through for one frame
+                    output |= 1 << bit;
+                }
+            }
+        }
+        output
+    }
+
+    fn layer(&self, mut buttons: u32, product: Product) -> (Option<usize>, u32) {
+        let mut active = None;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let shift = mask(&[layer.button], product);
+            if shift != 0 && buttons & shift == shift {
+                active = active.or(Some(i));
+                buttons &= !shift;
+            }
+        }
+        (active, buttons)
+    }
+
+    fn bind(
+        &mut self,
+        logical: u32,
+        layer: Option<usize>,
+        product: Product,
+        time: Duration,
+    ) -> Transitions {
+        let mut transitions = Transitions::default();
+        let mut active = BTreeSet::new();
+
+        for bit in 0..BUTTON_BITS {
+            let (was, is) = (self.logical & (1 << bit) != 0, logical & (1 << bit) != 0);
+            if is && !was {
+                // A button keeps the binding it was pressed with, even if the layer changes
+                self.presses[bit] = self.lookup(bit, layer, product).map(|binding| Press {
+                    since: time,
+                    binding,
+                });
+            } else if was && !is {
+                if let Some(press) = self.presses[bit].take() {
+                    match press.binding.tap {
+                        Some(tap) if time.saturating_sub(press.since) < self.timings.hold => {
+                            transitions.tapped.push(tap)
+                        }
+                        _ => (),
+                    }
+                }
+            }
+
+            let press = match self.presses[bit] {
+                Some(press) if is => press,
+                _ => continue,
+            };
+            let held_for = time.saturating_sub(press.since);
+            if press.binding.tap.is_some() && held_for < self.timings.hold {
+                continue;
+            }
+            let on = match press.binding.turbo {
+                Some(period) => {
+                    held_for.as_nanos() % period.as_nanos().max(1) < period.as_nanos() / 2
+                }
+                None => true,
+            };
+            match press.binding.hold {
+                Some(action) if on => {
+                    active.insert(action);
+                }
+                _ => (),
+            }
+        }
+
+        self.logical = logical;
+        transitions.pressed = active.difference(&self.active).cloned().collect();
+        transitions.released = self.active.difference(&active).cloned().collect();
+        self.active = active;
+        transitions
+    }
+
+    fn lookup(&self, bit: usize, layer: Option<usize>, product: Product) -> Option<Binding> {
+        let find = |bindings: &[(Button, Binding)]| {
+            bindings
+                .iter()
+                .find(|(button, _)| mask(&[*button], product) == 1 << bit)
+                .map(|&(_, binding)| binding)
+        };
+        layer
+            .and_then(|i| find(&self.layers[i].bindings))
+            .or_else(|| find(&self.bindings))
+    }
+}
+
+fn mask(buttons: &[Button], product: Product) -> u32 {
+    buttons
+        .iter()
+        .filter_map(|button| button.to_real(product))
+        .fold(0, |mask, button| mask | u32::from(button))
+}
+
+fn bits(mask: u32) -> impl Iterator<Item = usize> + Clone {
+    (0..BUTTON_BITS).filter(move |bit| mask & (1 << bit) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Button::*;
+    use super::*;
+
+    const PRO: Product = Product::ProController;
+
+    fn hold(action: usize) -> Binding {
+        Binding {
+            hold: Some(action),
+            ..Binding::default()
+        }
+    }
+
+    fn feed(pipeline: &mut Pipeline, buttons: &[Button], ms: u64) -> Transitions {
+        pipeline.update(
+            ButtonFrame(mask(buttons, PRO)),
+            PRO,
+            Duration::from_millis(ms),
+        )
+    }
+
+    // Minus+Plus as Home, with each of them bound on its own too
+    fn chorded() -> Pipeline {
+        Pipeline::new(
+            Timings::default(),
+            vec![Chord {
+                buttons: vec![Minus, Plus],
+                output: Home,
+            }],
+            vec![],
+            vec![(Home, hold(0)), (Minus, hold(1)), (Plus, hold(2))],
+        )
+    }
+
+    #[test]
+    fn chord_inside_its_window() {
+        let mut pipeline = chorded();
+        // The first button is held back in case the rest follow
+        let t = feed(&mut pipeline, &[Minus], 0);
+        assert!(t.pressed.is_empty());
+        let t = feed(&mut pipeline, &[Minus, Plus], 50);
+        assert_eq!(t.pressed, vec![0]);
+        let t = feed(&mut pipeline, &[Minus, Plus], 300);
+        assert!(t.pressed.is_empty() && t.released.is_empty());
+        let t = feed(&mut pipeline, &[], 400);
+        assert_eq!(t.released, vec![0]);
+        assert!(t.pressed.is_empty());
+    }
+
+    #[test]
+    fn chord_outside_its_window() {
+        let mut pipeline = chorded();
+        feed(&mut pipeline, &[Minus], 0);
+        let t = feed(&mut pipeline, &[Minus, Plus], 51);
+        assert_eq!(t.pressed, vec![1]);
+        // Plus is held back in turn, but the chord can't complete anymore
+        let t = feed(&mut pipeline, &[Minus, Plus], 101);
+        assert_eq!(t.pressed, vec![2]);
+        let t = feed(&mut pipeline, &[], 150);
+        assert_eq!(t.released, vec![1, 2]);
+    }
+
+    #[test]
+    fn held_back_button_tapped_alone() {
+        let mut pipeline = chorded();
+        feed(&mut pipeline, &[Minus], 0);
+        // Released before the window closed, so it's let through for a frame
+        let t = feed(&mut pipeline, &[], 20);
+        assert_eq!(t.pressed, vec![1]);
+        let t = feed(&mut pipeline, &[], 40);
+        assert_eq!(t.released, vec![1]);
+    }
+
+    #[test]
+    fn broken_chord_releases_its_buttons() {
+        let mut pipeline = chorded();
+        let t = feed(&mut pipeline, &[Minus, Plus], 0);
+        assert_eq!(t.pressed, vec![0]);
+        let t = feed(&mut pipeline, &[Minus], 100);
+        assert_eq!(t.released, vec![0]);
+        assert!(t.pressed.is_empty());
+        // What's left of the chord stays quiet until it's let go
+        let t = feed(&mut pipeline, &[Minus], 300);
+        assert!(t.pressed.is_empty());
+        let t = feed(&mut pipeline, &[], 400);
+        assert!(t.pressed.is_empty() && t.released.is_empty());
+        // ...after which it works on its own again
+        feed(&mut pipeline, &[Minus], 500);
+        let t = feed(&mut pipeline, &[Minus], 550);
+        assert_eq!(t.pressed, vec![1]);
+    }
+
+    fn tap_or_hold() -> Pipeline {
+        let binding = Binding {
+            hold: Some(6),
+            tap: Some(5),
+            turbo: None,
+        };
+        Pipeline::new(Timings::default(), vec![], vec![], vec![(A, binding)])
+    }
+
+    #[test]
+    fn tap_just_under_the_hold_time() {
+        let mut pipeline = tap_or_hold();
+        feed(&mut pipeline, &[A], 0);
+        let t = feed(&mut pipeline, &[A], 199);
+        assert!(t.pressed.is_empty());
+        let t = feed(&mut pipeline, &[], 199);
+        assert_eq!(t.tapped, vec![5]);
+        assert!(t.pressed.is_empty() && t.released.is_empty());
+    }
+
+    #[test]
+    fn hold_from_the_hold_time() {
+        let mut pipeline = tap_or_hold();
+        feed(&mut pipeline, &[A], 0);
+        let t = feed(&mut pipeline, &[A], 200);
+        assert_eq!(t.pressed, vec![6]);
+        let t = feed(&mut pipeline, &[], 250);
+        assert_eq!(t.released, vec![6]);
+        assert!(t.tapped.is_empty());
+    }
+
+    #[test]
+    fn layer_shift_while_buttons_are_held() {
+        let mut pipeline = Pipeline::new(
+            Timings::default(),
+            vec![],
+            vec![Layer {
+                button: Zl,
+                bindings: vec![(A, hold(10))],
+            }],
+            vec![(A, hold(1)), (B, hold(2))],
+        );
+        let t = feed(&mut pipeline, &[B], 0);
+        assert_eq!(t.pressed, vec![2]);
+        // B keeps the binding it was pressed with
+        let t = feed(&mut pipeline, &[B, Zl], 10);
+        assert!(t.pressed.is_empty() && t.released.is_empty());
+        let t = feed(&mut pipeline, &[B, Zl, A], 20);
+        assert_eq!(t.pressed, vec![10]);
+        // ...and so does A once the layer's let go
+        let t = feed(&mut pipeline, &[B, A], 30);
+        assert!(t.pressed.is_empty() && t.released.is_empty());
+        let t = feed(&mut pipeline, &[B], 40);
+        assert_eq!(t.released, vec![10]);
+        let t = feed(&mut pipeline, &[B, A], 50);
+        assert_eq!(t.pressed, vec![1]);
+        let t = feed(&mut pipeline, &[], 60);
+        assert_eq!(t.released, vec![1, 2]);
+    }
+
+    #[test]
+    fn turbo_period() {
+        let binding = Binding {
+            hold: Some(3),
+            tap: None,
+            turbo: Some(Duration::from_millis(100)),
+        };
+        let mut pipeline = Pipeline::new(Timings::default(), vec![], vec![], vec![(X, binding)]);
+        // On for the first half of each period, off for the second
+        assert_eq!(feed(&mut pipeline, &[X], 0).pressed, vec![3]);
+        assert!(feed(&mut pipeline, &[X], 49).released.is_empty());
+        assert_eq!(feed(&mut pipeline, &[X], 50).released, vec![3]);
+        assert!(feed(&mut pipeline, &[X], 99).pressed.is_empty());
+        assert_eq!(feed(&mut pipeline, &[X], 100).pressed, vec![3]);
+        assert_eq!(feed(&mut pipeline, &[X], 150).released, vec![3]);
+        assert_eq!(feed(&mut pipeline, &[X], 230).pressed, vec![3]);
+        assert_eq!(feed(&mut pipeline, &[], 240).released, vec![3]);
+    }
+
+    #[test]
+    fn release_all_lets_go_of_everything() {
+        let mut pipeline = chorded();
+        feed(&mut pipeline, &[Minus, Plus], 0);
+        assert_eq!(pipeline.release_all(), vec![0]);
+        let t = feed(&mut pipeline, &[], 100);
+        assert!(t.released.is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+//! What the four player LEDs show. The controller can only light or flash
+//! each of them, so anything that moves is stepped through by
+//! `LedController`, whenever the driver's loop calls `Driver::update_leds`.
+
+use std::time::{Duration, Instant};
+
+/// Bitmask the controller takes: the low nibble lights LEDs, player 1 first,
+/// and the high nibble flashes them
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LedState {
+    Raw(u8),
+    /// The first N of the LEDs lit, from 0 to 4
+    Steady(u8),
+    /// The first N of the LEDs flashing, from 0 to 4
+    Flash(u8),
+    /// One LED running back and forth, while a controller waits for a player
+    Searching,
+    /// Every LED blinking quickly, when something is wrong
+    Error,
+}
+
+impl LedState {
+    /// Player numbers from 1 to 8 the way the Switch shows them: players 1
+    /// to 4 light that many LEDs, and players 5 to 8 flash them
+    pub fn player(player: u8) -> LedState {
+        let count = (player.max(1) - 1) % 4 + 1;
+        if player > 4 {
+            LedState::Flash(count)
+        } else {
+            LedState::Steady(count)
+        }
+    }
+}
+
+// Bitmasks shown in turn, each for `interval`, over and over
+struct Sequence {
+    interval: Duration,
+    frames: &'static [u8],
+}
+
+const SEARCHING: Sequence = Sequence {
+    interval: Duration::from_millis(150),
+    frames: &[0b0001, 0b0010, 0b0100, 0b1000, 0b0100, 0b0010],
+};
+
+const ERROR: Sequence = Sequence {
+    interval: Duration::from_millis(200),
+    frames: &[0b1111, 0b0000],
+};
+
+impl Sequence {
+    fn frame(&self, elapsed: Duration) -> u8 {
+        let step = elapsed.as_millis() / self.interval.as_millis();
+        self.frames[(step % self.frames.len() as u128) as usize]
+    }
+}
+
+/// Keeps track of the state the LEDs should be in, and how far along its
+/// sequence they are
+pub struct LedController {
+    state: LedState,
+    since: Instant,
+}
+
+impl LedController {
+    pub fn new(state: LedState) -> LedController {
+        LedController {
+            state,
+            since: Instant::now(),
+        }
+    }
+
+    pub fn state(&self) -> LedState {
+        self.state
+    }
+
+    /// Switching to a sequence starts it from the beginning, unless it's
+    /// already playing
+    pub fn set(&mut self, state: LedState) {
+        if state != self.state {
+            self.state = state;
+            self.since = Instant::now();
+        }
+    }
+
+    /// What the LEDs should show right now, as a raw bitmask
+    pub fn bitmask(&self) -> u8 {
+        match self.state {
+            LedState::Raw(bitmask) => bitmask,
+            LedState::Steady(count) => lit(count),
+            LedState::Flash(count) => lit(count) << 4,
+            LedState::Searching => SEARCHING.frame(self.since.elapsed()),
+            LedState::Error => ERROR.frame(self.since.elapsed()),
+        }
+    }
+}
+
+// The first `count` LEDs
+fn lit(count: u8) -> u8 {
+    (1u8 << count.min(4)) - 1
+}
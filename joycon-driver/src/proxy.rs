@@ -0,0 +1,92 @@
+use std::io::{Error, ErrorKind};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use common::ipc::paths;
+use common::ipc::protocol::{self, Response};
+use common::ipc::proxy::{InputSample, ProxyCommand, ProxyRequest};
+
+use super::frame::InputFrame;
+use super::id::Product;
+
+/// The driver's end of a connection to `joycon-proxy`, which owns the
+/// virtual device for the controller
+pub struct ProxyLink {
+    stream: UnixStream,
+    commands: Receiver<ProxyCommand>,
+}
+
+impl ProxyLink {
+    /// Connects to the proxy for `id`, among those in `dir`, as the driver of
+    /// the controller `serial`
+    pub fn connect(dir: &Path, id: &str, serial: &str) -> Result<ProxyLink, Error> {
+        let mut stream = UnixStream::connect(paths::proxy_path(dir, id))?;
+        protocol::write_message(
+            &mut stream,
+            &ProxyRequest::Driver {
+                version: protocol::VERSION,
+                serial: serial.to_string(),
+            },
+        )?;
+        match protocol::read_message(&mut stream)? {
+            Response::Ok => (),
+            Response::Error { message, .. } => return Err(Error::other(message)),
+            response => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unexpected response {:?}", response),
+                ))
+            }
+        }
+
+        // Commands are read on their own thread, so checking for them never blocks
+        let (tx, commands) = mpsc::channel();
+        let mut reader = stream.try_clone()?;
+        thread::spawn(move || {
+            while let Ok(command) = protocol::read_message(&mut reader) {
+                if tx.send(command).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(ProxyLink { stream, commands })
+    }
+
+    pub fn send(&mut self, frame: &InputFrame, product: Option<Product>) -> Result<(), Error> {
+        protocol::write_message(
+            &mut self.stream,
+            &ProxyRequest::Input {
+                sample: InputSample::from(frame),
+                product: product.map(|product| format!("{:?}", product)),
+            },
+        )
+    }
+
+    pub fn release(&mut self) -> Result<(), Error> {
+        protocol::write_message(&mut self.stream, &ProxyRequest::Release)
+    }
+
+    /// Tell the proxy which controllers its input comes from, e.g. both
+    /// halves of a pair, so it can choose the profile for them
+    pub fn set_serials(&mut self, serials: &[String]) -> Result<(), Error> {
+        protocol::write_message(
+            &mut self.stream,
+            &ProxyRequest::Serials {
+                serials: serials.to_vec(),
+            },
+        )
+    }
+
+    /// The next command from the proxy, if one is waiting
+    pub fn next_command(&self) -> Result<Option<ProxyCommand>, Error> {
+        match self.commands.try_recv() {
+            Ok(command) => Ok(Some(command)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => {
+                Err(Error::new(ErrorKind::BrokenPipe, "Proxy went away"))
+            }
+        }
+    }
+}
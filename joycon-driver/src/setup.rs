@@ -0,0 +1,43 @@
+//! Steps for setting a controller up, which `Driver::poll_setup` works
+//! through without blocking, waiting for the controller to answer each one
+//! before sending the next. That way one caller can set up several
+//! controllers at once, and a slow one doesn't hold up the rest.
+
+use super::calibration;
+use super::device::InputMode;
+use super::player_leds::LedState;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    InputMode(InputMode),
+    /// Ask for the firmware version, product and MAC address
+    DeviceInfo,
+    /// Read a range of flash into the driver's mirror of it
+    ReadFlash(u32, usize),
+    /// Take the stick and gyroscope calibration from the flash read by
+    /// earlier steps, which takes no time
+    LoadCalibration,
+    /// Ask what the player LEDs show
+    ReadLeds,
+    /// Doesn't wait for an answer
+    Leds(LedState),
+    Imu(bool),
+    Vibration(bool),
+}
+
+/// Everything needed to tell what a controller is, and what colors it is
+pub const IDENTIFY: [Step; 4] = [
+    Step::InputMode(InputMode::Simple),
+    Step::DeviceInfo,
+    Step::ReadFlash(0x6050, 6),
+    Step::ReadLeds,
+];
+
+/// Reads and applies the calibration stored in flash
+pub const CALIBRATE: [Step; 5] = [
+    Step::ReadFlash(calibration::FACTORY_STICKS.0, calibration::FACTORY_STICKS.1),
+    Step::ReadFlash(calibration::FACTORY_IMU.0, calibration::FACTORY_IMU.1),
+    Step::ReadFlash(calibration::USER_STICKS.0, calibration::USER_STICKS.1),
+    Step::ReadFlash(calibration::USER_IMU.0, calibration::USER_IMU.1),
+    Step::LoadCalibration,
+];
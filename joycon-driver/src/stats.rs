@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use arraydeque::{ArrayDeque, Wrapping};
+
+/// Rolling record of the last 512 input reports (~8s in full mode), used to
+/// diagnose a poor wireless connection
+#[derive(Default)]
+pub struct ReportStats {
+    last_timer: Option<u8>,
+    last_read: Option<Instant>,
+    // Controller timer ticks between consecutive reports
+    ticks: ArrayDeque<[u8; 512], Wrapping>,
+    // Host time between consecutive reads
+    intervals: ArrayDeque<[Duration; 512], Wrapping>,
+    // Host time from reading a report until its frame was consumed
+    latencies: ArrayDeque<[Duration; 512], Wrapping>,
+}
+
+impl ReportStats {
+    /// Record a report carrying the given timer byte, read from the device at `read_at`
+    pub fn record_report(&mut self, timer: u8, read_at: Instant) {
+        if let Some(last) = self.last_timer {
+            self.ticks.push_back(timer.wrapping_sub(last));
+        }
+        if let Some(last) = self.last_read {
+            self.intervals.push_back(read_at.duration_since(last));
+        }
+        self.last_timer = Some(timer);
+        self.last_read = Some(read_at);
+    }
+
+    /// Record how long a frame waited between being read and being consumed
+    pub fn record_latency(&mut self, latency: Duration) {
+        self.latencies.push_back(latency);
+    }
+
+    pub fn summary(&self) -> StatsSummary {
+        // The controller's report rate, in timer ticks, is whichever step it takes most often
+        let mut steps = HashMap::new();
+        for &ticks in self.ticks.iter().filter(|&&t| t > 0) {
+            *steps.entry(ticks).or_insert(0) += 1;
+        }
+        let step = steps
+            .into_iter()
+            .max_by_key(|&(ticks, count)| (count, ticks))
+            .map_or(1, |(ticks, _)| u32::from(ticks));
+
+        let mut lost = 0;
+        let mut duplicated = 0;
+        for &ticks in self.ticks.iter() {
+            match u32::from(ticks) {
+                0 => duplicated += 1,
+                ticks => lost += ((ticks + step / 2) / step).saturating_sub(1),
+            }
+        }
+
+        let reports = self.ticks.len() as u32;
+        let expected = reports - duplicated + lost;
+        StatsSummary {
+            reports,
+            lost,
+            duplicated,
+            loss_percent: if expected > 0 {
+                lost as f32 * 100.0 / expected as f32
+            } else {
+                0.0
+            },
+            interval: Distribution::of(&self.intervals),
+            latency: Distribution::of(&self.latencies),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Distribution {
+    pub mean: Duration,
+    pub p99: Duration,
+    /// Standard deviation, i.e. jitter
+    pub deviation: Duration,
+}
+
+impl Distribution {
+    fn of(samples: &ArrayDeque<[Duration; 512], Wrapping>) -> Distribution {
+        if samples.is_empty() {
+            return Distribution::default();
+        }
+        let mut sorted: Vec<f64> = samples.iter().map(|d| d.as_secs_f64()).collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let n = sorted.len() as f64;
+        let mean = sorted.iter().sum::<f64>() / n;
+        let variance = sorted.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / n;
+        let p99 = sorted[((n * 0.99).ceil() as usize).max(1) - 1];
+        Distribution {
+            mean: Duration::from_secs_f64(mean),
+            p99: Duration::from_secs_f64(p99),
+            deviation: Duration::from_secs_f64(variance.sqrt()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StatsSummary {
+    /// Reports in the window
+    pub reports: u32,
+    /// Reports the timer skipped over, which never arrived
+    pub lost: u32,
+    /// Reports that repeated the previous timer value
+    pub duplicated: u32,
+    pub loss_percent: f32,
+    /// Time between reports arriving on the host
+    pub interval: Distribution,
+    /// Time from a report arriving until it was handled
+    pub latency: Distribution,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl fmt::Display for StatsSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} reports, {} lost ({:.1}%), {} duplicated, \
+             interval {:.1}ms mean / {:.1}ms p99 / {:.1}ms jitter, \
+             latency {:.2}ms mean / {:.2}ms p99",
+            self.reports,
+            self.lost,
+            self.loss_percent,
+            self.duplicated,
+            millis(self.interval.mean),
+            millis(self.interval.p99),
+            millis(self.interval.deviation),
+            millis(self.latency.mean),
+            millis(self.latency.p99),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stats after reports with these timer bytes, read 15ms apart
+    fn stats(timers: &[u8]) -> StatsSummary {
+        let mut stats = ReportStats::default();
+        let start = Instant::now();
+        for (i, &timer) in timers.iter().enumerate() {
+            stats.record_report(timer, start + Duration::from_millis(15 * i as u64));
+        }
+        stats.summary()
+    }
+
+    #[test]
+    fn steady_stream_loses_nothing() {
+        let timers: Vec<u8> = (0..100).map(|i| (i * 3) as u8).collect();
+        let summary = stats(&timers);
+        assert_eq!(summary.reports, 99);
+        assert_eq!(summary.lost, 0);
+        assert_eq!(summary.duplicated, 0);
+        assert_eq!(summary.loss_percent, 0.0);
+        assert_eq!(summary.interval.mean, Duration::from_millis(15));
+        assert_eq!(summary.interval.deviation, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn counts_a_gap() {
+        let summary = stats(&[0, 3, 6, 12, 15]);
+        assert_eq!(summary.reports, 4);
+        assert_eq!(summary.lost, 1);
+        assert_eq!(summary.duplicated, 0);
+        // One of the five reports expected
+        assert_eq!(summary.loss_percent, 20.0);
+    }
+
+    #[test]
+    fn rounds_jitter_to_the_nearest_step() {
+        // 4 ticks is a late report, 5 is one missing and the next early
+        assert_eq!(stats(&[0, 3, 6, 9, 13, 16, 19]).lost, 0);
+        assert_eq!(stats(&[0, 3, 6, 9, 14, 17, 20]).lost, 1);
+    }
+
+    #[test]
+    fn counts_a_duplicate() {
+        let summary = stats(&[0, 3, 3, 6, 9]);
+        assert_eq!(summary.reports, 4);
+        assert_eq!(summary.duplicated, 1);
+        assert_eq!(summary.lost, 0);
+        assert_eq!(summary.loss_percent, 0.0);
+    }
+
+    #[test]
+    fn timer_wraps_around() {
+        assert_eq!(stats(&[249, 252, 255, 2, 5]).lost, 0);
+        let summary = stats(&[246, 249, 252, 255, 5, 8]);
+        assert_eq!(summary.lost, 1);
+        assert_eq!(summary.duplicated, 0);
+    }
+
+    #[test]
+    fn latency_distribution() {
+        let mut stats = ReportStats::default();
+        for ms in 1..=100 {
+            stats.record_latency(Duration::from_millis(ms));
+        }
+        let latency = stats.summary().latency;
+        assert_eq!(latency.mean, Duration::from_micros(50_500));
+        assert_eq!(latency.p99, Duration::from_millis(99));
+    }
+}
@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+// Approximate period of the timer byte in each input report. It counts up by
+// about three between the full-mode reports sent every 15ms.
+const TICK_MICROS: u64 = 5000;
+
+/// Unwraps the 8-bit timer sent with each input report into a monotonic clock
+#[derive(Default)]
+pub struct ReportClock {
+    last: Option<u8>,
+    ticks: u64,
+}
+
+impl ReportClock {
+    /// Advance the clock to the timer value of a new report, returning the
+    /// time since the first report
+    pub fn tick(&mut self, timer: u8) -> Duration {
+        if let Some(last) = self.last {
+            self.ticks += u64::from(timer.wrapping_sub(last));
+        }
+        self.last = Some(timer);
+        self.time()
+    }
+
+    pub fn time(&self) -> Duration {
+        Duration::from_micros(self.ticks * TICK_MICROS)
+    }
+}
@@ -1 +1,373 @@
-fn main() {}
+extern crate getopts;
+extern crate signal_hook;
+
+extern crate common;
+extern crate joycon_driver;
+
+use std::env;
+use std::fs::{self, Permissions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use getopts::Options;
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGINT, SIGTERM};
+
+use common::config;
+use common::ipc::peer;
+use common::ipc::protocol::{self, Response};
+use common::ipc::proxy::{ProxyCommand, ProxyRequest};
+use common::ipc::{self, paths};
+use common::log;
+
+use joycon_driver::evdev::VirtualDevice;
+use joycon_driver::frame::InputFrame;
+use joycon_driver::id::Product;
+use joycon_driver::mapping::{self, Context, Mapper, Profile};
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help text");
+    opts.optmulti(
+        "p",
+        "profile",
+        "Remap buttons and sticks with a profile, may be given several times",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "name",
+        "Name of the virtual device (default \"Joy-Con (ID)\")",
+        "NAME",
+    );
+    opts.optmulti(
+        "u",
+        "allow-user",
+        "Take connections from this user as well as root and our own, may be given several times",
+        "USER",
+    );
+    opts.optopt(
+        "",
+        "dir",
+        &format!(
+            "Directory for the socket, named after ID (default daemon.proxy_dir in joycond.toml, \
+             or {})",
+            paths::PROXY_ROOT_PATH
+        ),
+        "DIR",
+    );
+    opts
+}
+
+/// A virtual device, and whoever is feeding it
+struct Proxy {
+    name: String,
+    // Users allowed to connect, since the socket is open to everyone
+    allowed: Vec<u32>,
+    output: Mutex<Output>,
+    // Where commands go, while a driver is connected
+    driver: Mutex<Option<UnixStream>>,
+}
+
+struct Output {
+    mapper: Mapper,
+    // Made when the first driver connects, and kept when it goes away, so
+    // games don't lose the device while the controller reconnects
+    device: Option<VirtualDevice>,
+}
+
+impl Proxy {
+    fn handle_connection(&self, mut stream: UnixStream) -> Result<(), Error> {
+        let credentials = peer::credentials(&stream)?;
+        if !self.allowed.contains(&credentials.uid) {
+            log::e(&format!(
+                "Turning away user {} (pid {})",
+                credentials.uid, credentials.pid
+            ));
+            return Ok(());
+        }
+
+        match protocol::read_message(&mut stream)? {
+            ProxyRequest::Driver { version, serial } => {
+                if let Some(response) = check_version(version) {
+                    return protocol::write_message(&mut stream, &response);
+                }
+                self.drive(stream, &serial)
+            }
+            ProxyRequest::Control { version } => {
+                if let Some(response) = check_version(version) {
+                    return protocol::write_message(&mut stream, &response);
+                }
+                protocol::write_message(&mut stream, &Response::Ok)?;
+                self.control(stream)
+            }
+            _ => protocol::write_message(
+                &mut stream,
+                &Response::error(
+                    protocol::ErrorKind::InvalidRequest,
+                    "Expected driver or control",
+                ),
+            ),
+        }
+    }
+
+    // Forwards input from the driver to the virtual device until it disconnects
+    fn drive(&self, mut stream: UnixStream, serial: &str) -> Result<(), Error> {
+        {
+            let mut driver = self.driver.lock().unwrap();
+            if driver.is_some() {
+                return protocol::write_message(
+                    &mut stream,
+                    &Response::error(
+                        protocol::ErrorKind::InvalidRequest,
+                        "Another driver is connected",
+                    ),
+                );
+            }
+            if let Err(e) = self.open_output(&[serial.to_string()]) {
+                return protocol::write_message(
+                    &mut stream,
+                    &Response::error(protocol::ErrorKind::Device, &e),
+                );
+            }
+            *driver = Some(stream.try_clone()?);
+        }
+        log::i(&format!("Driver for {} connected", serial));
+
+        let result = protocol::write_message(&mut stream, &Response::Ok).and_then(|_| loop {
+            match protocol::read_message(&mut stream)? {
+                ProxyRequest::Input { sample, product } => {
+                    let frame = InputFrame::from(&sample);
+                    let product = product.as_ref().and_then(|name| Product::from_name(name));
+                    let output = &mut *self.output.lock().unwrap();
+                    if let Some(ref mut device) = output.device {
+                        let time = frame.time;
+                        if let Err(e) = output.mapper.update(&frame, product, time, device) {
+                            log::e(&format!("Couldn't forward input: {:?}", e));
+                        }
+                    }
+                }
+                ProxyRequest::Release => self.release(),
+                ProxyRequest::Serials { serials } => {
+                    let output = &mut *self.output.lock().unwrap();
+                    if let Some(ref mut device) = output.device {
+                        let context = Context {
+                            serials: &serials,
+                            application: None,
+                        };
+                        if let Err(e) = output.mapper.select(&context, device) {
+                            log::e(&format!("Couldn't set up {}: {:?}", self.name, e));
+                        }
+                    }
+                }
+                request => log::e(&format!("Ignoring {:?} from the driver", request)),
+            }
+        });
+
+        *self.driver.lock().unwrap() = None;
+        self.release();
+        log::i(&format!("Driver for {} disconnected", serial));
+        result
+    }
+
+    fn release(&self) {
+        let output = &mut *self.output.lock().unwrap();
+        if let Some(ref mut device) = output.device {
+            if let Err(e) = output.mapper.release(device) {
+                log::e(&format!("Couldn't release input: {:?}", e));
+            }
+        }
+    }
+
+    // Creates the virtual device if this is the first driver, and picks the
+    // profile for its controller
+    fn open_output(&self, serials: &[String]) -> Result<(), String> {
+        let output = &mut *self.output.lock().unwrap();
+        let mut device = match output.device.take() {
+            Some(device) => device,
+            None => VirtualDevice::new(&self.name, &output.mapper.events())
+                .map_err(|e| format!("Couldn't create {}: {:?}", self.name, e))?,
+        };
+        let context = Context {
+            serials,
+            application: None,
+        };
+        let selected = output.mapper.select(&context, &mut device);
+        output.device = Some(device);
+        selected.map_err(|e| format!("Couldn't set up {}: {:?}", self.name, e))
+    }
+
+    // Passes commands on to the driver, answering each one
+    fn control(&self, mut stream: UnixStream) -> Result<(), Error> {
+        loop {
+            let command = match protocol::read_message(&mut stream)? {
+                ProxyRequest::SetLeds { leds } => Some(ProxyCommand::SetLeds { leds }),
+                ProxyRequest::Rumble { rumble } => Some(ProxyCommand::Rumble { rumble }),
+                _ => None,
+            };
+            let response = match command {
+                Some(command) => self.command(&command),
+                None => Response::error(protocol::ErrorKind::InvalidRequest, "Expected a command"),
+            };
+            protocol::write_message(&mut stream, &response)?;
+        }
+    }
+
+    fn command(&self, command: &ProxyCommand) -> Response {
+        let mut driver = self.driver.lock().unwrap();
+        let sent = match *driver {
+            Some(ref mut driver) => protocol::write_message(driver, command),
+            None => {
+                return Response::error(
+                    protocol::ErrorKind::NoSuchController,
+                    "No driver is connected",
+                )
+            }
+        };
+        match sent {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::error(
+                protocol::ErrorKind::Device,
+                &format!("Couldn't reach the driver: {}", e),
+            ),
+        }
+    }
+}
+
+fn check_version(version: u32) -> Option<Response> {
+    if version == protocol::VERSION {
+        None
+    } else {
+        Some(Response::error(
+            protocol::ErrorKind::UnsupportedVersion,
+            &format!(
+                "Protocol version {} isn't supported, expected {}",
+                version,
+                protocol::VERSION
+            ),
+        ))
+    }
+}
+
+fn load_profiles(paths: &[String]) -> Result<Vec<Profile>, String> {
+    let mut profiles = paths
+        .iter()
+        .map(|path| Profile::load(Path::new(path)))
+        .collect::<Result<Vec<Profile>, String>>()?;
+    if profiles.is_empty() {
+        profiles.push(Profile::parse(mapping::DEFAULT_PROFILE).expect("Default profile is valid"));
+    }
+    Ok(profiles)
+}
+
+// Anyone may connect, and credentials are checked per connection
+fn bind(socket: &Path) -> Result<UnixListener, String> {
+    let listener = ipc::socket::bind(socket)
+        .map_err(|e| format!("Couldn't open {}: {}", socket.display(), e))?;
+    fs::set_permissions(socket, Permissions::from_mode(0o666))
+        .map_err(|e| format!("Couldn't open up {}: {}", socket.display(), e))?;
+    Ok(listener)
+}
+
+fn allowed_users(users: &[String]) -> Result<Vec<u32>, String> {
+    let mut allowed = vec![0, peer::own_user_id()];
+    for user in users {
+        allowed.push(peer::user_id(user).map_err(|e| format!("--allow-user: {}", e))?);
+    }
+    Ok(allowed)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let opts = options();
+    let usage = opts.usage(&format!("Usage: {} [options] ID", args[0]));
+    let matches = match opts.parse(&args[1..]) {
+        Ok(matches) => matches,
+        Err(e) => {
+            eprint!("{}\n{}", e, usage);
+            process::exit(2);
+        }
+    };
+    if matches.opt_present("help") || matches.free.len() != 1 {
+        print!("{}", usage);
+        return;
+    }
+
+    let id = &matches.free[0];
+    let setup = load_profiles(&matches.opt_strs("profile"))
+        .and_then(|profiles| Ok((profiles, allowed_users(&matches.opt_strs("allow-user"))?)));
+    let (profiles, allowed) = match setup {
+        Ok(setup) => setup,
+        Err(e) => {
+            log::wtf(&e);
+            process::exit(1);
+        }
+    };
+    let signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(signals) => signals,
+        Err(e) => panic!("{}", e),
+    };
+    let dir = match matches.opt_str("dir") {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => config::proxy_dir().map_err(|e| format!("Invalid configuration: {}", e)),
+    };
+    let listener = dir.and_then(|dir| {
+        let socket = paths::proxy_path(&dir, id);
+        bind(&socket).map(|listener| (socket, listener))
+    });
+    let (socket, listener) = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::wtf(&e);
+            process::exit(1);
+        }
+    };
+    log::i(&format!("Listening on {}", socket.display()));
+
+    let proxy = Arc::new(Proxy {
+        name: matches
+            .opt_str("name")
+            .unwrap_or_else(|| format!("Joy-Con ({})", id)),
+        allowed,
+        output: Mutex::new(Output {
+            mapper: Mapper::new(profiles),
+            device: None,
+        }),
+        driver: Mutex::new(None),
+    });
+    let listening = proxy.clone();
+    thread::spawn(move || listen(listener, listening));
+
+    // Games get nothing stuck down, and the next proxy for this ID finds no
+    // socket in its way
+    signals.forever().next();
+    log::i("Shutting down");
+    proxy.release();
+    ipc::socket::unlink(&socket);
+}
+
+fn listen(listener: UnixListener, proxy: Arc<Proxy>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                log::e(&format!("Failed to connect: {:?}", e));
+                continue;
+            }
+        };
+
+        let proxy = proxy.clone();
+        thread::spawn(move || {
+            if let Err(e) = proxy.handle_connection(stream) {
+                if e.kind() != ErrorKind::UnexpectedEof {
+                    log::e(&format!("Connection failed: {:?}", e));
+                }
+            }
+        });
+    }
+}
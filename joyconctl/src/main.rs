@@ -0,0 +1,513 @@
+extern crate getopts;
+#[macro_use]
+extern crate serde_json;
+
+extern crate common;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::process;
+use std::str::FromStr;
+
+use getopts::{Matches, Options};
+
+use common::config;
+use common::ipc::client::Error;
+use common::ipc::paths;
+use common::ipc::protocol::{
+    self, Battery, ControllerInfo, ControllerState, Event, Filter, HomeLight, Power, Rumble,
+    Setting,
+};
+use common::ipc::Client;
+use common::leds;
+
+const COMMANDS: &str = "
+Commands:
+    list                      Connected controllers
+    status SERIAL             Buttons, sticks and LEDs of a controller
+    leds SERIAL PATTERN       Set player LEDs, e.g. 1010, with f to flash
+    rumble SERIAL             Rumble with --freq, --amp and --ms
+    home-light SERIAL LIGHT   Set the Home light to off, on, breathing or blink
+    power SERIAL STATE        Disconnect a controller to sleep, reconnect,
+                              pair or home
+    power-off [SERIAL...]     Put controllers to sleep, or with --idle every
+                              one unused for that many minutes, at least 1
+    pair LEFT RIGHT           Combine two Joy-Con into one controller
+    unpair SERIAL             Split a paired controller
+    set SERIAL KEY=VALUE...   Save settings: name, slot, profile, deadzones
+                              (left,right) and rumble-strength. An empty
+                              name, slot or profile clears it.
+    battery [SERIAL...]       Battery level of some or all controllers, with
+                              --uevent like a power_supply device's uevent
+    calibrate SERIAL          Take the current sticks and gyroscope as neutral
+    dump-flash SERIAL FILE    Save SPI flash to FILE, or - for stdout
+    watch [SERIAL...]         Print events as they happen, with --buttons,
+                              --battery and --rate for more than connections
+";
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help text");
+    opts.optflag("j", "json", "Print results as JSON for scripts");
+    opts.optopt(
+        "",
+        "socket",
+        &format!(
+            "Daemon socket (default daemon.socket in joycond.toml, or {})",
+            paths::DAEMON_PATH
+        ),
+        "PATH",
+    );
+    opts.optopt("", "freq", "Rumble frequency (default 160)", "HZ");
+    opts.optopt(
+        "",
+        "amp",
+        "Rumble amplitude from 0 to 1 (default 0.5)",
+        "AMP",
+    );
+    opts.optopt("", "ms", "Rumble duration (default 300)", "MS");
+    opts.optopt(
+        "",
+        "offset",
+        "First flash address to dump (default 0)",
+        "ADDR",
+    );
+    opts.optopt(
+        "",
+        "length",
+        "Bytes of flash to dump (default all of it)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "idle",
+        "Power off controllers unused for this many minutes",
+        "MINUTES",
+    );
+    opts.optflag("", "buttons", "Watch button presses and releases");
+    opts.optflag("", "battery", "Watch battery changes");
+    opts.optflag("", "uevent", "Print batteries as POWER_SUPPLY_* variables");
+    opts.optopt(
+        "",
+        "rate",
+        "Watch sticks and motion this many times a second (default 0)",
+        "HZ",
+    );
+    opts
+}
+
+fn parse_opt<T: FromStr>(matches: &Matches, name: &str, default: T) -> Result<T, String> {
+    match matches.opt_str(name) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("Invalid value \"{}\" for --{}", value, name)),
+        None => Ok(default),
+    }
+}
+
+// Flash addresses are usually written in hex
+fn parse_address(matches: &Matches, name: &str, default: u32) -> Result<u32, String> {
+    match matches.opt_str(name) {
+        Some(value) => {
+            let parsed = match value.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => value.parse(),
+            };
+            parsed.map_err(|_| format!("Invalid value \"{}\" for --{}", value, name))
+        }
+        None => Ok(default),
+    }
+}
+
+// A setting as given on the command line, e.g. "deadzones=0.1,0.15"
+fn parse_setting(arg: &str) -> Result<Setting, String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Expected KEY=VALUE, not \"{}\"", arg))?;
+    let invalid = || format!("Invalid value \"{}\" for {}", value, key);
+    let given = Some(value).filter(|value| !value.is_empty());
+    match key {
+        "name" => Ok(Setting::Name(given.map(str::to_string))),
+        "slot" => match given {
+            Some(slot) => slot.parse().map(|slot| Setting::Slot(Some(slot))),
+            None => Ok(Setting::Slot(None)),
+        }
+        .map_err(|_| invalid()),
+        // The daemon doesn't share our working directory
+        "profile" => match given.map(|path| env::current_dir().map(|dir| dir.join(path))) {
+            Some(Ok(path)) => Ok(Setting::Profile(Some(path.display().to_string()))),
+            Some(Err(e)) => Err(e.to_string()),
+            None => Ok(Setting::Profile(None)),
+        },
+        "deadzones" => match value.split_once(',') {
+            Some((left, right)) => match (left.parse(), right.parse()) {
+                (Ok(left), Ok(right)) => Ok(Setting::Deadzones([left, right])),
+                _ => Err(invalid()),
+            },
+            None => Err(invalid()),
+        },
+        "rumble-strength" => value
+            .parse()
+            .map(Setting::RumbleStrength)
+            .map_err(|_| invalid()),
+        _ => Err(format!("Unknown setting \"{}\"", key)),
+    }
+}
+
+fn print_controllers(controllers: &[ControllerInfo]) {
+    if controllers.is_empty() {
+        println!("No controllers connected");
+        return;
+    }
+    println!(
+        "{:<14} {:<18} {:<12} {:<7} {:<18} {:<9} PAIRED WITH",
+        "SERIAL", "NAME", "STATUS", "PLAYER", "MAC", "FIRMWARE"
+    );
+    for controller in controllers {
+        println!(
+            "{:<14} {:<18} {:<12} {:<7} {:<18} {:<9} {}",
+            controller.serial,
+            controller.name.as_ref().unwrap_or(&controller.product),
+            format!("{:?}", controller.status),
+            controller
+                .slot
+                .map_or_else(|| "-".to_string(), |slot| slot.to_string()),
+            controller.mac_address.as_deref().unwrap_or("-"),
+            controller.firmware_version.as_deref().unwrap_or("-"),
+            controller.paired_with.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+fn print_state(state: &ControllerState) {
+    println!("Serial:  {}", state.serial);
+    println!("Buttons: {}", state.buttons.join(" "));
+    println!(
+        "Sticks:  L ({:4}, {:4})  R ({:4}, {:4})",
+        state.sticks[0], state.sticks[1], state.sticks[2], state.sticks[3]
+    );
+    match state.reported_leds {
+        Some(reported) if reported != state.leds => println!(
+            "LEDs:    {} (controller reports {})",
+            leds::format(state.leds),
+            leds::format(reported)
+        ),
+        _ => println!("LEDs:    {}", leds::format(state.leds)),
+    }
+}
+
+fn print_battery(serial: &str, battery: &Battery) {
+    let state = if battery.charging {
+        ", charging"
+    } else if battery.powered {
+        ", plugged in"
+    } else {
+        ""
+    };
+    let reading = match (battery.percent, battery.millivolts) {
+        (Some(percent), Some(millivolts)) => format!(" ({}%, {} mV)", percent, millivolts),
+        _ => String::new(),
+    };
+    println!("{:<14} {:?}{}{}", serial, battery.level, reading, state);
+}
+
+// Like /sys/class/power_supply/*/uevent, with the serial as the name
+fn print_uevent(serial: &str, battery: &Battery) {
+    println!("POWER_SUPPLY_NAME={}", serial);
+    for (key, value) in battery.power_supply() {
+        println!("{}={}", key, value);
+    }
+}
+
+fn print_event(event: &Event) {
+    match event {
+        Event::Connected { controller } => println!(
+            "{:<14} connected, {}",
+            controller.serial,
+            controller.name.as_ref().unwrap_or(&controller.product)
+        ),
+        Event::Disconnected { serial } => println!("{:<14} disconnected", serial),
+        Event::Button {
+            serial,
+            button,
+            pressed,
+            time_ms,
+        } => println!(
+            "{:<14} {} {} at {}.{:03}s",
+            serial,
+            button,
+            if *pressed { "pressed" } else { "released" },
+            time_ms / 1000,
+            time_ms % 1000
+        ),
+        Event::Sample {
+            serial,
+            sticks,
+            accelerometer,
+            gyroscope,
+        } => println!(
+            "{:<14} L ({:4}, {:4})  R ({:4}, {:4})  accel {:?}  gyro {:?}",
+            serial, sticks[0], sticks[1], sticks[2], sticks[3], accelerometer, gyroscope
+        ),
+        Event::Battery { serial, battery } => print_battery(serial, battery),
+        Event::LowBattery { serial, battery } => {
+            println!("{:<14} battery low, {:?}", serial, battery.level)
+        }
+    }
+}
+
+fn print_json(value: &serde_json::Value) {
+    println!("{}", value);
+}
+
+fn run(matches: &Matches, mut client: Client, json: bool) -> Result<(), String> {
+    let args = &matches.free;
+    let arg = |i: usize, name: &str| {
+        args.get(i)
+            .map(|s| s.as_str())
+            .ok_or_else(|| format!("Missing {} for \"{}\"", name, args[0]))
+    };
+    let daemon = |e: Error| e.to_string();
+
+    match args[0].as_str() {
+        "list" => {
+            let controllers = client.list().map_err(daemon)?;
+            if json {
+                print_json(&json!(controllers));
+            } else {
+                print_controllers(&controllers);
+            }
+        }
+        "status" => {
+            let state = client.state(arg(1, "SERIAL")?).map_err(daemon)?;
+            if json {
+                print_json(&json!(state));
+            } else {
+                print_state(&state);
+            }
+        }
+        "leds" => {
+            let leds = leds::parse(arg(2, "PATTERN")?)?;
+            client.set_leds(arg(1, "SERIAL")?, leds).map_err(daemon)?;
+            if json {
+                print_json(&json!({ "ok": true }));
+            }
+        }
+        "rumble" => {
+            let rumble = Rumble {
+                frequency: parse_opt(matches, "freq", 160.0)?,
+                amplitude: parse_opt(matches, "amp", 0.5)?,
+                duration_ms: parse_opt(matches, "ms", 300)?,
+            };
+            client.rumble(arg(1, "SERIAL")?, rumble).map_err(daemon)?;
+            if json {
+                print_json(&json!({ "ok": true }));
+            }
+        }
+        "home-light" => {
+            let light = match arg(2, "LIGHT")? {
+                "off" => HomeLight::Off,
+                "on" => HomeLight::On,
+                "breathing" => HomeLight::Breathing,
+                "blink" => HomeLight::Blink,
+                light => return Err(format!("Unknown Home light \"{}\"", light)),
+            };
+            client
+                .set_home_light(arg(1, "SERIAL")?, light)
+                .map_err(daemon)?;
+            if json {
+                print_json(&json!({ "ok": true }));
+            }
+        }
+        "power" => {
+            let power = match arg(2, "STATE")? {
+                "sleep" => Power::Sleep,
+                "reconnect" => Power::Reconnect,
+                "pair" => Power::Pair,
+                "home" => Power::Home,
+                power => return Err(format!("Unknown power state \"{}\"", power)),
+            };
+            client.set_power(arg(1, "SERIAL")?, power).map_err(daemon)?;
+            if json {
+                print_json(&json!({ "ok": true }));
+            }
+        }
+        "power-off" => {
+            let serials = if matches.opt_present("idle") {
+                let minutes: u64 = parse_opt(matches, "idle", 0)?;
+                if minutes == 0 || minutes > u64::MAX / 60 {
+                    return Err(format!("Invalid value \"{}\" for --idle", minutes));
+                }
+                client.sleep_idle(minutes * 60).map_err(daemon)?
+            } else if args.len() > 1 {
+                for serial in &args[1..] {
+                    client.set_power(serial, Power::Sleep).map_err(daemon)?;
+                }
+                args[1..].to_vec()
+            } else {
+                return Err("Give the controllers to power off, or --idle".to_string());
+            };
+            if json {
+                print_json(&json!({ "serials": serials }));
+            } else {
+                for serial in serials {
+                    println!("{:<14} powered off", serial);
+                }
+            }
+        }
+        "pair" => {
+            client
+                .pair(arg(1, "LEFT")?, arg(2, "RIGHT")?)
+                .map_err(daemon)?;
+            if json {
+                print_json(&json!({ "ok": true }));
+            }
+        }
+        "unpair" => {
+            client.unpair(arg(1, "SERIAL")?).map_err(daemon)?;
+            if json {
+                print_json(&json!({ "ok": true }));
+            }
+        }
+        "set" => {
+            let serial = arg(1, "SERIAL")?;
+            if args.len() < 3 {
+                return Err("Give at least one KEY=VALUE to set".to_string());
+            }
+            let settings = args[2..]
+                .iter()
+                .map(|arg| parse_setting(arg))
+                .collect::<Result<Vec<Setting>, String>>()?;
+            client.set_settings(serial, settings).map_err(daemon)?;
+            if json {
+                print_json(&json!({ "ok": true }));
+            }
+        }
+        "battery" => {
+            let named = args.len() > 1;
+            let serials = if named {
+                args[1..].to_vec()
+            } else {
+                let controllers = client.list().map_err(daemon)?;
+                controllers.into_iter().map(|c| c.serial).collect()
+            };
+            let mut batteries = serde_json::Map::new();
+            for serial in serials {
+                // A controller that's still connecting doesn't hide the others
+                let battery = match client.battery(&serial) {
+                    Ok(battery) => battery,
+                    Err(e) if !named => {
+                        if json {
+                            batteries.insert(serial, json!({ "error": e.to_string() }));
+                        } else {
+                            eprintln!("{:<14} {}", serial, e);
+                        }
+                        continue;
+                    }
+                    Err(e) => return Err(daemon(e)),
+                };
+                if json {
+                    batteries.insert(serial, json!(battery));
+                } else if matches.opt_present("uevent") {
+                    print_uevent(&serial, &battery);
+                } else {
+                    print_battery(&serial, &battery);
+                }
+            }
+            if json {
+                print_json(&serde_json::Value::Object(batteries));
+            }
+        }
+        "calibrate" => {
+            client.calibrate(arg(1, "SERIAL")?).map_err(daemon)?;
+            if json {
+                print_json(&json!({ "ok": true }));
+            }
+        }
+        "dump-flash" => {
+            let serial = arg(1, "SERIAL")?;
+            let path = arg(2, "FILE")?;
+            let offset = parse_address(matches, "offset", 0)?;
+            let length = parse_address(
+                matches,
+                "length",
+                protocol::FLASH_SIZE.saturating_sub(offset),
+            )?;
+            let flash = client.read_flash(serial, offset, length).map_err(daemon)?;
+
+            let written = if path == "-" {
+                io::stdout().write_all(&flash)
+            } else {
+                File::create(path).and_then(|mut file| file.write_all(&flash))
+            };
+            written.map_err(|e| format!("Couldn't write {}: {}", path, e))?;
+            if json && path != "-" {
+                print_json(&json!({ "offset": offset, "length": flash.len(), "file": path }));
+            }
+        }
+        "watch" => {
+            let filter = Filter {
+                serials: args[1..].to_vec(),
+                buttons: matches.opt_present("buttons"),
+                battery: matches.opt_present("battery"),
+                sample_rate: parse_opt(matches, "rate", 0)?,
+                ..Filter::default()
+            };
+            for event in client.subscribe(filter).map_err(daemon)? {
+                let event = event.map_err(daemon)?;
+                if json {
+                    print_json(&json!(event));
+                } else {
+                    print_event(&event);
+                }
+            }
+        }
+        command => return Err(format!("Unknown command \"{}\"", command)),
+    }
+    Ok(())
+}
+
+fn fail(message: &str, json: bool) -> ! {
+    if json {
+        print_json(&json!({ "error": message }));
+    } else {
+        eprintln!("{}", message);
+    }
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let opts = options();
+    let usage = opts.usage(&format!("Usage: {} [options] COMMAND [ARGS]", args[0])) + COMMANDS;
+    let matches = match opts.parse(&args[1..]) {
+        Ok(matches) => matches,
+        Err(e) => {
+            eprint!("{}\n{}", e, usage);
+            process::exit(2);
+        }
+    };
+    if matches.opt_present("help") || matches.free.is_empty() {
+        print!("{}", usage);
+        return;
+    }
+
+    let json = matches.opt_present("json");
+    let socket = match matches.opt_str("socket") {
+        Some(socket) => socket,
+        // Wherever joycond itself was told to listen
+        None => match config::daemon_socket() {
+            Ok(socket) => socket.display().to_string(),
+            Err(e) => fail(&format!("Invalid configuration: {}", e), json),
+        },
+    };
+    let client = match Client::connect_to(&socket) {
+        Ok(client) => client,
+        Err(e) => fail(&format!("Couldn't connect to {}: {}", socket, e), json),
+    };
+
+    if let Err(e) = run(&matches, client, json) {
+        fail(&e, json);
+    }
+}
@@ -0,0 +1,238 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use toml;
+
+use common::config;
+use common::ipc::paths;
+use common::leds;
+use common::log::Level;
+
+use joycon_driver::device::InputMode;
+use joycon_driver::player_leds::LedState;
+
+/// Everything about the daemon that can be configured. Every key is
+/// optional, and falls back to the defaults below.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub daemon: DaemonConfig,
+    pub pairing: PairingConfig,
+    pub controllers: ControllerConfig,
+    pub mapping: MappingConfig,
+    pub leds: LedConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Where clients connect. Only read at startup.
+    pub socket: PathBuf,
+    pub log_level: Level,
+    /// Whether players' virtual devices are left to a `joycon-proxy` per
+    /// player slot, with the slot as its ID, so the daemon itself needs no
+    /// access to `/dev/uinput`. Only read at startup.
+    pub proxies: bool,
+    /// Where the proxies' sockets are. Only read at startup.
+    pub proxy_dir: PathBuf,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> DaemonConfig {
+        DaemonConfig {
+            socket: PathBuf::from(paths::DAEMON_PATH),
+            log_level: Level::Info,
+            proxies: false,
+            proxy_dir: PathBuf::from(paths::PROXY_ROOT_PATH),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoPair {
+    /// Only pair Joy-Con when asked to
+    Off,
+    /// Pair Joy-Con with the partner their settings remember
+    Remembered,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PairingConfig {
+    pub auto: AutoPair,
+    /// Whether Joy-Con wait for L and R to be pressed on two of them to pair
+    /// them, or SL and SR on one to use it alone, instead of being used alone
+    /// right away
+    pub ritual: bool,
+}
+
+impl Default for PairingConfig {
+    fn default() -> PairingConfig {
+        PairingConfig {
+            auto: AutoPair::Remembered,
+            ritual: true,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputModeName {
+    /// Buttons, sticks and motion
+    Full,
+    /// As above, plus NFC and IR camera data
+    NfcIr,
+}
+
+impl From<InputModeName> for InputMode {
+    fn from(mode: InputModeName) -> InputMode {
+        match mode {
+            InputModeName::Full => InputMode::Full,
+            InputModeName::NfcIr => InputMode::NfcIr,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControllerConfig {
+    /// Report mode controllers are put in once connected
+    pub input_mode: InputModeName,
+    /// Whether controllers report motion
+    pub imu: bool,
+    /// Minutes without input after which a controller is put to sleep, or 0
+    /// to keep it connected
+    pub idle_timeout: u64,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> ControllerConfig {
+        ControllerConfig {
+            input_mode: InputModeName::Full,
+            imu: true,
+            idle_timeout: 0,
+        }
+    }
+}
+
+impl ControllerConfig {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.idle_timeout {
+            0 => None,
+            minutes => Some(Duration::from_secs(minutes * 60)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MappingConfig {
+    /// Profiles offered to every controller, on top of the standard gamepad.
+    /// Each one's `select` section decides which controllers it applies to.
+    pub profiles: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LedConfig {
+    /// Shown while a controller has no player slot: "searching" for a light
+    /// running back and forth, or 4 characters of 0, 1 or f for flashing,
+    /// player LED 1 first
+    pub pending: String,
+    /// Whether a controller's player slot is shown once it has one
+    pub show_slot: bool,
+    /// Whether the Home light blinks a controller's player number when it
+    /// gets a slot, and blinks again when its battery runs low
+    pub home_notifications: bool,
+}
+
+impl Default for LedConfig {
+    fn default() -> LedConfig {
+        LedConfig {
+            pending: SEARCHING_LEDS.to_string(),
+            show_slot: true,
+            home_notifications: true,
+        }
+    }
+}
+
+impl LedConfig {
+    pub fn pending(&self) -> LedState {
+        parse_leds(&self.pending).unwrap_or(LedState::Searching)
+    }
+}
+
+// Config's name for `LedState::Searching`
+const SEARCHING_LEDS: &str = "searching";
+
+fn parse_leds(leds: &str) -> Result<LedState, String> {
+    if leds == SEARCHING_LEDS {
+        Ok(LedState::Searching)
+    } else {
+        leds::parse(leds).map(LedState::Raw)
+    }
+}
+
+impl Config {
+    /// Reads the system-wide file, then the user's, either of which may be
+    /// missing
+    pub fn load() -> Result<Config, String> {
+        let mut merged = toml::value::Table::new();
+        for path in &config::config_files() {
+            if let Some(table) = read_table(path)? {
+                merge(&mut merged, table);
+            }
+        }
+        let config: Config = toml::Value::Table(merged)
+            .try_into()
+            .map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Err(e) = parse_leds(&self.leds.pending) {
+            return Err(format!("leds.pending: {}", e));
+        }
+        for (i, path) in self.mapping.profiles.iter().enumerate() {
+            if !path.is_file() {
+                return Err(format!(
+                    "mapping.profiles[{}]: {} doesn't exist",
+                    i,
+                    path.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reads a config file into a table, checking it against `Config` on its own
+// first, while errors can still point at the line they're on
+fn read_table(path: &Path) -> Result<Option<toml::value::Table>, String> {
+    let mut text = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+        Ok(_) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    }
+    toml::from_str::<Config>(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    toml::from_str(&text)
+        .map(Some)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Overlays `from` on `into`, replacing values but merging tables
+fn merge(into: &mut toml::value::Table, from: toml::value::Table) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(toml::Value::Table(into)), toml::Value::Table(from)) => merge(into, from),
+            (_, value) => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
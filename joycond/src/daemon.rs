@@ -1,18 +1,19 @@
-use std::io::{Error, Write};
+use std::io::{Error, ErrorKind};
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
 use std::thread;
 
-use common::ipc::paths;
+use common::ipc::protocol::{self, Request, Response};
 use common::log;
 use common::types::Never;
 
-//TODO This should really be Result<!, Error>, watch `never_type` feature progress
-pub fn listen() -> Result<Never, Error> {
-    let listener = match UnixListener::bind(paths::DAEMON_PATH) {
-        Ok(l) => l,
-        Err(e) => panic!("Couldn't open socket: {:?}", e),
-    };
+use super::events::EventQueue;
+use super::supervisor::Message;
 
+//TODO This should really be Result<!, Error>, watch `never_type` feature progress
+/// Accepts clients on `listener`, passing their requests on to the supervisor
+pub fn listen(listener: UnixListener, supervisor: Sender<Message>) -> Result<Never, Error> {
     for stream in listener.incoming() {
         let stream = match stream {
             Ok(s) => s,
@@ -22,8 +23,13 @@ pub fn listen() -> Result<Never, Error> {
             }
         };
 
+        let supervisor = supervisor.clone();
         thread::spawn(move || {
-            handle_connection(stream);
+            if let Err(e) = handle_connection(stream, &supervisor) {
+                if e.kind() != ErrorKind::UnexpectedEof {
+                    log::e(&format!("Connection failed: {:?}", e));
+                }
+            }
         });
     }
 
@@ -31,6 +37,83 @@ pub fn listen() -> Result<Never, Error> {
     Err(Error::last_os_error())
 }
 
-fn handle_connection(mut stream: UnixStream) {
-    stream.write(String::from("bytes").as_bytes());
+fn handle_connection(mut stream: UnixStream, supervisor: &Sender<Message>) -> Result<(), Error> {
+    match protocol::read_message(&mut stream)? {
+        Request::Hello { version } if version == protocol::VERSION => {
+            protocol::write_message(&mut stream, &Response::Hello { version })?
+        }
+        Request::Hello { version } => {
+            return protocol::write_message(
+                &mut stream,
+                &Response::error(
+                    protocol::ErrorKind::UnsupportedVersion,
+                    &format!(
+                        "Protocol version {} isn't supported, expected {}",
+                        version,
+                        protocol::VERSION
+                    ),
+                ),
+            );
+        }
+        _ => {
+            return protocol::write_message(
+                &mut stream,
+                &Response::error(protocol::ErrorKind::InvalidRequest, "Expected hello"),
+            );
+        }
+    }
+
+    let filter = loop {
+        let response = match protocol::read_message(&mut stream) {
+            Ok(Request::Subscribe { filter }) => break filter,
+            Ok(request) => {
+                let (tx, rx) = mpsc::channel();
+                if supervisor.send(Message::Request(request, tx)).is_err() {
+                    return Err(Error::new(ErrorKind::BrokenPipe, "Supervisor stopped"));
+                }
+                match rx.recv() {
+                    Ok(response) => response,
+                    Err(_) => return Err(Error::new(ErrorKind::BrokenPipe, "Supervisor stopped")),
+                }
+            }
+            // The stream can't be trusted to be at a message boundary anymore
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                return protocol::write_message(
+                    &mut stream,
+                    &Response::error(
+                        protocol::ErrorKind::InvalidRequest,
+                        &format!("Malformed request: {}", e),
+                    ),
+                );
+            }
+            Err(e) => return Err(e),
+        };
+        protocol::write_message(&mut stream, &response)?;
+    };
+
+    let queue = Arc::new(EventQueue::new());
+    if supervisor
+        .send(Message::Subscribe(filter, queue.clone()))
+        .is_err()
+    {
+        return Err(Error::new(ErrorKind::BrokenPipe, "Supervisor stopped"));
+    }
+    let streamed = protocol::write_message(&mut stream, &Response::Ok).and_then(|_| {
+        while let Some(event) = queue.pop() {
+            protocol::write_message(&mut stream, &Response::Event { event })?;
+        }
+        if queue.overflowed() {
+            protocol::write_message(
+                &mut stream,
+                &Response::error(
+                    protocol::ErrorKind::Overflowed,
+                    "Too many events went unread",
+                ),
+            )?;
+        }
+        Ok(())
+    });
+    // Tells the supervisor to stop queueing events for this connection
+    queue.close();
+    streamed
 }
@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use common::ipc::protocol::{Event, Filter};
+
+// Samples a subscriber can fall behind by before the oldest are dropped
+const MAX_QUEUED_SAMPLES: usize = 64;
+
+// Events of any kind a subscriber can fall behind by. Once these are all
+// events other than samples, the subscription is closed.
+const MAX_QUEUED_EVENTS: usize = 256;
+
+/// Events waiting to be written to one subscriber's connection. The
+/// supervisor pushes to it without ever blocking, however slow the client.
+/// Old samples make way for new ones, but other events are never dropped:
+/// a subscriber that falls too far behind on them is cut off instead.
+pub struct EventQueue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+struct QueueState {
+    events: VecDeque<Event>,
+    samples: usize,
+    closed: bool,
+    overflowed: bool,
+}
+
+impl QueueState {
+    fn drop_oldest_sample(&mut self) -> bool {
+        match self.events.iter().position(Event::is_sample) {
+            Some(i) => {
+                self.events.remove(i);
+                self.samples -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl EventQueue {
+    pub fn new() -> EventQueue {
+        EventQueue {
+            state: Mutex::new(QueueState {
+                events: VecDeque::new(),
+                samples: 0,
+                closed: false,
+                overflowed: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Queue an event, dropping the oldest sample if there are too many. If
+    /// there's no sample left to drop, the queue is closed as overflowed.
+    pub fn push(&self, event: Event) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+        let full = state.events.len() == MAX_QUEUED_EVENTS
+            || event.is_sample() && state.samples == MAX_QUEUED_SAMPLES;
+        if full && !state.drop_oldest_sample() {
+            state.events.clear();
+            state.samples = 0;
+            state.closed = true;
+            state.overflowed = true;
+            self.ready.notify_all();
+            return;
+        }
+        if event.is_sample() {
+            state.samples += 1;
+        }
+        state.events.push_back(event);
+        self.ready.notify_one();
+    }
+
+    /// Wait for the next event, or `None` once the queue is closed
+    pub fn pop(&self) -> Option<Event> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(event) = state.events.pop_front() {
+                if event.is_sample() {
+                    state.samples -= 1;
+                }
+                return Some(event);
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Stop delivering events, e.g. because the client went away
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Whether the queue was closed because the subscriber fell too far behind
+    pub fn overflowed(&self) -> bool {
+        self.state.lock().unwrap().overflowed
+    }
+}
+
+/// A client's subscription, as the supervisor sees it
+pub struct Subscriber {
+    filter: Filter,
+    queue: Arc<EventQueue>,
+    // When each controller's last sample went out
+    sampled: BTreeMap<String, Instant>,
+}
+
+impl Subscriber {
+    pub fn new(filter: Filter, queue: Arc<EventQueue>) -> Subscriber {
+        Subscriber {
+            filter,
+            queue,
+            sampled: BTreeMap::new(),
+        }
+    }
+
+    pub fn wants_samples(&self) -> bool {
+        self.filter.sample_rate > 0
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.queue.is_closed()
+    }
+
+    /// Queue an event if it passes the filter, holding samples back to the
+    /// requested rate
+    pub fn send(&mut self, event: &Event) {
+        if !self.filter.matches(event) {
+            return;
+        }
+        if event.is_sample() {
+            let interval = Duration::from_secs(1) / self.filter.sample_rate;
+            let now = Instant::now();
+            match self.sampled.get(event.serial()) {
+                Some(&last) if now.duration_since(last) < interval => return,
+                _ => {
+                    self.sampled.insert(event.serial().to_string(), now);
+                }
+            }
+        }
+        self.queue.push(event.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(n: u16) -> Event {
+        Event::Sample {
+            serial: "XAW1".to_string(),
+            sticks: [n, 0, 0, 0],
+            accelerometer: [0; 3],
+            gyroscope: [0; 3],
+        }
+    }
+
+    fn disconnected(n: usize) -> Event {
+        Event::Disconnected {
+            serial: n.to_string(),
+        }
+    }
+
+    fn drain(queue: &EventQueue) -> Vec<Event> {
+        // `pop` blocks on an empty queue, so stop before it would
+        let mut events = Vec::new();
+        while !queue.state.lock().unwrap().events.is_empty() {
+            events.push(queue.pop().unwrap());
+        }
+        events
+    }
+
+    #[test]
+    fn drops_the_oldest_sample() {
+        let queue = EventQueue::new();
+        queue.push(disconnected(0));
+        for n in 0..=MAX_QUEUED_SAMPLES as u16 {
+            queue.push(sample(n));
+        }
+        let events = drain(&queue);
+        assert_eq!(events.len(), MAX_QUEUED_SAMPLES + 1);
+        assert_eq!(events[0], disconnected(0));
+        assert_eq!(events[1], sample(1));
+        assert_eq!(
+            events[MAX_QUEUED_SAMPLES],
+            sample(MAX_QUEUED_SAMPLES as u16)
+        );
+        assert!(!queue.is_closed());
+    }
+
+    #[test]
+    fn samples_make_way_for_other_events() {
+        let queue = EventQueue::new();
+        queue.push(sample(0));
+        for n in 0..MAX_QUEUED_EVENTS {
+            queue.push(disconnected(n));
+        }
+        assert!(!queue.is_closed());
+        let events = drain(&queue);
+        assert_eq!(events.len(), MAX_QUEUED_EVENTS);
+        assert_eq!(events[0], disconnected(0));
+    }
+
+    #[test]
+    fn overflow_closes_the_queue() {
+        let queue = EventQueue::new();
+        for n in 0..MAX_QUEUED_EVENTS {
+            queue.push(disconnected(n));
+        }
+        assert!(!queue.overflowed());
+        queue.push(disconnected(MAX_QUEUED_EVENTS));
+        assert!(queue.is_closed());
+        assert!(queue.overflowed());
+        assert_eq!(queue.pop(), None);
+        // Nothing more is kept for it
+        queue.push(disconnected(0));
+        assert!(queue.state.lock().unwrap().events.is_empty());
+    }
+
+    #[test]
+    fn samples_overflow_a_queue_full_of_events() {
+        let queue = EventQueue::new();
+        for n in 0..MAX_QUEUED_EVENTS {
+            queue.push(disconnected(n));
+        }
+        queue.push(sample(0));
+        assert!(queue.overflowed());
+    }
+
+    #[test]
+    fn closing_isnt_overflowing() {
+        let queue = EventQueue::new();
+        queue.push(disconnected(0));
+        queue.close();
+        assert_eq!(queue.pop(), None);
+        assert!(!queue.overflowed());
+    }
+}
@@ -0,0 +1,302 @@
+use std::ffi::CString;
+use std::io::{Error, Result};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::str;
+use std::sync::mpsc::Sender;
+
+use libc;
+
+use super::supervisor::Message;
+
+// Multicast group the kernel itself sends uevents to, as opposed to udev's
+const KERNEL_GROUP: u32 = 1;
+
+// Uevents are limited to a page or so by the kernel
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Add,
+    Remove,
+}
+
+/// A `hidraw` device appearing or disappearing
+#[derive(Clone, Debug)]
+pub struct Uevent {
+    pub action: Action,
+    /// Path of the device node, matching `HidDeviceInfo::path`
+    pub path: CString,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl Uevent {
+    /// Parses a kernel uevent, which is a `action@devpath` header followed by
+    /// `KEY=value` pairs, all separated by NULs. Only `hidraw` add and remove
+    /// events are recognized.
+    pub fn parse(buf: &[u8]) -> Option<Uevent> {
+        let mut fields = buf
+            .split(|&b| b == 0)
+            .filter_map(|f| str::from_utf8(f).ok());
+        // Skip the header, the same information is repeated below
+        fields.next()?;
+
+        let (mut action, mut subsystem, mut devpath, mut devname) = (None, None, None, None);
+        for field in fields {
+            let mut parts = field.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("ACTION"), Some(value)) => action = Some(value),
+                (Some("SUBSYSTEM"), Some(value)) => subsystem = Some(value),
+                (Some("DEVPATH"), Some(value)) => devpath = Some(value),
+                (Some("DEVNAME"), Some(value)) => devname = Some(value),
+                _ => (),
+            }
+        }
+
+        if subsystem? != "hidraw" {
+            return None;
+        }
+        let action = match action? {
+            "add" => Action::Add,
+            "remove" => Action::Remove,
+            _ => return None,
+        };
+        let (vendor_id, product_id) = devpath?.split('/').find_map(parse_hid_id)?;
+        let devname = devname?;
+        let path = if devname.starts_with('/') {
+            devname.to_string()
+        } else {
+            format!("/dev/{}", devname)
+        };
+
+        Some(Uevent {
+            action,
+            path: CString::new(path).ok()?,
+            vendor_id,
+            product_id,
+        })
+    }
+}
+
+/// Reads vendor and product IDs from the name HID devices get in sysfs,
+/// `BUS:VENDOR:PRODUCT.INSTANCE` in hex, e.g. `0005:057E:2006.0009`
+fn parse_hid_id(component: &str) -> Option<(u16, u16)> {
+    let mut parts = component.split(':');
+    let (bus, vendor, product) = (parts.next()?, parts.next()?, parts.next()?);
+    let product = product.split('.').next()?;
+    if parts.next().is_some() || bus.len() != 4 || vendor.len() != 4 || product.len() != 4 {
+        return None;
+    }
+    Some((
+        u16::from_str_radix(vendor, 16).ok()?,
+        u16::from_str_radix(product, 16).ok()?,
+    ))
+}
+
+/// Where raw uevents come from. Anything can stand in for the kernel, e.g.
+/// to replay recorded events.
+pub trait UeventSource {
+    /// Blocks until the next uevent arrives
+    fn recv(&mut self) -> Result<Vec<u8>>;
+}
+
+/// Receives uevents straight from the kernel over netlink
+pub struct NetlinkSource {
+    fd: RawFd,
+}
+
+impl NetlinkSource {
+    pub fn open() -> Result<NetlinkSource> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let source = NetlinkSource { fd };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = KERNEL_GROUP;
+        let bound = unsafe {
+            libc::bind(
+                source.fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(source)
+    }
+}
+
+impl UeventSource for NetlinkSource {
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        let mut buf = vec![0; UEVENT_BUFFER_SIZE];
+        let len =
+            unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if len < 0 {
+            return Err(Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+        Ok(buf)
+    }
+}
+
+impl Drop for NetlinkSource {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Passes `hidraw` uevents from `source` to the supervisor until either goes
+/// away. If the source fails, the supervisor is told with
+/// `Message::HotplugStopped`, so it can look for controllers another way.
+pub fn monitor<S: UeventSource>(mut source: S, supervisor: Sender<Message>) -> Result<()> {
+    loop {
+        let buf = match source.recv() {
+            Ok(buf) => buf,
+            Err(e) => {
+                let _ = supervisor.send(Message::HotplugStopped);
+                return Err(e);
+            }
+        };
+        if let Some(uevent) = Uevent::parse(&buf) {
+            if supervisor.send(Message::Hotplug(uevent)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::ErrorKind;
+    use std::sync::mpsc;
+
+    // Recorded from a Joy-Con (R) connecting over Bluetooth
+    const JOYCON_ADD: &[u8] =
+        b"add@/devices/virtual/misc/uhid/0005:057E:2007.0009/hidraw/hidraw3\0\
+ACTION=add\0DEVPATH=/devices/virtual/misc/uhid/0005:057E:2007.0009/hidraw/hidraw3\0\
+SUBSYSTEM=hidraw\0MAJOR=241\0MINOR=3\0DEVNAME=hidraw3\0SEQNUM=5120\0";
+
+    // A Pro Controller unplugged from USB
+    const PRO_REMOVE: &[u8] = b"remove@/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0/\
+0003:057E:2009.000A/hidraw/hidraw4\0ACTION=remove\0DEVPATH=/devices/pci0000:00/0000:00:14.0/\
+usb1/1-2/1-2:1.0/0003:057E:2009.000A/hidraw/hidraw4\0SUBSYSTEM=hidraw\0MAJOR=241\0MINOR=4\0\
+DEVNAME=hidraw4\0SEQNUM=5121\0";
+
+    // A Logitech receiver, which isn't ours to drive
+    const MOUSE_ADD: &[u8] = b"add@/devices/pci0000:00/0000:00:14.0/usb1/1-3/1-3:1.1/\
+0003:046D:C52B.0003/hidraw/hidraw1\0ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:14.0/\
+usb1/1-3/1-3:1.1/0003:046D:C52B.0003/hidraw/hidraw1\0SUBSYSTEM=hidraw\0MAJOR=241\0MINOR=1\0\
+DEVNAME=hidraw1\0SEQNUM=812\0";
+
+    // The same Joy-Con's input device, which isn't a hidraw node
+    const INPUT_ADD: &[u8] = b"add@/devices/virtual/misc/uhid/0005:057E:2007.0009/input/input20\0\
+ACTION=add\0DEVPATH=/devices/virtual/misc/uhid/0005:057E:2007.0009/input/input20\0\
+SUBSYSTEM=input\0PRODUCT=5/57e/2007/8001\0SEQNUM=5119\0";
+
+    const JOYCON_BIND: &[u8] = b"bind@/devices/virtual/misc/uhid/0005:057E:2007.0009\0\
+ACTION=bind\0DEVPATH=/devices/virtual/misc/uhid/0005:057E:2007.0009\0SUBSYSTEM=hid\0\
+DRIVER=nintendo\0SEQNUM=5122\0";
+
+    // Plays back recorded uevents, then fails like a closed socket
+    struct Replay(VecDeque<&'static [u8]>);
+
+    impl UeventSource for Replay {
+        fn recv(&mut self) -> Result<Vec<u8>> {
+            match self.0.pop_front() {
+                Some(buf) => Ok(buf.to_vec()),
+                None => Err(Error::new(ErrorKind::ConnectionReset, "Replay is over")),
+            }
+        }
+    }
+
+    fn path(uevent: &Uevent) -> &str {
+        uevent.path.to_str().unwrap()
+    }
+
+    #[test]
+    fn parses_bluetooth_add() {
+        let uevent = Uevent::parse(JOYCON_ADD).unwrap();
+        assert_eq!(uevent.action, Action::Add);
+        assert_eq!(path(&uevent), "/dev/hidraw3");
+        assert_eq!((uevent.vendor_id, uevent.product_id), (0x057e, 0x2007));
+    }
+
+    #[test]
+    fn parses_usb_remove() {
+        let uevent = Uevent::parse(PRO_REMOVE).unwrap();
+        assert_eq!(uevent.action, Action::Remove);
+        assert_eq!(path(&uevent), "/dev/hidraw4");
+        assert_eq!((uevent.vendor_id, uevent.product_id), (0x057e, 0x2009));
+    }
+
+    #[test]
+    fn parses_other_vendors() {
+        // Filtering by vendor is up to the supervisor
+        let uevent = Uevent::parse(MOUSE_ADD).unwrap();
+        assert_eq!((uevent.vendor_id, uevent.product_id), (0x046d, 0xc52b));
+    }
+
+    #[test]
+    fn ignores_everything_else() {
+        assert!(Uevent::parse(INPUT_ADD).is_none());
+        assert!(Uevent::parse(JOYCON_BIND).is_none());
+        assert!(Uevent::parse(b"").is_none());
+        assert!(Uevent::parse(b"libudev\0\xfe\xed\xca\xfe").is_none());
+    }
+
+    #[test]
+    fn parses_hid_ids() {
+        assert_eq!(parse_hid_id("0005:057E:2006.0009"), Some((0x057e, 0x2006)));
+        assert_eq!(parse_hid_id("hidraw3"), None);
+        assert_eq!(parse_hid_id("0000:00:14.0"), None);
+        assert_eq!(parse_hid_id("0005:057E:2006:0009.0001"), None);
+    }
+
+    #[test]
+    fn monitor_forwards_hidraw_events() {
+        let (tx, rx) = mpsc::channel();
+        let source = Replay(vec![INPUT_ADD, JOYCON_ADD, MOUSE_ADD, JOYCON_BIND, PRO_REMOVE].into());
+        assert!(monitor(source, tx).is_err());
+
+        let forwarded: Vec<Message> = rx.try_iter().collect();
+        assert_eq!(forwarded.len(), 4);
+        match forwarded[0] {
+            Message::Hotplug(ref uevent) => assert_eq!(path(uevent), "/dev/hidraw3"),
+            _ => panic!("Expected the Joy-Con to be added"),
+        }
+        match forwarded[1] {
+            Message::Hotplug(ref uevent) => assert_eq!(path(uevent), "/dev/hidraw1"),
+            _ => panic!("Expected the mouse to be added"),
+        }
+        match forwarded[2] {
+            Message::Hotplug(ref uevent) => assert_eq!(uevent.action, Action::Remove),
+            _ => panic!("Expected the Pro Controller to be removed"),
+        }
+        match forwarded[3] {
+            Message::HotplugStopped => (),
+            _ => panic!("Expected the supervisor to be told hotplug stopped"),
+        }
+    }
+
+    #[test]
+    fn monitor_stops_when_supervisor_does() {
+        let (tx, rx) = mpsc::channel();
+        drop(rx);
+        assert!(monitor(Replay(vec![JOYCON_ADD].into()), tx).is_ok());
+    }
+}
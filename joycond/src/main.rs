@@ -1,10 +1,130 @@
+extern crate hidapi;
+extern crate libc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate signal_hook;
+extern crate toml;
+
 extern crate common;
+extern crate joycon_driver;
 
+mod config;
 mod daemon;
+mod events;
+mod hotplug;
+mod session;
+mod settings;
+mod slots;
+mod supervisor;
+mod systemd;
+mod worker;
+
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+use common::ipc::socket;
+use common::log;
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGINT, SIGTERM};
+
+use config::Config;
+use hotplug::NetlinkSource;
+use supervisor::Supervisor;
 
 fn main() {
-    match daemon::listen() {
-        Err(e) => panic!(e),
-        _ => (),
+    // This clears systemd's variables, which has to happen before any other
+    // thread could be reading the environment
+    let activated = systemd::listener();
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            log::wtf(&format!("Invalid configuration: {}", e));
+            process::exit(1);
+        }
+    };
+    log::set_level(config.daemon.log_level);
+
+    // The supervisor reloads the configuration when it sees this set
+    let reload = Arc::new(AtomicBool::new(false));
+    if let Err(e) = signal_hook::flag::register(signal_hook::SIGHUP, reload.clone()) {
+        log::e(&format!(
+            "Can't reload the configuration on SIGHUP: {:?}",
+            e
+        ));
+    }
+
+    let (tx, rx) = mpsc::channel();
+
+    let polling = match NetlinkSource::open() {
+        Ok(source) => {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                if let Err(e) = hotplug::monitor(source, tx) {
+                    log::e(&format!(
+                        "Stopped watching for hotplug events, polling instead: {:?}",
+                        e
+                    ));
+                }
+            });
+            false
+        }
+        Err(e) => {
+            log::e(&format!(
+                "Couldn't watch for hotplug events, polling instead: {:?}",
+                e
+            ));
+            true
+        }
+    };
+
+    let signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(signals) => signals,
+        Err(e) => panic!("{}", e),
+    };
+
+    // Under socket activation, systemd has already bound the socket, and
+    // cleans it up too
+    let mut bound = false;
+    let listener = activated.and_then(|listener| match listener {
+        Some(listener) => Ok(listener),
+        None => {
+            bound = true;
+            socket::bind(&config.daemon.socket)
+        }
+    });
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::wtf(&format!(
+                "Couldn't open {}: {}",
+                config.daemon.socket.display(),
+                e
+            ));
+            process::exit(1);
+        }
+    };
+    thread::spawn(move || {
+        let Err(e) = daemon::listen(listener, tx);
+        log::wtf(&format!("Stopped listening: {:?}", e));
+        process::exit(1);
+    });
+
+    // HID devices can't be shared between threads, so the supervisor keeps the main one
+    let socket_path = config.daemon.socket.clone();
+    let mut supervisor = match Supervisor::new(rx, polling, config, reload, signals) {
+        Ok(supervisor) => supervisor,
+        Err(e) => {
+            log::wtf("Couldn't initialize HidApi");
+            panic!("{:?}", e);
+        }
+    };
+    supervisor.run();
+    if bound {
+        socket::unlink(&socket_path);
     }
 }
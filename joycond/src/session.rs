@@ -0,0 +1,441 @@
+use std::cmp;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use common::ipc::paths;
+use common::ipc::protocol::Battery;
+use common::ipc::proxy::ProxyCommand;
+use common::log;
+
+use joycon_driver::button::Button;
+use joycon_driver::evdev::VirtualDevice;
+use joycon_driver::frame::{AxisFrame, InputFrame};
+use joycon_driver::id::Product;
+use joycon_driver::mapping::{self, Context, Mapper, Profile};
+use joycon_driver::player_leds::LedState;
+use joycon_driver::proxy::ProxyLink;
+
+use super::settings::ControllerSettings;
+
+// How long a session outlives its controller, waiting for it to come back
+const RECONNECT_GRACE: Duration = Duration::from_secs(60);
+
+// Connecting to a proxy that isn't up yet, e.g. while booting, is retried
+// after this delay, doubling each time...
+const PROXY_RETRY_DELAY: Duration = Duration::from_millis(500);
+// ...up to this one
+const MAX_PROXY_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Raw stick values are 12 bits wide, centered on this value
+const STICK_CENTER: f32 = 2048.0;
+
+// How far a stick has to move to count as the controller being used
+const ACTIVITY_THRESHOLD: f32 = 0.25;
+
+/// Everything about a controller that should survive it dropping out for a
+/// moment: its virtual device, which games hold on to, and what it was told
+/// to show. Sessions are keyed by serial, so a controller reappearing under a
+/// different device path picks up where it left off.
+///
+/// Controllers only get a virtual device once they've been given a player,
+/// and the right half of a pair never does, since its input goes through the
+/// left half's. The device is either made here, or left to the proxy for the
+/// player slot, when the daemon runs without access to `/dev/uinput`.
+pub struct Session {
+    serial: String,
+    // Of the virtual device
+    name: String,
+    mapper: Mapper,
+    // Where the proxies' sockets are, when devices are left to them
+    proxy_dir: Option<PathBuf>,
+    output: Option<Output>,
+    // When to try reaching the proxy again, after failing to
+    proxy_retry: Option<ProxyRetry>,
+    // The right half, while this is the left half of a pair
+    partner: Option<String>,
+    /// Bluetooth address the settings are kept under
+    pub mac_address: Option<String>,
+    pub settings: ControllerSettings,
+    /// Player LEDs last set on the controller, restored when it reconnects
+    pub leds: LedState,
+    /// Waiting to be paired or made standalone
+    pub pending: bool,
+    /// Battery last reported
+    pub battery: Option<Battery>,
+    lost_at: Option<Instant>,
+    last_used: Instant,
+    buttons: u32,
+    // Latest frame, for the other half of a pair to pick up
+    frame: InputFrame,
+    // When L or R was last pressed while pending
+    shoulder_at: Option<Instant>,
+}
+
+// Where input goes once the controller has a player
+enum Output {
+    Device(VirtualDevice),
+    // A proxy maps input itself, with its own profiles. It's found by the
+    // player slot.
+    Proxy(ProxyLink, u8),
+}
+
+struct ProxyRetry {
+    slot: u8,
+    at: Instant,
+    delay: Duration,
+}
+
+impl Session {
+    pub fn new(
+        serial: &str,
+        product: &str,
+        mac_address: Option<String>,
+        settings: ControllerSettings,
+        profiles: &[PathBuf],
+        leds: LedState,
+        proxy_dir: Option<PathBuf>,
+    ) -> Session {
+        // A profile from the controller's settings replaces everything else
+        let paths = match settings.profile {
+            Some(ref path) => vec![path.clone()],
+            None => profiles.to_vec(),
+        };
+        let mut profiles = Vec::with_capacity(paths.len() + 1);
+        for path in &paths {
+            match Profile::load(path) {
+                Ok(profile) => profiles.push(profile),
+                Err(e) => log::e(&format!("Skipping a profile for {}: {}", serial, e)),
+            }
+        }
+        if profiles.is_empty() || settings.profile.is_none() {
+            profiles
+                .push(Profile::parse(mapping::DEFAULT_PROFILE).expect("Default profile is valid"));
+        }
+        let name = format!(
+            "{} ({})",
+            settings.name.as_deref().unwrap_or(product),
+            serial
+        );
+        Session {
+            serial: serial.to_string(),
+            name,
+            mapper: Mapper::new(profiles),
+            proxy_dir,
+            output: None,
+            proxy_retry: None,
+            partner: None,
+            mac_address,
+            settings,
+            leds,
+            pending: true,
+            battery: None,
+            lost_at: None,
+            last_used: Instant::now(),
+            buttons: 0,
+            frame: InputFrame::new(),
+            shoulder_at: None,
+        }
+    }
+
+    /// Replace the virtual device with a new one, which moves it after every
+    /// other device, as far as games enumerating them are concerned. Proxies'
+    /// devices stay put, since they're made in player order to begin with.
+    pub fn reopen_device(&mut self) {
+        if let Some(Output::Device(_)) = self.output {
+            self.close_device();
+            self.open_device(None);
+        }
+    }
+
+    /// Lets go of the virtual device, or the proxy, which keeps its device
+    /// for the next controller to take the slot
+    pub fn close_device(&mut self) {
+        self.proxy_retry = None;
+        if let Some(Output::Device(mut device)) = self.output.take() {
+            if let Err(e) = self.mapper.release(&mut device) {
+                log::e(&format!("Couldn't release input: {:?}", e));
+            }
+        }
+    }
+
+    /// Start sending input to a virtual device, made here or by the proxy
+    /// for the player `slot`
+    pub fn open_device(&mut self, slot: Option<u8>) {
+        if self.output.is_some() {
+            return;
+        }
+        if let Some(ref dir) = self.proxy_dir {
+            let slot = match slot {
+                Some(slot) => slot,
+                None => {
+                    log::e(&format!(
+                        "{} has no player slot to find a proxy by",
+                        self.serial
+                    ));
+                    return;
+                }
+            };
+            let id = slot.to_string();
+            let connected = ProxyLink::connect(dir, &id, &self.serial).and_then(|mut link| {
+                if self.partner.is_some() {
+                    link.set_serials(&self.serials())?;
+                }
+                Ok(link)
+            });
+            match connected {
+                Ok(link) => {
+                    self.output = Some(Output::Proxy(link, slot));
+                    self.proxy_retry = None;
+                }
+                Err(e) => {
+                    log::e(&format!(
+                        "Couldn't connect to {}: {}",
+                        paths::proxy_path(dir, &id).display(),
+                        e
+                    ));
+                    self.retry_proxy_later(slot);
+                }
+            }
+            return;
+        }
+        let mut device = match VirtualDevice::new(&self.name, &self.mapper.events()) {
+            Ok(device) => device,
+            Err(e) => {
+                log::e(&format!("Couldn't create {}: {:?}", self.name, e));
+                return;
+            }
+        };
+        let serials = self.serials();
+        let context = Context {
+            serials: &serials,
+            application: None,
+        };
+        match self.mapper.select(&context, &mut device) {
+            Ok(()) => self.output = Some(Output::Device(device)),
+            Err(e) => log::e(&format!("Couldn't set up {}: {:?}", self.name, e)),
+        }
+    }
+
+    /// Try reaching the proxy again, if it couldn't be reached before and
+    /// it's time to
+    pub fn retry_proxy(&mut self) {
+        let slot = match self.proxy_retry {
+            Some(ref retry) if Instant::now() >= retry.at => retry.slot,
+            _ => return,
+        };
+        self.open_device(Some(slot));
+    }
+
+    fn retry_proxy_later(&mut self, slot: u8) {
+        let delay = match self.proxy_retry {
+            Some(ref retry) if retry.slot == slot => {
+                cmp::min(retry.delay * 2, MAX_PROXY_RETRY_DELAY)
+            }
+            _ => PROXY_RETRY_DELAY,
+        };
+        self.proxy_retry = Some(ProxyRetry {
+            slot,
+            at: Instant::now() + delay,
+            delay,
+        });
+    }
+
+    /// Pair this controller with the right half `partner`, or split it from
+    /// its partner, choosing the profile again for the controllers now behind
+    /// the device
+    pub fn set_partner(&mut self, partner: Option<String>) {
+        if self.partner == partner {
+            return;
+        }
+        self.partner = partner;
+        let serials = self.serials();
+        let sent = match self.output {
+            Some(Output::Device(ref mut device)) => {
+                let context = Context {
+                    serials: &serials,
+                    application: None,
+                };
+                if let Err(e) = self.mapper.select(&context, device) {
+                    log::e(&format!("Couldn't set up {}: {:?}", self.name, e));
+                }
+                return;
+            }
+            Some(Output::Proxy(ref mut link, _)) => link.set_serials(&serials),
+            None => return,
+        };
+        self.check_proxy(sent);
+    }
+
+    // Both halves of a pair, or just this controller
+    fn serials(&self) -> Vec<String> {
+        let mut serials = vec![self.serial.clone()];
+        serials.extend(self.partner.iter().cloned());
+        serials
+    }
+
+    /// Forward a frame from the controller to the virtual device, together
+    /// with the latest frame from the right half if this is the left half of
+    /// a pair
+    pub fn update(
+        &mut self,
+        frame: &InputFrame,
+        product: Option<Product>,
+        partner: Option<&InputFrame>,
+    ) {
+        let mut frame = *frame;
+        let [left, right] = self.settings.deadzones;
+        apply_deadzone(&mut frame.axes, left, right);
+        let axes = frame.axes;
+        let moved = |x: u16, y: u16| {
+            let dx = f32::from(x) - STICK_CENTER;
+            let dy = f32::from(y) - STICK_CENTER;
+            (dx * dx + dy * dy).sqrt() > ACTIVITY_THRESHOLD * STICK_CENTER
+        };
+        if frame.buttons.0 != self.buttons || moved(axes.lx, axes.ly) || moved(axes.rx, axes.ry) {
+            self.last_used = Instant::now();
+        }
+        let pressed = frame.buttons.0 & !self.buttons;
+        self.buttons = frame.buttons.0;
+        let shoulder = match product {
+            Some(Product::JoyConL) => u32::from(Button::L),
+            Some(Product::JoyConR) => u32::from(Button::R),
+            _ => 0,
+        };
+        if self.pending && pressed & shoulder != 0 {
+            self.shoulder_at = Some(Instant::now());
+        }
+        self.frame = frame;
+
+        let (frame, product) = match partner {
+            Some(partner) => (combine(&frame, partner), Some(Product::ProController)),
+            None => (frame, product),
+        };
+        self.forward(&frame, product);
+    }
+
+    fn forward(&mut self, frame: &InputFrame, product: Option<Product>) {
+        let sent = match self.output {
+            Some(Output::Device(ref mut device)) => {
+                if let Err(e) = self.mapper.update(frame, product, frame.time, device) {
+                    log::e(&format!("Couldn't forward input: {:?}", e));
+                }
+                return;
+            }
+            Some(Output::Proxy(ref mut link, _)) => link.send(frame, product),
+            None => return,
+        };
+        self.check_proxy(sent);
+    }
+
+    // Forgets a proxy that can't be reached anymore, until it's back
+    fn check_proxy<T>(&mut self, result: io::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::e(&format!("Lost the proxy for {}: {}", self.serial, e));
+                if let Some(Output::Proxy(_, slot)) = self.output.take() {
+                    self.retry_proxy_later(slot);
+                }
+                None
+            }
+        }
+    }
+
+    /// The next rumble or LEDs a proxy passed on, if any
+    pub fn next_command(&mut self) -> Option<ProxyCommand> {
+        let next = match self.output {
+            Some(Output::Proxy(ref link, _)) => link.next_command(),
+            _ => return None,
+        };
+        self.check_proxy(next).and_then(|command| command)
+    }
+
+    /// The controller went away. The virtual device stays, but lets go of
+    /// everything so nothing is held down while it's gone.
+    pub fn lose(&mut self) {
+        if self.lost_at.is_some() {
+            return;
+        }
+        self.lost_at = Some(Instant::now());
+        let released = match self.output {
+            Some(Output::Device(ref mut device)) => {
+                if let Err(e) = self.mapper.release(device) {
+                    log::e(&format!("Couldn't release input: {:?}", e));
+                }
+                return;
+            }
+            Some(Output::Proxy(ref mut link, _)) => link.release(),
+            None => return,
+        };
+        self.check_proxy(released);
+    }
+
+    pub fn frame(&self) -> &InputFrame {
+        &self.frame
+    }
+
+    /// Whether every button in `mask` is held
+    pub fn holds(&self, mask: u32) -> bool {
+        mask != 0 && self.buttons & mask == mask
+    }
+
+    /// When L or R, whichever this Joy-Con has, was last pressed while pending
+    pub fn shoulder_pressed_at(&self) -> Option<Instant> {
+        self.shoulder_at
+    }
+
+    /// Forget the last L or R press, e.g. once pairing with it has failed
+    pub fn forget_shoulder(&mut self) {
+        self.shoulder_at = None;
+    }
+
+    /// The controller came back, returning whether it had been lost
+    pub fn resume(&mut self) -> bool {
+        self.last_used = Instant::now();
+        self.lost_at.take().is_some()
+    }
+
+    /// How long it's been since a button was pressed or a stick moved
+    pub fn idle_for(&self) -> Duration {
+        self.last_used.elapsed()
+    }
+
+    /// Start counting idle time from now
+    pub fn touch(&mut self) {
+        self.last_used = Instant::now();
+    }
+
+    /// Whether the controller has been gone too long to expect it back
+    pub fn expired(&self) -> bool {
+        self.lost_at
+            .is_some_and(|lost_at| lost_at.elapsed() >= RECONNECT_GRACE)
+    }
+}
+
+/// Centers each stick while it's within its deadzone, a fraction of its travel
+fn apply_deadzone(axes: &mut AxisFrame, left: f32, right: f32) {
+    let within = |x: u16, y: u16, deadzone: f32| {
+        let dx = f32::from(x) - STICK_CENTER;
+        let dy = f32::from(y) - STICK_CENTER;
+        (dx * dx + dy * dy).sqrt() < deadzone * STICK_CENTER
+    };
+    if within(axes.lx, axes.ly, left) {
+        axes.lx = STICK_CENTER as u16;
+        axes.ly = STICK_CENTER as u16;
+    }
+    if within(axes.rx, axes.ry, right) {
+        axes.rx = STICK_CENTER as u16;
+        axes.ry = STICK_CENTER as u16;
+    }
+}
+
+/// Merges frames from the two halves of a pair into what a Pro Controller
+/// would send
+fn combine(left: &InputFrame, right: &InputFrame) -> InputFrame {
+    let mut frame = *left;
+    frame.buttons.0 |= right.buttons.0;
+    frame.axes.rx = right.axes.rx;
+    frame.axes.ry = right.axes.ry;
+    frame
+}
@@ -0,0 +1,290 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use toml;
+
+use common::config::config_home;
+use common::ipc::protocol::Setting;
+use common::log;
+
+use super::slots::MAX_SLOT;
+
+/// What's remembered about one controller, applied whenever it connects.
+/// Everything is optional, so files only need to mention what's changed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControllerSettings {
+    /// Shown instead of the product name, e.g. on the virtual device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// MAC address of the Joy-Con to pair this one with whenever both are connected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partner: Option<String>,
+    /// Player number from 1 to 8, used if it's free when the controller connects
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot: Option<u8>,
+    /// Mapping profile to use instead of the standard gamepad
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<PathBuf>,
+    /// How much of each stick's travel around the center to ignore, left
+    /// then right, from 0 to 1
+    pub deadzones: [f32; 2],
+    /// Scales the amplitude of every rumble, from 0 to 1
+    pub rumble_strength: f32,
+}
+
+impl Default for ControllerSettings {
+    fn default() -> ControllerSettings {
+        ControllerSettings {
+            name: None,
+            partner: None,
+            slot: None,
+            profile: None,
+            deadzones: [0.0, 0.0],
+            rumble_strength: 1.0,
+        }
+    }
+}
+
+impl ControllerSettings {
+    pub fn parse(text: &str) -> Result<ControllerSettings, String> {
+        let settings: ControllerSettings = toml::from_str(text).map_err(|e| e.to_string())?;
+        settings.check()?;
+        Ok(settings)
+    }
+
+    /// Make sure every setting is in range
+    pub fn check(&self) -> Result<(), String> {
+        if let Some(slot) = self.slot {
+            if !(1..=MAX_SLOT).contains(&slot) {
+                return Err(format!("slot: {} isn't between 1 and {}", slot, MAX_SLOT));
+            }
+        }
+        for (i, &deadzone) in self.deadzones.iter().enumerate() {
+            if !(0.0..1.0).contains(&deadzone) {
+                return Err(format!(
+                    "deadzones[{}]: {} isn't between 0 and 1",
+                    i, deadzone
+                ));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.rumble_strength) {
+            return Err(format!(
+                "rumble_strength: {} isn't between 0 and 1",
+                self.rumble_strength
+            ));
+        }
+        Ok(())
+    }
+
+    /// Change one setting, as asked over IPC
+    pub fn apply(&mut self, setting: Setting) {
+        match setting {
+            Setting::Name(name) => self.name = name,
+            Setting::Slot(slot) => self.slot = slot,
+            Setting::Profile(profile) => self.profile = profile.map(PathBuf::from),
+            Setting::Deadzones(deadzones) => self.deadzones = deadzones,
+            Setting::RumbleStrength(strength) => self.rumble_strength = strength,
+        }
+    }
+}
+
+/// Per-controller settings, one file per MAC address in
+/// `$XDG_CONFIG_HOME/joycond/controllers`
+pub struct SettingsStore {
+    dir: Option<PathBuf>,
+}
+
+impl SettingsStore {
+    pub fn new() -> SettingsStore {
+        let dir = config_home().map(|mut dir| {
+            dir.push("joycond");
+            dir.push("controllers");
+            dir
+        });
+        if dir.is_none() {
+            log::e("Neither XDG_CONFIG_HOME nor HOME is set, so controller settings won't be kept");
+        }
+        SettingsStore { dir }
+    }
+
+    /// Settings for a controller, or the defaults if it has none. A file
+    /// that can't be read or parsed is an error, rather than the defaults, so
+    /// it isn't saved over.
+    pub fn load(&self, mac_address: &str) -> Result<ControllerSettings, String> {
+        let path = match self.path(mac_address) {
+            Some(path) => path,
+            None => return Ok(ControllerSettings::default()),
+        };
+        let mut text = String::new();
+        match File::open(&path).and_then(|mut f| f.read_to_string(&mut text)) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(ControllerSettings::default())
+            }
+            Err(e) => return Err(format!("Couldn't read {}: {}", path.display(), e)),
+        }
+        ControllerSettings::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, mac_address: &str, settings: &ControllerSettings) -> io::Result<()> {
+        let path = match self.path(mac_address) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let text =
+            toml::to_string(settings).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        File::create(&path).and_then(|mut f| f.write_all(text.as_bytes()))
+    }
+
+    /// Load, change and save a controller's settings, returning them as
+    /// saved. Nothing is saved if the existing file is broken, so fixing it by
+    /// hand doesn't mean starting over, or if the change puts a setting out of
+    /// range.
+    pub fn update<F: FnOnce(&mut ControllerSettings)>(
+        &self,
+        mac_address: &str,
+        change: F,
+    ) -> Result<ControllerSettings, String> {
+        let mut settings = self.load(mac_address).map_err(|e| {
+            format!(
+                "Not saving settings for {}, since they can't be loaded: {}",
+                mac_address, e
+            )
+        })?;
+        change(&mut settings);
+        settings.check()?;
+        self.save(mac_address, &settings)
+            .map_err(|e| format!("Couldn't save settings for {}: {}", mac_address, e))?;
+        Ok(settings)
+    }
+
+    fn path(&self, mac_address: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| {
+            let mut path = dir.clone();
+            path.push(format!("{}.toml", mac_address.replace(':', "-")));
+            path
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const MAC_ADDRESS: &str = "98:B6:E9:00:00:01";
+
+    // A store in a directory of its own, which is removed when it's dropped
+    struct TempStore {
+        store: SettingsStore,
+        dir: PathBuf,
+    }
+
+    impl TempStore {
+        fn new(name: &str) -> TempStore {
+            let mut dir = env::temp_dir();
+            dir.push(format!("joycond-settings-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempStore {
+                store: SettingsStore {
+                    dir: Some(dir.clone()),
+                },
+                dir,
+            }
+        }
+
+        fn write(&self, text: &str) {
+            fs::create_dir_all(&self.dir).unwrap();
+            fs::write(self.store.path(MAC_ADDRESS).unwrap(), text).unwrap();
+        }
+
+        fn read(&self) -> String {
+            fs::read_to_string(self.store.path(MAC_ADDRESS).unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn missing_settings_are_the_defaults() {
+        let temp = TempStore::new("missing");
+        assert_eq!(
+            temp.store.load(MAC_ADDRESS),
+            Ok(ControllerSettings::default())
+        );
+    }
+
+    #[test]
+    fn broken_settings_are_an_error() {
+        let temp = TempStore::new("broken");
+        temp.write("slto = 2\n");
+        assert!(temp.store.load(MAC_ADDRESS).is_err());
+    }
+
+    #[test]
+    fn broken_settings_are_not_saved_over() {
+        let temp = TempStore::new("kept");
+        temp.write("name = \"Left\"\nslto = 2\n");
+        assert!(temp
+            .store
+            .update(MAC_ADDRESS, |settings| settings.slot = Some(3))
+            .is_err());
+        assert_eq!(temp.read(), "name = \"Left\"\nslto = 2\n");
+    }
+
+    #[test]
+    fn updates_keep_other_settings() {
+        let temp = TempStore::new("update");
+        temp.write("name = \"Left\"\n");
+        let updated = temp
+            .store
+            .update(MAC_ADDRESS, |settings| settings.slot = Some(3))
+            .unwrap();
+        let settings = temp.store.load(MAC_ADDRESS).unwrap();
+        assert_eq!(settings, updated);
+        assert_eq!(settings.name.as_deref(), Some("Left"));
+        assert_eq!(settings.slot, Some(3));
+    }
+
+    #[test]
+    fn settings_out_of_range_are_not_saved() {
+        let temp = TempStore::new("range");
+        temp.write("slot = 2\n");
+        let update = temp.store.update(MAC_ADDRESS, |settings| {
+            settings.apply(Setting::Slot(Some(9)));
+        });
+        assert!(update.is_err());
+        assert_eq!(temp.read(), "slot = 2\n");
+    }
+
+    #[test]
+    fn applies_settings_from_requests() {
+        let mut settings = ControllerSettings::default();
+        settings.apply(Setting::Name(Some("Left".to_string())));
+        settings.apply(Setting::Profile(Some("/tmp/fps.toml".to_string())));
+        settings.apply(Setting::Deadzones([0.1, 0.2]));
+        settings.apply(Setting::RumbleStrength(0.5));
+        settings.apply(Setting::Slot(Some(3)));
+        settings.apply(Setting::Slot(None));
+        assert_eq!(
+            settings,
+            ControllerSettings {
+                name: Some("Left".to_string()),
+                partner: None,
+                slot: None,
+                profile: Some(PathBuf::from("/tmp/fps.toml")),
+                deadzones: [0.1, 0.2],
+                rumble_strength: 0.5,
+            }
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+
+/// Highest player number. The LEDs can show eight players by flashing.
+pub const MAX_SLOT: u8 = 8;
+
+/// Player numbers handed out to controllers by serial
+pub struct Slots {
+    owners: BTreeMap<u8, String>,
+}
+
+impl Slots {
+    pub fn new() -> Slots {
+        Slots {
+            owners: BTreeMap::new(),
+        }
+    }
+
+    /// Give a controller a slot, `preferred` if that's free, otherwise the
+    /// lowest free one. Controllers that already have a slot keep it.
+    pub fn assign(&mut self, serial: &str, preferred: Option<u8>) -> Option<u8> {
+        if let Some(slot) = self.slot_of(serial) {
+            return Some(slot);
+        }
+        let slot = preferred
+            .filter(|slot| !self.owners.contains_key(slot))
+            .or_else(|| (1..=MAX_SLOT).find(|slot| !self.owners.contains_key(slot)))?;
+        self.owners.insert(slot, serial.to_string());
+        Some(slot)
+    }
+
+    pub fn release(&mut self, serial: &str) {
+        if let Some(slot) = self.slot_of(serial) {
+            self.owners.remove(&slot);
+        }
+    }
+
+    pub fn slot_of(&self, serial: &str) -> Option<u8> {
+        self.owners
+            .iter()
+            .find(|(_, owner)| *owner == serial)
+            .map(|(&slot, _)| slot)
+    }
+
+    /// Controllers in slots after `slot`, in order
+    pub fn after(&self, slot: u8) -> Vec<String> {
+        self.owners
+            .range(slot + 1..)
+            .map(|(_, owner)| owner.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,1219 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hidapi::{HidApi, HidError};
+use signal_hook::iterator::Signals;
+
+use common::has::Has;
+use common::ipc::protocol::{
+    self, Battery, BatteryConnection, BatteryLevel, ControllerInfo, ControllerState,
+    ControllerStatus, ErrorKind, Event, Filter, HomeLight, Power, Request, Response,
+};
+use common::ipc::proxy::ProxyCommand;
+use common::log;
+
+use joycon_driver::battery::{BatteryState, Connection, Level};
+use joycon_driver::button::{Button, REAL_BUTTONS};
+use joycon_driver::device::{HciState, InputMode};
+use joycon_driver::driver::Driver;
+use joycon_driver::event::ButtonEvent;
+use joycon_driver::home_light::Pattern;
+use joycon_driver::id::{Product, Vendor};
+use joycon_driver::player_leds::LedState;
+
+use super::config::{AutoPair, Config};
+use super::events::{EventQueue, Subscriber};
+use super::hotplug::{Action, Uevent};
+use super::session::Session;
+use super::settings::{ControllerSettings, SettingsStore};
+use super::slots::Slots;
+use super::systemd::{self, Watchdog};
+use super::worker::Worker;
+
+// How often the device list is refreshed to find new controllers, when
+// hotplug events aren't available
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long the supervisor sleeps between passes over its workers. Full-mode
+// reports arrive every 15ms, so this keeps up with them comfortably.
+const POLL_INTERVAL: Duration = Duration::from_millis(4);
+
+// How close together L and R have to be pressed on two Joy-Con to pair them
+const PAIR_WINDOW: Duration = Duration::from_secs(1);
+
+// Times the Home light blinks when the battery runs low
+const LOW_BATTERY_BLINKS: u8 = 10;
+
+/// Sent from connection threads to the supervisor
+pub enum Message {
+    Request(Request, Sender<Response>),
+    Subscribe(Filter, Arc<EventQueue>),
+    Hotplug(Uevent),
+    /// Hotplug events won't be coming anymore
+    HotplugStopped,
+}
+
+/// Owns every controller. HID devices can't leave the thread that opened
+/// them, so the supervisor runs all of its workers on its own thread and
+/// answers requests from other threads over a channel. Nothing on it waits on
+/// a controller: workers set controllers up and read their flash a step at a
+/// time, so one that's slow to answer doesn't stall the rest.
+pub struct Supervisor {
+    api: HidApi,
+    messages: Receiver<Message>,
+    config: Config,
+    // Set from a signal handler when the configuration should be reloaded
+    reload: Arc<AtomicBool>,
+    // Keyed by device path
+    workers: BTreeMap<CString, Worker>,
+    // Keyed by serial, outliving workers while their controller is briefly gone
+    sessions: BTreeMap<String, Session>,
+    settings: SettingsStore,
+    slots: Slots,
+    // Serials of left and right Joy-Con combined into one controller
+    pairs: Vec<(String, String)>,
+    subscribers: Vec<Subscriber>,
+    // Whether to keep scanning for controllers, instead of relying on hotplug events
+    polling: bool,
+    scanned: Option<Instant>,
+    watchdog: Option<Watchdog>,
+    // SIGINT and SIGTERM, which end `run`
+    signals: Signals,
+}
+
+impl Supervisor {
+    /// Creates a supervisor that finds controllers through `Message::Hotplug`,
+    /// or by scanning for them periodically if `polling` is set
+    pub fn new(
+        messages: Receiver<Message>,
+        polling: bool,
+        config: Config,
+        reload: Arc<AtomicBool>,
+        signals: Signals,
+    ) -> Result<Supervisor, HidError> {
+        Ok(Supervisor {
+            api: HidApi::new()?,
+            messages,
+            config,
+            reload,
+            workers: BTreeMap::new(),
+            sessions: BTreeMap::new(),
+            settings: SettingsStore::new(),
+            slots: Slots::new(),
+            pairs: Vec::new(),
+            subscribers: Vec::new(),
+            polling,
+            scanned: None,
+            watchdog: Watchdog::from_env(),
+            signals,
+        })
+    }
+
+    /// Looks after controllers until asked to stop, then lets go of them
+    pub fn run(&mut self) {
+        systemd::notify("READY=1");
+        while self.signals.pending().next().is_none() {
+            if let Some(ref mut watchdog) = self.watchdog {
+                watchdog.feed();
+            }
+            if self.reload.swap(false, Ordering::Relaxed) {
+                self.reload_config();
+            }
+            let due = match self.scanned {
+                Some(scanned) => self.polling && scanned.elapsed() >= SCAN_INTERVAL,
+                None => true,
+            };
+            if due {
+                self.scanned = Some(Instant::now());
+                self.scan();
+            }
+            self.poll_workers();
+            self.pairing_ritual();
+            self.expire_sessions();
+            self.retry_proxies();
+            self.handle_messages();
+            thread::sleep(POLL_INTERVAL);
+        }
+        self.shut_down();
+    }
+
+    // Leaves controllers the way they were found, and games with nothing
+    // held down on devices that are about to disappear
+    fn shut_down(&mut self) {
+        systemd::notify("STOPPING=1");
+        log::i("Shutting down");
+        for worker in self.workers.values_mut() {
+            let serial = worker.serial_number().to_string();
+            if let Some(driver) = worker.driver() {
+                if let Err(e) = driver.reset() {
+                    log::e(&format!("Couldn't reset {}: {:?}", serial, e));
+                }
+            }
+        }
+        for (_, mut session) in mem::take(&mut self.sessions) {
+            session.close_device();
+        }
+        self.slots = Slots::new();
+        self.pairs.clear();
+    }
+
+    // Settings that only matter when a controller connects take effect the
+    // next time one does, except for the input mode and IMU, which are sent
+    // to every active controller again
+    fn reload_config(&mut self) {
+        systemd::notify("RELOADING=1");
+        let loaded = Config::load();
+        systemd::notify("READY=1");
+        let mut config = match loaded {
+            Ok(config) => config,
+            Err(e) => {
+                log::e(&format!("Keeping the previous configuration: {}", e));
+                return;
+            }
+        };
+        if config.daemon.socket != self.config.daemon.socket {
+            log::e("daemon.socket only changes when joycond is restarted");
+        }
+        if config.daemon.proxies != self.config.daemon.proxies {
+            log::e("daemon.proxies only changes when joycond is restarted");
+            config.daemon.proxies = self.config.daemon.proxies;
+        }
+        if config.daemon.proxy_dir != self.config.daemon.proxy_dir {
+            log::e("daemon.proxy_dir only changes when joycond is restarted");
+            config.daemon.proxy_dir = self.config.daemon.proxy_dir.clone();
+        }
+        log::set_level(config.daemon.log_level);
+        for worker in self.workers.values_mut() {
+            let serial = worker.serial_number().to_string();
+            if let Some(driver) = worker.driver() {
+                let sent = driver
+                    .set_input_mode(InputMode::from(config.controllers.input_mode))
+                    .and_then(|_| driver.enable_imu(config.controllers.imu));
+                if let Err(e) = sent {
+                    log::e(&format!("Couldn't reconfigure {}: {:?}", serial, e));
+                }
+            }
+        }
+        self.config = config;
+        log::i("Reloaded the configuration");
+    }
+
+    /// Start a worker for each new controller, and drop those that have gone away
+    fn scan(&mut self) {
+        if let Err(e) = self.api.refresh_devices() {
+            log::e(&format!("Couldn't enumerate devices: {:?}", e));
+            return;
+        }
+        let controllers: Vec<_> = self
+            .api
+            .devices()
+            .iter()
+            .filter(|info| is_controller(info.vendor_id, info.product_id))
+            .cloned()
+            .collect();
+
+        let removed: Vec<CString> = self
+            .workers
+            .keys()
+            .filter(|path| !controllers.iter().any(|info| &info.path == *path))
+            .cloned()
+            .collect();
+        for path in removed {
+            self.remove(&path);
+        }
+
+        for info in controllers {
+            if !self.workers.contains_key(&info.path) {
+                log::i(&format!(
+                    "Found {} [{}]",
+                    info.product_string.as_deref().unwrap_or("controller"),
+                    info.serial_number.as_deref().unwrap_or("")
+                ));
+                // A controller coming back shows what it showed before.
+                // Those without a serial number are only recognized once
+                // they've said their address.
+                let leds = info
+                    .serial_number
+                    .as_ref()
+                    .filter(|serial| !serial.is_empty())
+                    .and_then(|serial| self.sessions.get(serial))
+                    .map_or(self.config.leds.pending(), |session| session.leds);
+                self.workers
+                    .insert(info.path.clone(), Worker::new(info, leds));
+            }
+        }
+    }
+
+    // Pairings and the virtual device are kept for a while, in case the
+    // controller comes back
+    fn remove(&mut self, path: &CString) {
+        if let Some(worker) = self.workers.remove(path) {
+            log::i(&format!("Controller {} removed", worker.serial_number()));
+            if let Some(session) = self.sessions.get_mut(worker.serial_number()) {
+                session.lose();
+            }
+            if worker.status() == ControllerStatus::Active {
+                self.publish(Event::Disconnected {
+                    serial: worker.serial_number().to_string(),
+                });
+            }
+        }
+    }
+
+    fn hotplug(&mut self, uevent: Uevent) {
+        if !is_controller(uevent.vendor_id, uevent.product_id) {
+            return;
+        }
+        match uevent.action {
+            // Let enumeration fill in the details, like the serial number
+            Action::Add => self.scan(),
+            Action::Remove => self.remove(&uevent.path),
+        }
+    }
+
+    fn poll_workers(&mut self) {
+        let mut events = Vec::new();
+        let mut commands = Vec::new();
+        let mut activated = Vec::new();
+        let mut started = Vec::new();
+        let idle_timeout = self.config.controllers.idle_timeout();
+        let sampling = self.subscribers.iter().any(Subscriber::wants_samples);
+        let home_notifications = self.config.leds.home_notifications;
+        // A pair is in use while either half is, and both halves go to sleep
+        // together once neither is
+        let mut pair_idle = BTreeMap::new();
+        for (left, right) in &self.pairs {
+            if let (Some(l), Some(r)) = (self.sessions.get(left), self.sessions.get(right)) {
+                let idle = l.idle_for().min(r.idle_for());
+                pair_idle.insert(left.clone(), idle);
+                pair_idle.insert(right.clone(), idle);
+            }
+        }
+        for worker in self.workers.values_mut() {
+            let previous = worker.status();
+            match worker.poll(&self.api, &self.config.controllers) {
+                Some(ControllerStatus::Active) => {
+                    let serial = worker.serial_number().to_string();
+                    let resumed = match self.sessions.get_mut(&serial) {
+                        Some(session) => session.resume(),
+                        None => {
+                            let session = start_session(&self.settings, &self.config, worker);
+                            self.sessions.insert(serial.clone(), session);
+                            started.push(serial.clone());
+                            false
+                        }
+                    };
+                    activated.push(serial.clone());
+                    if resumed {
+                        log::i(&format!("Controller {} reconnected", serial));
+                    } else {
+                        log::i(&format!("Controller {} active", serial));
+                    }
+                    events.push(Event::Connected {
+                        controller: worker.describe(),
+                    });
+                }
+                Some(_) if previous == ControllerStatus::Active => {
+                    if let Some(session) = self.sessions.get_mut(worker.serial_number()) {
+                        session.lose();
+                    }
+                    events.push(Event::Disconnected {
+                        serial: worker.serial_number().to_string(),
+                    });
+                }
+                _ => (),
+            }
+
+            // The left half of a pair speaks for both
+            let serial = worker.serial_number().to_string();
+            let sessions = &self.sessions;
+            let partner = self
+                .pairs
+                .iter()
+                .find(|(left, _)| *left == serial)
+                .and_then(|(_, right)| sessions.get(right))
+                .map(|session| *session.frame());
+            if let (Some(driver), Some(session)) = (worker.driver(), self.sessions.get_mut(&serial))
+            {
+                // Taken from the driver rather than diffed from frames here,
+                // so they keep the controller's own timing
+                while let Some((time, event)) = driver.next_event() {
+                    let (button, pressed) = match event {
+                        ButtonEvent::Pressed(button) => (button, true),
+                        ButtonEvent::Released(button) => (button, false),
+                    };
+                    events.push(Event::Button {
+                        serial: serial.clone(),
+                        button: format!("{:?}", button),
+                        pressed,
+                        time_ms: time.as_millis() as u64,
+                    });
+                }
+                while let Some(frame) = driver.next_frame() {
+                    // Replies to commands carry no motion to sample
+                    if let (true, Some(motion)) = (sampling, frame.motion) {
+                        let (ax, ay, az) = motion.accelerometer;
+                        let (gx, gy, gz) = motion.gyroscope;
+                        events.push(Event::Sample {
+                            serial: serial.clone(),
+                            sticks: [frame.axes.lx, frame.axes.ly, frame.axes.rx, frame.axes.ry],
+                            accelerometer: [ax, ay, az],
+                            gyroscope: [gx, gy, gz],
+                        });
+                    }
+                    session.update(&frame, driver.product(), partner.as_ref());
+                }
+                while let Some(command) = session.next_command() {
+                    commands.push((serial.clone(), command));
+                }
+                if let Some(battery) = driver.battery().map(|state| battery_status(state, driver)) {
+                    let last = session.battery.replace(battery);
+                    if last.is_some_and(|last| last != battery) {
+                        events.push(Event::Battery {
+                            serial: serial.clone(),
+                            battery,
+                        });
+                    }
+                    if battery.low && !last.is_some_and(|last| last.low) {
+                        log::e(&format!("{}'s battery is {:?}", serial, battery.level));
+                        if home_notifications {
+                            show_home_light(driver, &Pattern::blink(LOW_BATTERY_BLINKS));
+                        }
+                        events.push(Event::LowBattery {
+                            serial: serial.clone(),
+                            battery,
+                        });
+                    }
+                }
+                let idle = pair_idle
+                    .get(&serial)
+                    .copied()
+                    .unwrap_or_else(|| session.idle_for());
+                if idle_timeout.is_some_and(|timeout| idle >= timeout) {
+                    log::i(&format!(
+                        "Putting {} to sleep, since it isn't being used",
+                        serial
+                    ));
+                    if let Err(e) = driver.set_hci_state(HciState::Disconnect) {
+                        log::e(&format!("Couldn't put {} to sleep: {:?}", serial, e));
+                    }
+                    session.touch();
+                }
+            }
+        }
+        for event in events {
+            self.publish(event);
+        }
+        // Proxies pass on what games ask of their devices, which is carried
+        // out like the same request from a client
+        for (serial, command) in commands {
+            let request = match command {
+                ProxyCommand::SetLeds { leds } => Request::SetLeds { serial, leds },
+                ProxyCommand::Rumble { rumble } => Request::Rumble { serial, rumble },
+            };
+            if let Err((_, message)) = self.try_handle(request) {
+                log::e(&message);
+            }
+        }
+        for serial in activated {
+            self.auto_pair(&serial);
+        }
+
+        // Pro Controllers are ready to play right away, Joy-Con are first
+        // asked how they're held, unless they were paired above
+        for serial in started {
+            let joycon = self
+                .workers
+                .values_mut()
+                .find(|w| w.serial_number() == serial)
+                .and_then(|w| w.driver())
+                .and_then(|d| d.product())
+                .is_some_and(|p| matches!(p, Product::JoyConL | Product::JoyConR));
+            let pending = self.sessions.get(&serial).is_some_and(|s| s.pending);
+            if !pending {
+                continue;
+            }
+            if joycon && self.config.pairing.ritual {
+                log::i(&format!(
+                    "Press L and R on two Joy-Con to pair {}, or SL and SR to use it alone",
+                    serial
+                ));
+            } else {
+                self.place(&serial);
+            }
+        }
+    }
+
+    /// Pair pending Joy-Con when L is pressed on a left one and R on a right
+    /// one at about the same time, or let one be used sideways on its own
+    /// when its SL and SR are held together
+    fn pairing_ritual(&mut self) {
+        let (mut lefts, mut rights, mut alone) = (Vec::new(), Vec::new(), Vec::new());
+        for worker in self.workers.values_mut() {
+            let serial = worker.serial_number().to_string();
+            let product = match worker.driver().and_then(|d| d.product()) {
+                Some(product) => product,
+                None => continue,
+            };
+            let session = match self.sessions.get(&serial) {
+                Some(session) if session.pending => session,
+                _ => continue,
+            };
+            let sides = [Button::Sl, Button::Sr]
+                .iter()
+                .filter_map(|button| button.to_real(product))
+                .fold(0, |mask, button| mask | u32::from(button));
+            if session.holds(sides) {
+                alone.push(serial);
+                continue;
+            }
+            let pressed = match session.shoulder_pressed_at() {
+                Some(at) if at.elapsed() < PAIR_WINDOW => at,
+                _ => continue,
+            };
+            match product {
+                Product::JoyConL => lefts.push((serial, pressed)),
+                Product::JoyConR => rights.push((serial, pressed)),
+                _ => (),
+            }
+        }
+
+        for serial in alone {
+            log::i(&format!("{} is playing on its own", serial));
+            self.place(&serial);
+        }
+        for (left, right) in closest_presses(&lefts, &rights) {
+            match self.pair(left.clone(), right.clone()) {
+                Ok(()) => log::i(&format!("Paired {} with {}", left, right)),
+                Err((_, message)) => {
+                    log::e(&format!("Couldn't pair {}: {}", left, message));
+                    // Otherwise they'd be tried again until the presses expire
+                    for serial in &[left, right] {
+                        if let Some(session) = self.sessions.get_mut(serial) {
+                            session.forget_shoulder();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Proxies are started alongside the daemon, so they may not be up yet
+    // when their controllers are placed
+    fn retry_proxies(&mut self) {
+        for session in self.sessions.values_mut() {
+            session.retry_proxy();
+        }
+    }
+
+    /// Give a controller that's ready to play a player slot and a virtual
+    /// device, unless it already has them
+    fn place(&mut self, serial: &str) {
+        let preferred = match self.sessions.get_mut(serial) {
+            Some(session) => {
+                session.pending = false;
+                session.settings.slot
+            }
+            None => return,
+        };
+        let slot = self.slots.assign(serial, preferred);
+        if let Some(session) = self.sessions.get_mut(serial) {
+            session.open_device(slot);
+        }
+        // The controller gets the same player next time, if it's free
+        if let Some(slot) = slot.filter(|&slot| preferred != Some(slot)) {
+            if let Err(e) = self.save_settings(serial, |settings| settings.slot = Some(slot)) {
+                log::e(&e);
+            }
+        }
+        let leds = match slot {
+            Some(slot) => {
+                log::i(&format!("{} is player {}", serial, slot));
+                if self.config.leds.show_slot {
+                    LedState::player(slot)
+                } else {
+                    self.config.leds.pending()
+                }
+            }
+            None => {
+                log::e(&format!("No player slots left for {}", serial));
+                LedState::Error
+            }
+        };
+        self.show_leds(serial, leds);
+        if let Some(slot) = slot {
+            if self.config.leds.home_notifications {
+                let driver = self
+                    .workers
+                    .values_mut()
+                    .find(|w| w.serial_number() == serial)
+                    .and_then(Worker::driver);
+                if let Some(driver) = driver {
+                    show_home_light(driver, &Pattern::blink(slot));
+                }
+            }
+            self.reorder_devices(slot);
+        }
+    }
+
+    fn show_leds(&mut self, serial: &str, leds: LedState) {
+        if let Some(session) = self.sessions.get_mut(serial) {
+            session.leds = leds;
+        }
+        if let Some(worker) = self
+            .workers
+            .values_mut()
+            .find(|w| w.serial_number() == serial)
+        {
+            if let Err(e) = worker.set_leds(leds) {
+                log::e(&format!("Couldn't set LEDs on {}: {:?}", serial, e));
+            }
+        }
+    }
+
+    // Games number players in the order their devices appeared, so a
+    // controller taking a slot ahead of others has their devices recreated
+    // behind its own
+    fn reorder_devices(&mut self, slot: u8) {
+        for serial in self.slots.after(slot) {
+            if let Some(session) = self.sessions.get_mut(&serial) {
+                log::i(&format!(
+                    "Recreating the device for {} to keep players in order",
+                    serial
+                ));
+                session.reopen_device();
+            }
+        }
+    }
+
+    /// Pair a Joy-Con with the partner its settings name, if that's connected
+    /// and neither is paired yet
+    fn auto_pair(&mut self, serial: &str) {
+        if self.config.pairing.auto == AutoPair::Off {
+            return;
+        }
+        let mac_address = match self
+            .sessions
+            .get(serial)
+            .and_then(|session| session.settings.partner.clone())
+        {
+            Some(mac_address) => mac_address,
+            None => return,
+        };
+        let partner = self
+            .workers
+            .values()
+            .find(|w| {
+                w.status() == ControllerStatus::Active
+                    && w.mac_address().as_ref() == Some(&mac_address)
+            })
+            .map(|w| w.serial_number().to_string());
+        let partner = match partner {
+            Some(partner) => partner,
+            None => return,
+        };
+        if self.partner(serial).is_some() || self.partner(&partner).is_some() {
+            return;
+        }
+        // Joy-Con already in use on their own stay that way
+        let pending = |serial: &str| self.sessions.get(serial).is_some_and(|s| s.pending);
+        if !pending(serial) || !pending(&partner) {
+            return;
+        }
+
+        let left = self
+            .active(serial)
+            .ok()
+            .and_then(|w| w.driver())
+            .and_then(|d| d.product())
+            .is_some_and(|p| p as u16 == Product::JoyConL as u16);
+        let (left, right) = if left {
+            (serial.to_string(), partner)
+        } else {
+            (partner, serial.to_string())
+        };
+        match self.pair(left.clone(), right.clone()) {
+            Ok(()) => log::i(&format!("Paired {} with {}", left, right)),
+            Err((_, message)) => log::e(&format!("Couldn't pair {}: {}", serial, message)),
+        }
+    }
+
+    /// Forget controllers that have been gone too long, along with their
+    /// virtual devices and pairings
+    fn expire_sessions(&mut self) {
+        let expired: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.expired())
+            .map(|(serial, _)| serial.clone())
+            .collect();
+        for serial in expired {
+            log::i(&format!("Controller {} didn't come back", serial));
+            self.sessions.remove(&serial);
+            self.slots.release(&serial);
+            self.unpair(&serial);
+        }
+    }
+
+    fn handle_messages(&mut self) {
+        loop {
+            match self.messages.try_recv() {
+                Ok(Message::Request(
+                    Request::ReadFlash {
+                        serial,
+                        address,
+                        length,
+                    },
+                    reply,
+                )) => self.read_flash(&serial, address, length, reply),
+                Ok(Message::Request(request, reply)) => {
+                    let response = self.handle(request);
+                    // The client may have hung up while waiting, which is its business
+                    let _ = reply.send(response);
+                }
+                Ok(Message::Subscribe(filter, queue)) => {
+                    self.subscribers.push(Subscriber::new(filter, queue))
+                }
+                Ok(Message::Hotplug(uevent)) => self.hotplug(uevent),
+                // Events may have been missed, so scan right away too
+                Ok(Message::HotplugStopped) => {
+                    self.polling = true;
+                    self.scanned = None;
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => panic!("Lost the daemon's listener"),
+            }
+        }
+    }
+
+    /// Sends an event to every subscriber that wants it, forgetting those
+    /// that have gone away
+    fn publish(&mut self, event: Event) {
+        self.subscribers
+            .retain(|subscriber| !subscriber.is_closed());
+        for subscriber in &mut self.subscribers {
+            subscriber.send(&event);
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> Response {
+        match self.try_handle(request) {
+            Ok(response) => response,
+            Err((kind, message)) => Response::Error { kind, message },
+        }
+    }
+
+    fn try_handle(&mut self, request: Request) -> Result<Response, Failure> {
+        match request {
+            Request::Hello { .. } => Err(failure(ErrorKind::InvalidRequest, "Already said hello")),
+            Request::Subscribe { .. } => Err(failure(
+                ErrorKind::InvalidRequest,
+                "Subscriptions are handled by the connection",
+            )),
+            Request::ListControllers => Ok(Response::Controllers {
+                controllers: self.workers.values().map(|w| self.describe(w)).collect(),
+            }),
+            Request::GetState { serial } => {
+                let worker = self.active(&serial)?;
+                let driver = worker.driver().unwrap();
+                let frame = driver.frame();
+                let state = ControllerState {
+                    serial,
+                    buttons: REAL_BUTTONS
+                        .iter()
+                        .filter(|&&btn| frame.buttons.has(btn))
+                        .map(|btn| format!("{:?}", btn))
+                        .collect(),
+                    sticks: [frame.axes.lx, frame.axes.ly, frame.axes.rx, frame.axes.ry],
+                    leds: driver.leds(),
+                    reported_leds: driver.reported_leds(),
+                };
+                Ok(Response::State { state })
+            }
+            Request::SetLeds { serial, leds } => {
+                let leds = LedState::Raw(leds);
+                self.active(&serial)?.set_leds(leds).map_err(device_error)?;
+                if let Some(session) = self.sessions.get_mut(&serial) {
+                    session.leds = leds;
+                }
+                Ok(Response::Ok)
+            }
+            Request::Rumble { serial, mut rumble } => {
+                if let Some(session) = self.sessions.get(&serial) {
+                    rumble.amplitude *= session.settings.rumble_strength;
+                }
+                self.active(&serial)?.rumble(rumble).map_err(device_error)?;
+                Ok(Response::Ok)
+            }
+            Request::SetHomeLight { serial, light } => {
+                let driver = self.active(&serial)?.driver().unwrap();
+                if !driver.product().is_some_and(Product::has_home_light) {
+                    return Err(failure(
+                        ErrorKind::InvalidRequest,
+                        &format!("{} has no Home light", serial),
+                    ));
+                }
+                let pattern = match light {
+                    HomeLight::Off => Pattern::off(),
+                    HomeLight::On => Pattern::on(0xf),
+                    HomeLight::Breathing => Pattern::breathing(),
+                    HomeLight::Blink => Pattern::blink(0),
+                };
+                driver.set_home_light(&pattern).map_err(device_error)?;
+                Ok(Response::Ok)
+            }
+            Request::SetPower { serial, power } => {
+                let state = match power {
+                    Power::Sleep => HciState::Disconnect,
+                    Power::Reconnect => HciState::Reconnect,
+                    Power::Pair => HciState::Pair,
+                    Power::Home => HciState::Home,
+                };
+                let driver = self.active(&serial)?.driver().unwrap();
+                driver.set_hci_state(state).map_err(device_error)?;
+                log::i(&format!("Disconnected {} to {:?}", serial, power));
+                Ok(Response::Ok)
+            }
+            Request::SleepIdle { idle_secs: 0 } => Err(failure(
+                ErrorKind::InvalidRequest,
+                "No controller is ever idle for 0 seconds",
+            )),
+            Request::SleepIdle { idle_secs } => {
+                let idle = Duration::from_secs(idle_secs);
+                let mut serials = Vec::new();
+                for worker in self.workers.values_mut() {
+                    let serial = worker.serial_number().to_string();
+                    let session = match self.sessions.get_mut(&serial) {
+                        Some(session) if session.idle_for() >= idle => session,
+                        _ => continue,
+                    };
+                    if let Some(driver) = worker.driver() {
+                        match driver.set_hci_state(HciState::Disconnect) {
+                            Ok(_) => serials.push(serial),
+                            Err(e) => log::e(&format!("Couldn't put {} to sleep: {:?}", serial, e)),
+                        }
+                        session.touch();
+                    }
+                }
+                log::i(&format!("Put {} idle controllers to sleep", serials.len()));
+                Ok(Response::Serials { serials })
+            }
+            Request::Pair { left, right } => {
+                self.pair(left.clone(), right.clone())?;
+                // Remember the pairing, so it's made again next time
+                self.remember_partner(&left, Some(&right));
+                self.remember_partner(&right, Some(&left));
+                Ok(Response::Ok)
+            }
+            Request::Unpair { serial } => {
+                let partner = self.partner(&serial).map(str::to_string);
+                if self.unpair(&serial) {
+                    self.remember_partner(&serial, None);
+                    if let Some(partner) = partner {
+                        self.remember_partner(&partner, None);
+                    }
+                    Ok(Response::Ok)
+                } else {
+                    Err(failure(
+                        ErrorKind::InvalidRequest,
+                        &format!("{} isn't paired", serial),
+                    ))
+                }
+            }
+            Request::GetBattery { serial } => {
+                let driver = self.active(&serial)?.driver().unwrap();
+                match driver.battery() {
+                    Some(battery) => Ok(Response::Battery {
+                        battery: battery_status(battery, driver),
+                    }),
+                    None => Err(failure(
+                        ErrorKind::Device,
+                        &format!("{} hasn't reported its battery yet", serial),
+                    )),
+                }
+            }
+            Request::Calibrate { serial } => {
+                self.active(&serial)?.driver().unwrap().calibrate();
+                Ok(Response::Ok)
+            }
+            Request::ReadFlash { .. } => Err(failure(
+                ErrorKind::InvalidRequest,
+                "Flash reads are answered by the controller's worker",
+            )),
+            Request::SetSettings { serial, settings } => {
+                let session = self.sessions.get(&serial).ok_or_else(|| {
+                    failure(
+                        ErrorKind::NoSuchController,
+                        &format!("No controller with serial {}", serial),
+                    )
+                })?;
+                let mut changed = session.settings.clone();
+                for setting in &settings {
+                    changed.apply(setting.clone());
+                }
+                changed
+                    .check()
+                    .map_err(|e| failure(ErrorKind::InvalidRequest, &e))?;
+                self.save_settings(&serial, |saved| {
+                    for setting in &settings {
+                        saved.apply(setting.clone());
+                    }
+                })
+                .map_err(|e| failure(ErrorKind::Settings, &e))?;
+                Ok(Response::Ok)
+            }
+        }
+    }
+
+    // Flash reads wait on the controller, so rather than being answered
+    // here, they're handed to its worker to answer once the data arrives
+    fn read_flash(&mut self, serial: &str, address: u32, length: u8, reply: Sender<Response>) {
+        if !flash_read_fits(address, length) {
+            let message = format!("Can't read {} bytes at {:#x}", length, address);
+            let _ = reply.send(Response::error(ErrorKind::InvalidRequest, &message));
+            return;
+        }
+        match self.active(serial) {
+            Ok(worker) => worker.read_flash(address, length, reply),
+            Err((kind, message)) => {
+                let _ = reply.send(Response::Error { kind, message });
+            }
+        }
+    }
+
+    /// The worker for a controller that can take commands
+    fn active(&mut self, serial: &str) -> Result<&mut Worker, Failure> {
+        let worker = match self
+            .workers
+            .values_mut()
+            .find(|w| w.serial_number() == serial)
+        {
+            Some(worker) => worker,
+            None => {
+                return Err(failure(
+                    ErrorKind::NoSuchController,
+                    &format!("No controller with serial {}", serial),
+                ))
+            }
+        };
+        match worker.status() {
+            ControllerStatus::Active => Ok(worker),
+            status => Err(failure(
+                ErrorKind::Device,
+                &format!("Controller {} is {:?}", serial, status),
+            )),
+        }
+    }
+
+    fn describe(&self, worker: &Worker) -> ControllerInfo {
+        ControllerInfo {
+            name: self
+                .sessions
+                .get(worker.serial_number())
+                .and_then(|session| session.settings.name.clone()),
+            slot: self.slots.slot_of(worker.serial_number()).or_else(|| {
+                self.partner(worker.serial_number())
+                    .and_then(|partner| self.slots.slot_of(partner))
+            }),
+            paired_with: self.partner(worker.serial_number()).map(str::to_string),
+            ..worker.describe()
+        }
+    }
+
+    fn pair(&mut self, left: String, right: String) -> Result<(), Failure> {
+        for (serial, product) in &[(&left, Product::JoyConL), (&right, Product::JoyConR)] {
+            let driver = self.active(serial)?.driver().unwrap();
+            match driver.product() {
+                Some(p) if p as u16 == *product as u16 => (),
+                _ => {
+                    return Err(failure(
+                        ErrorKind::InvalidRequest,
+                        &format!("{} isn't a {:?}", serial, product),
+                    ))
+                }
+            }
+            if self.partner(serial).is_some() {
+                return Err(failure(
+                    ErrorKind::InvalidRequest,
+                    &format!("{} is already paired", serial),
+                ));
+            }
+        }
+        self.pairs.push((left.clone(), right.clone()));
+
+        // The pair is one player now, playing through the left half's device
+        self.slots.release(&right);
+        if let Some(session) = self.sessions.get_mut(&right) {
+            session.pending = false;
+            session.close_device();
+        }
+        if let Some(session) = self.sessions.get_mut(&left) {
+            session.set_partner(Some(right.clone()));
+        }
+        self.place(&left);
+        let leds = self.sessions.get(&left).map(|session| session.leds);
+        if let Some(leds) = leds {
+            self.show_leds(&right, leds);
+        }
+        Ok(())
+    }
+
+    /// Save a controller's preferred partner, by MAC address, to its settings
+    fn remember_partner(&mut self, serial: &str, partner: Option<&str>) {
+        let partner = partner
+            .and_then(|partner| self.sessions.get(partner))
+            .and_then(|session| session.mac_address.clone());
+        if let Err(e) = self.save_settings(serial, |settings| settings.partner = partner.clone()) {
+            log::e(&e);
+        }
+    }
+
+    /// Change a controller's settings, then save them under its MAC address.
+    /// They're changed for now even if they can't be saved.
+    fn save_settings<F: Fn(&mut ControllerSettings)>(
+        &mut self,
+        serial: &str,
+        change: F,
+    ) -> Result<(), String> {
+        let session = match self.sessions.get_mut(serial) {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        change(&mut session.settings);
+        let mac_address = session.mac_address.as_ref().ok_or_else(|| {
+            format!(
+                "{} hasn't said its address, which settings are kept under",
+                serial
+            )
+        })?;
+        session.settings = self.settings.update(mac_address, change)?;
+        Ok(())
+    }
+
+    fn partner(&self, serial: &str) -> Option<&str> {
+        self.pairs.iter().find_map(|(left, right)| {
+            if left == serial {
+                Some(right.as_str())
+            } else if right == serial {
+                Some(left.as_str())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Split a pair, leaving each half to be used on its own
+    fn unpair(&mut self, serial: &str) -> bool {
+        let i = match self
+            .pairs
+            .iter()
+            .position(|(left, right)| left == serial || right == serial)
+        {
+            Some(i) => i,
+            None => return false,
+        };
+        let (left, right) = self.pairs.remove(i);
+        if let Some(session) = self.sessions.get_mut(&left) {
+            session.set_partner(None);
+        }
+        self.place(&right);
+        true
+    }
+}
+
+/// Set up a controller seen for the first time, as its settings say. It's
+/// pending until it's placed as a player.
+fn start_session(settings: &SettingsStore, config: &Config, worker: &Worker) -> Session {
+    let mac_address = worker.mac_address();
+    let settings = mac_address
+        .as_ref()
+        .and_then(|mac_address| settings.load(mac_address).map_err(|e| log::e(&e)).ok())
+        .unwrap_or_default();
+    let product = worker.describe().product;
+    Session::new(
+        worker.serial_number(),
+        &product,
+        mac_address,
+        settings,
+        &config.mapping.profiles,
+        config.leds.pending(),
+        if config.daemon.proxies {
+            Some(config.daemon.proxy_dir.clone())
+        } else {
+            None
+        },
+    )
+}
+
+fn is_controller(vendor_id: u16, product_id: u16) -> bool {
+    vendor_id == Vendor::Nintendo as u16
+        && [Product::JoyConL, Product::JoyConR, Product::ProController]
+            .iter()
+            .any(|&product| product as u16 == product_id)
+}
+
+// Pairs up left and right Joy-Con by when their L and R were pressed, the
+// closest presses first, so several pairs can be made at once
+fn closest_presses(
+    lefts: &[(String, Instant)],
+    rights: &[(String, Instant)],
+) -> Vec<(String, String)> {
+    let gap = |a: Instant, b: Instant| a.max(b) - a.min(b);
+    let mut candidates = Vec::new();
+    for (l, &(_, left_at)) in lefts.iter().enumerate() {
+        for (r, &(_, right_at)) in rights.iter().enumerate() {
+            candidates.push((gap(left_at, right_at), l, r));
+        }
+    }
+    candidates.sort();
+
+    let (mut left_used, mut right_used) = (vec![false; lefts.len()], vec![false; rights.len()]);
+    let mut pairs = Vec::new();
+    for (_, l, r) in candidates {
+        if !left_used[l] && !right_used[r] {
+            left_used[l] = true;
+            right_used[r] = true;
+            pairs.push((lefts[l].0.clone(), rights[r].0.clone()));
+        }
+    }
+    pairs
+}
+
+// Why a request couldn't be carried out
+type Failure = (ErrorKind, String);
+
+fn failure(kind: ErrorKind, message: &str) -> Failure {
+    (kind, message.to_string())
+}
+
+fn device_error(e: HidError) -> Failure {
+    failure(ErrorKind::Device, &format!("{:?}", e))
+}
+
+// Whether a `ReadFlash` request stays within the controller's flash, without
+// trusting the client's numbers not to overflow
+fn flash_read_fits(address: u32, length: u8) -> bool {
+    length <= protocol::MAX_FLASH_READ
+        && address
+            .checked_add(u32::from(length))
+            .is_some_and(|end| end <= protocol::FLASH_SIZE)
+}
+
+// For notifications, which controllers without a Home light just go without
+fn show_home_light(driver: &Driver, pattern: &Pattern) {
+    if !driver.product().is_some_and(Product::has_home_light) {
+        return;
+    }
+    if let Err(e) = driver.set_home_light(pattern) {
+        log::e(&format!(
+            "Couldn't set the Home light on {}: {:?}",
+            driver.serial_number(),
+            e
+        ));
+    }
+}
+
+fn battery_status(state: BatteryState, driver: &Driver) -> Battery {
+    Battery {
+        level: match state.level {
+            Level::Empty => BatteryLevel::Empty,
+            Level::Critical => BatteryLevel::Critical,
+            Level::Low => BatteryLevel::Low,
+            Level::Medium => BatteryLevel::Medium,
+            Level::Full => BatteryLevel::Full,
+        },
+        charging: state.charging,
+        powered: state.powered,
+        connection: match state.connection {
+            Connection::JoyCon => BatteryConnection::JoyCon,
+            Connection::ProOrGrip => BatteryConnection::ProOrGrip,
+            Connection::Unknown(_) => BatteryConnection::Unknown,
+        },
+        low: state.is_low(),
+        millivolts: driver.voltage(),
+        percent: driver.battery_percent(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flash_reads_stay_in_bounds() {
+        assert!(flash_read_fits(0, protocol::MAX_FLASH_READ));
+        assert!(flash_read_fits(protocol::FLASH_SIZE - 1, 1));
+        assert!(flash_read_fits(protocol::FLASH_SIZE, 0));
+        assert!(!flash_read_fits(protocol::FLASH_SIZE, 1));
+        assert!(!flash_read_fits(0, protocol::MAX_FLASH_READ + 1));
+    }
+
+    #[test]
+    fn flash_reads_near_the_top_of_the_address_space_are_refused() {
+        assert!(!flash_read_fits(u32::MAX, 1));
+        assert!(!flash_read_fits(u32::MAX - 2, protocol::MAX_FLASH_READ));
+        assert!(!flash_read_fits(u32::MAX, 0));
+    }
+
+    fn presses(now: Instant, presses: &[(&str, u64)]) -> Vec<(String, Instant)> {
+        presses
+            .iter()
+            .map(|&(serial, ms)| (serial.to_string(), now + Duration::from_millis(ms)))
+            .collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(left, right)| (left.to_string(), right.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn pairs_the_closest_presses() {
+        let now = Instant::now();
+        let lefts = presses(now, &[("L1", 0), ("L2", 500)]);
+        let rights = presses(now, &[("R1", 520), ("R2", 30)]);
+        assert_eq!(
+            closest_presses(&lefts, &rights),
+            pairs(&[("L2", "R1"), ("L1", "R2")])
+        );
+    }
+
+    #[test]
+    fn closest_presses_win_over_order() {
+        let now = Instant::now();
+        // L1 is closer to R1 than L2 is, but R1 is closer still to L2
+        let lefts = presses(now, &[("L1", 0), ("L2", 300)]);
+        let rights = presses(now, &[("R1", 280), ("R2", 900)]);
+        assert_eq!(
+            closest_presses(&lefts, &rights),
+            pairs(&[("L2", "R1"), ("L1", "R2")])
+        );
+    }
+
+    #[test]
+    fn unmatched_presses_wait() {
+        let now = Instant::now();
+        let lefts = presses(now, &[("L1", 0), ("L2", 100)]);
+        let rights = presses(now, &[("R1", 90)]);
+        assert_eq!(closest_presses(&lefts, &rights), pairs(&[("L2", "R1")]));
+        assert!(closest_presses(&lefts, &[]).is_empty());
+    }
+
+    #[test]
+    fn hotplug_only_picks_up_controllers() {
+        assert!(is_controller(0x057e, 0x2006));
+        assert!(is_controller(0x057e, 0x2007));
+        assert!(is_controller(0x057e, 0x2009));
+        // Some other Nintendo device, and a Logitech receiver
+        assert!(!is_controller(0x057e, 0x2010));
+        assert!(!is_controller(0x046d, 0xc52b));
+    }
+}
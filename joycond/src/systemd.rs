@@ -0,0 +1,218 @@
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+use std::process;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use common::log;
+
+// Sockets passed by the service manager start right after stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The listening socket systemd passed in with socket activation, if it did.
+/// The variables describing it are cleared, so children don't pick it up,
+/// which means this has to be called before any other thread is started.
+pub fn listener() -> Result<Option<UnixListener>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if !passed_socket(pid.as_deref(), fds.as_deref(), process::id())? {
+        return Ok(None);
+    }
+    let fd = LISTEN_FDS_START;
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(Some(unsafe { UnixListener::from_raw_fd(fd) }))
+}
+
+// Whether `LISTEN_PID` and `LISTEN_FDS` say the process `own_pid` was passed
+// a socket. joycond only listens on one, so more than that is an error.
+fn passed_socket(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> Result<bool> {
+    // Sockets meant for another process, e.g. the one that exec'd this one
+    if pid.and_then(|pid| pid.parse().ok()) != Some(own_pid) {
+        return Ok(false);
+    }
+    match fds.and_then(|fds| fds.parse::<RawFd>().ok()) {
+        None | Some(0) => Ok(false),
+        Some(1) => Ok(true),
+        Some(n) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Expected one socket from systemd, got {}", n),
+        )),
+    }
+}
+
+/// Tells the service manager about a change in state, e.g. `READY=1`. Does
+/// nothing unless joycond was started with `NOTIFY_SOCKET` set.
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = notify_socket(&path.to_string_lossy(), state) {
+        log::e(&format!("Couldn't notify systemd of {}: {}", state, e));
+    }
+}
+
+fn notify_socket(path: &str, state: &str) -> Result<usize> {
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &notify_address(path)?)
+}
+
+// Paths starting with @ are in the abstract namespace
+fn notify_address(path: &str) -> Result<SocketAddr> {
+    match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(path),
+    }
+}
+
+/// Keeps systemd's watchdog fed, if the service has one
+pub struct Watchdog {
+    interval: Duration,
+    fed: Instant,
+}
+
+impl Watchdog {
+    /// Reads the watchdog timeout systemd set for this process, if any
+    pub fn from_env() -> Option<Watchdog> {
+        if let Ok(pid) = env::var("WATCHDOG_PID") {
+            if pid.parse().ok() != Some(process::id()) {
+                return None;
+            }
+        }
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        if usec == 0 {
+            return None;
+        }
+        // Feeding it twice per timeout leaves room for a slow pass
+        Some(Watchdog {
+            interval: Duration::from_micros(usec) / 2,
+            fed: Instant::now(),
+        })
+    }
+
+    /// Call regularly from the loop whose progress the watchdog stands for
+    pub fn feed(&mut self) {
+        if self.fed.elapsed() >= self.interval {
+            self.fed = Instant::now();
+            notify("WATCHDOG=1");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A notify socket standing in for systemd's, in a directory of its own
+    struct FakeSystemd {
+        dir: PathBuf,
+        socket: UnixDatagram,
+    }
+
+    impl FakeSystemd {
+        fn bind(name: &str) -> FakeSystemd {
+            let dir = env::temp_dir().join(format!("joycond-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let socket = UnixDatagram::bind(dir.join("notify")).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            FakeSystemd { dir, socket }
+        }
+
+        fn path(&self) -> PathBuf {
+            self.dir.join("notify")
+        }
+
+        fn recv(&self) -> Option<String> {
+            let mut buf = [0; 64];
+            let len = self.socket.recv(&mut buf).ok()?;
+            Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+        }
+    }
+
+    impl Drop for FakeSystemd {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // The only test that touches `NOTIFY_SOCKET`, since tests share the environment
+    #[test]
+    fn notifies_and_feeds_the_watchdog() {
+        let systemd = FakeSystemd::bind("notify");
+        env::set_var("NOTIFY_SOCKET", systemd.path());
+
+        notify("READY=1");
+        assert_eq!(systemd.recv().as_deref(), Some("READY=1"));
+
+        let mut watchdog = Watchdog {
+            interval: Duration::from_secs(3600),
+            fed: Instant::now(),
+        };
+        watchdog.feed();
+        assert_eq!(systemd.recv(), None);
+        watchdog.interval = Duration::from_secs(0);
+        watchdog.feed();
+        assert_eq!(systemd.recv().as_deref(), Some("WATCHDOG=1"));
+
+        env::remove_var("NOTIFY_SOCKET");
+        notify("STOPPING=1");
+        assert_eq!(systemd.recv(), None);
+    }
+
+    #[test]
+    fn notifies_abstract_sockets() {
+        let name = format!("joycond-test-{}", process::id());
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+
+        notify_socket(&format!("@{}", name), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn missing_notify_socket_is_an_error() {
+        let systemd = FakeSystemd::bind("missing");
+        let path = systemd.dir.join("elsewhere");
+        assert!(notify_socket(&path.to_string_lossy(), "READY=1").is_err());
+    }
+
+    #[test]
+    fn takes_a_socket_meant_for_this_process() {
+        assert!(passed_socket(Some("42"), Some("1"), 42).unwrap());
+    }
+
+    #[test]
+    fn ignores_sockets_meant_for_others() {
+        assert!(!passed_socket(Some("41"), Some("1"), 42).unwrap());
+        assert!(!passed_socket(None, Some("1"), 42).unwrap());
+        assert!(!passed_socket(Some("self"), Some("1"), 42).unwrap());
+    }
+
+    #[test]
+    fn needs_a_socket_count() {
+        assert!(!passed_socket(Some("42"), None, 42).unwrap());
+        assert!(!passed_socket(Some("42"), Some("0"), 42).unwrap());
+        assert!(!passed_socket(Some("42"), Some("one"), 42).unwrap());
+    }
+
+    #[test]
+    fn refuses_several_sockets() {
+        let e = passed_socket(Some("42"), Some("2"), 42).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+}
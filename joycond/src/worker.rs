@@ -0,0 +1,318 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use hidapi::{HidApi, HidDeviceInfo, HidError};
+
+use common::ipc::protocol::{ControllerInfo, ControllerStatus, ErrorKind, Response, Rumble};
+use common::log;
+
+use joycon_driver::device::InputMode;
+use joycon_driver::driver::Driver;
+use joycon_driver::player_leds::LedState;
+use joycon_driver::setup::{self, Step};
+
+use super::config::ControllerConfig;
+
+// Reconnection attempts back off exponentially from this delay...
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+// ...up to this one
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// How long a client's flash read waits for the controller to answer
+const FLASH_TIMEOUT: Duration = Duration::from_secs(1);
+
+// A client's flash read, answered once the controller sends the data
+struct FlashRead {
+    address: u32,
+    length: u8,
+    reply: Sender<Response>,
+    // When it was asked of the controller, which is one read at a time
+    sent: Option<Instant>,
+}
+
+/// Drives one controller through its lifecycle: connecting, calibrating,
+/// active, and lost whenever it fails, after which it's restarted. Every
+/// stage only ever does what it can without waiting on the controller, so a
+/// slow controller doesn't hold up the others.
+pub struct Worker {
+    info: HidDeviceInfo,
+    // What the controller is known by, see `serial_number`
+    serial: String,
+    status: ControllerStatus,
+    driver: Option<Driver>,
+    // Player LEDs to show, sent again whenever the controller reconnects
+    leds: LedState,
+    failures: u32,
+    retry_at: Instant,
+    rumble_until: Option<Instant>,
+    flash_reads: VecDeque<FlashRead>,
+}
+
+impl Worker {
+    /// Creates a worker that shows `leds` once the controller is set up
+    pub fn new(info: HidDeviceInfo, leds: LedState) -> Worker {
+        Worker {
+            serial: controller_id(info.serial_number.as_deref(), None, &info.path),
+            info,
+            status: ControllerStatus::Connecting,
+            driver: None,
+            leds,
+            failures: 0,
+            retry_at: Instant::now(),
+            rumble_until: None,
+            flash_reads: VecDeque::new(),
+        }
+    }
+
+    /// The controller's serial number, which its session, player slot and
+    /// clients know it by. Controllers that don't report one go by their
+    /// Bluetooth address instead, or their hidraw path until that's known.
+    pub fn serial_number(&self) -> &str {
+        &self.serial
+    }
+
+    pub fn status(&self) -> ControllerStatus {
+        self.status
+    }
+
+    /// Describe the controller, as far as it's been identified. Pairing is up to the caller.
+    pub fn describe(&self) -> ControllerInfo {
+        let driver = self.driver.as_ref();
+        ControllerInfo {
+            serial: self.serial_number().to_string(),
+            product: self
+                .info
+                .product_string
+                .clone()
+                .unwrap_or_else(|| format!("{:04x}", self.info.product_id)),
+            name: None,
+            status: self.status,
+            slot: None,
+            mac_address: self.mac_address(),
+            // Stored little-endian, major version first
+            firmware_version: driver
+                .and_then(|d| d.firmware_version())
+                .map(|version| format!("{:x}.{:02x}", version & 0xff, version >> 8)),
+            paired_with: None,
+        }
+    }
+
+    /// The controller's Bluetooth address, once it's been asked for it
+    pub fn mac_address(&self) -> Option<String> {
+        let mac = self.driver.as_ref()?.mac_address()?;
+        Some(
+            (0..6)
+                .rev()
+                .map(|i| format!("{:02X}", (mac >> (i * 8)) & 0xff))
+                .collect::<Vec<String>>()
+                .join(":"),
+        )
+    }
+
+    /// The controller's driver, once it's active
+    pub fn driver(&mut self) -> Option<&mut Driver> {
+        match self.status {
+            ControllerStatus::Active => self.driver.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Do whatever the controller's current stage calls for without blocking
+    /// for long, returning the new status if it changed
+    pub fn poll(&mut self, api: &HidApi, config: &ControllerConfig) -> Option<ControllerStatus> {
+        let now = Instant::now();
+        let leds = self.leds;
+        let flash_reads = &mut self.flash_reads;
+        let next = match (self.status, self.driver.as_mut()) {
+            (ControllerStatus::Connecting, None) if now >= self.retry_at => {
+                match Driver::connect(api, &self.info) {
+                    Ok(driver) => {
+                        self.driver = Some(driver);
+                        Ok(ControllerStatus::Connecting)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            // Identified, so it's calibrated and set up the way it's configured
+            (ControllerStatus::Connecting, Some(driver)) => driver.poll_setup().map(|done| {
+                if !done {
+                    return ControllerStatus::Connecting;
+                }
+                driver.start_setup(&setup::CALIBRATE);
+                driver.start_setup(&[
+                    Step::InputMode(InputMode::from(config.input_mode)),
+                    Step::Leds(leds),
+                    Step::Imu(config.imu),
+                    Step::Vibration(true),
+                ]);
+                ControllerStatus::Calibrating
+            }),
+            (ControllerStatus::Calibrating, Some(driver)) => driver.poll_setup().map(|done| {
+                if done {
+                    ControllerStatus::Active
+                } else {
+                    ControllerStatus::Calibrating
+                }
+            }),
+            (ControllerStatus::Active, Some(driver)) => {
+                let stop_rumble = self.rumble_until.is_some_and(|until| now >= until);
+                if stop_rumble {
+                    self.rumble_until = None;
+                }
+                Worker::service(driver, stop_rumble)
+                    .and_then(|_| Worker::serve_flash(driver, flash_reads))
+                    .map(|_| ControllerStatus::Active)
+            }
+            (ControllerStatus::Lost, _) if now >= self.retry_at => Ok(ControllerStatus::Connecting),
+            _ => return None,
+        };
+
+        let next = match next {
+            Ok(ControllerStatus::Active) => {
+                self.failures = 0;
+                ControllerStatus::Active
+            }
+            Ok(next) => next,
+            Err(e) => {
+                self.fail(&e);
+                ControllerStatus::Lost
+            }
+        };
+        if next == self.status {
+            return None;
+        }
+        if next == ControllerStatus::Calibrating {
+            self.serial = controller_id(
+                self.info.serial_number.as_deref(),
+                self.mac_address(),
+                &self.info.path,
+            );
+        }
+        self.status = next;
+        Some(next)
+    }
+
+    /// Show `leds` now if the controller is active, and whenever it reconnects
+    pub fn set_leds(&mut self, leds: LedState) -> Result<(), HidError> {
+        self.leds = leds;
+        match self.driver() {
+            Some(driver) => driver.set_leds(leds).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Start a rumble that stops by itself after its duration
+    pub fn rumble(&mut self, rumble: Rumble) -> Result<(), HidError> {
+        let until = Instant::now() + Duration::from_millis(u64::from(rumble.duration_ms));
+        match self.driver() {
+            Some(driver) => driver.rumble(rumble.frequency, rumble.amplitude)?,
+            None => return Ok(()),
+        };
+        self.rumble_until = Some(until);
+        Ok(())
+    }
+
+    /// Read `length` bytes of flash once the controller is active, answering
+    /// `reply` when they arrive
+    pub fn read_flash(&mut self, address: u32, length: u8, reply: Sender<Response>) {
+        self.flash_reads.push_back(FlashRead {
+            address,
+            length,
+            reply,
+            sent: None,
+        });
+    }
+
+    // Asks for the oldest flash read, or answers it once the data's arrived
+    fn serve_flash(driver: &mut Driver, reads: &mut VecDeque<FlashRead>) -> Result<(), HidError> {
+        let (address, sent) = match reads.front_mut() {
+            Some(read) if read.sent.is_none() => {
+                driver.request_flash(read.address, usize::from(read.length))?;
+                read.sent = Some(Instant::now());
+                return Ok(());
+            }
+            Some(read) => (read.address, read.sent.unwrap()),
+            None => return Ok(()),
+        };
+        let response = match driver.take_flash(address) {
+            Some(data) => Response::Flash { data },
+            None if sent.elapsed() >= FLASH_TIMEOUT => Response::error(
+                ErrorKind::Device,
+                &format!("Timed out reading flash at {:#x}", address),
+            ),
+            None => return Ok(()),
+        };
+        // The client may have hung up while waiting, which is its business
+        let _ = reads.pop_front().unwrap().reply.send(response);
+        Ok(())
+    }
+
+    // Answers every flash read still waiting, which won't be read now
+    fn cancel_flash(&mut self, message: &str) {
+        for read in self.flash_reads.drain(..) {
+            let _ = read.reply.send(Response::error(ErrorKind::Device, message));
+        }
+    }
+
+    // Reads pending input. Frames and button events are left for the
+    // supervisor to forward.
+    fn service(driver: &mut Driver, stop_rumble: bool) -> Result<(), HidError> {
+        if stop_rumble {
+            driver.rumble(0.0, 0.0)?;
+        }
+        driver.flush()?;
+        driver.poll_voltage()?;
+        driver.update_leds()?;
+        Ok(())
+    }
+
+    fn fail(&mut self, e: &HidError) {
+        log::e(&format!(
+            "Controller {} failed while {:?}: {:?}",
+            self.serial_number(),
+            self.status,
+            e
+        ));
+        self.cancel_flash(&format!("{:?}", e));
+        self.driver = None;
+        self.rumble_until = None;
+        self.failures += 1;
+        let backoff = RETRY_DELAY * 2u32.pow(cmp::min(self.failures - 1, 6));
+        self.retry_at = Instant::now() + cmp::min(backoff, MAX_RETRY_DELAY);
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.cancel_flash("The controller went away");
+    }
+}
+
+// The serial number if there is one, so controllers with the same empty
+// serial don't take over each other's sessions
+fn controller_id(serial: Option<&str>, mac_address: Option<String>, path: &CStr) -> String {
+    match serial {
+        Some(serial) if !serial.is_empty() => serial.to_string(),
+        _ => mac_address.unwrap_or_else(|| path.to_string_lossy().into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+
+    #[test]
+    fn controllers_without_serials_go_by_address_or_path() {
+        let path = CString::new("/dev/hidraw3").unwrap();
+        let mac = || Some("98:B6:E9:00:00:01".to_string());
+        assert_eq!(controller_id(Some("ABC123"), mac(), &path), "ABC123");
+        assert_eq!(controller_id(Some(""), mac(), &path), "98:B6:E9:00:00:01");
+        assert_eq!(controller_id(None, mac(), &path), "98:B6:E9:00:00:01");
+        assert_eq!(controller_id(Some(""), None, &path), "/dev/hidraw3");
+    }
+}